@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS public.images;
+DROP TABLE IF EXISTS public.messages;
+DROP TABLE IF EXISTS public.chats;
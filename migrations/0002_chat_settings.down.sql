@@ -0,0 +1,15 @@
+ALTER TABLE public.messages
+    DROP COLUMN IF EXISTS pinned;
+
+ALTER TABLE public.chats
+    DROP COLUMN IF EXISTS model,
+    DROP COLUMN IF EXISTS temperature,
+    DROP COLUMN IF EXISTS top_p,
+    DROP COLUMN IF EXISTS max_tokens,
+    DROP COLUMN IF EXISTS stop,
+    DROP COLUMN IF EXISTS presence_penalty,
+    DROP COLUMN IF EXISTS frequency_penalty,
+    DROP COLUMN IF EXISTS seed,
+    DROP COLUMN IF EXISTS response_format,
+    DROP COLUMN IF EXISTS context_strategy,
+    DROP COLUMN IF EXISTS context_last_n;
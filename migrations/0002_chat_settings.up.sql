@@ -0,0 +1,15 @@
+ALTER TABLE public.chats
+    ADD COLUMN IF NOT EXISTS model character varying(255) COLLATE pg_catalog."default",
+    ADD COLUMN IF NOT EXISTS temperature real,
+    ADD COLUMN IF NOT EXISTS top_p real,
+    ADD COLUMN IF NOT EXISTS max_tokens integer,
+    ADD COLUMN IF NOT EXISTS stop text[],
+    ADD COLUMN IF NOT EXISTS presence_penalty real,
+    ADD COLUMN IF NOT EXISTS frequency_penalty real,
+    ADD COLUMN IF NOT EXISTS seed bigint,
+    ADD COLUMN IF NOT EXISTS response_format jsonb,
+    ADD COLUMN IF NOT EXISTS context_strategy character varying(32) COLLATE pg_catalog."default",
+    ADD COLUMN IF NOT EXISTS context_last_n integer;
+
+ALTER TABLE public.messages
+    ADD COLUMN IF NOT EXISTS pinned boolean NOT NULL DEFAULT false;
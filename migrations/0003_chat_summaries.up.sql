@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS public.chat_summaries
+(
+    chat_id integer NOT NULL,
+    content text COLLATE pg_catalog."default" NOT NULL,
+    last_message_id integer NOT NULL,
+    created_on timestamp with time zone NOT NULL DEFAULT now(),
+    updated_on timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT chat_summaries_pkey PRIMARY KEY (chat_id),
+    CONSTRAINT chat_summaries_chat_id_fkey FOREIGN KEY (chat_id)
+    REFERENCES public.chats (chat_id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+ALTER TABLE public.chats
+    DROP CONSTRAINT IF EXISTS chats_app_user_fkey;
+
+DROP TABLE IF EXISTS public.users;
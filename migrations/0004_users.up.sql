@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS public.users
+(
+    id SERIAL PRIMARY KEY,
+    username character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    api_key_hash character varying(64) COLLATE pg_catalog."default" NOT NULL,
+    created_on timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT users_username_key UNIQUE (username),
+    CONSTRAINT users_api_key_hash_key UNIQUE (api_key_hash)
+);
+
+-- NOT VALID keeps chats created before users existed; new rows are checked.
+ALTER TABLE public.chats
+    ADD CONSTRAINT chats_app_user_fkey FOREIGN KEY (app_user)
+    REFERENCES public.users (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+    NOT VALID;
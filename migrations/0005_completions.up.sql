@@ -0,0 +1,33 @@
+-- One row per model call. chat_id and message_id are cleared rather than
+-- cascaded so deleting a chat does not erase what it cost.
+CREATE TABLE IF NOT EXISTS public.completions
+(
+    id SERIAL PRIMARY KEY,
+    app_user integer NOT NULL,
+    chat_id integer,
+    message_id integer,
+    model character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    prompt_tokens integer NOT NULL,
+    completion_tokens integer NOT NULL,
+    finish_reason character varying(64) COLLATE pg_catalog."default",
+    latency_ms integer NOT NULL,
+    cost double precision,
+    created_on timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT completions_app_user_fkey FOREIGN KEY (app_user)
+    REFERENCES public.users (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE,
+    CONSTRAINT completions_chat_id_fkey FOREIGN KEY (chat_id)
+    REFERENCES public.chats (chat_id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE SET NULL,
+    CONSTRAINT completions_message_id_fkey FOREIGN KEY (message_id)
+    REFERENCES public.messages (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS completions_app_user_created_on_idx
+    ON public.completions (app_user, created_on);
+CREATE INDEX IF NOT EXISTS completions_chat_id_idx
+    ON public.completions (chat_id);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS public.budgets;
+
+ALTER TABLE public.users
+    DROP COLUMN IF EXISTS is_admin;
@@ -0,0 +1,18 @@
+ALTER TABLE public.users
+    ADD COLUMN IF NOT EXISTS is_admin boolean NOT NULL DEFAULT false;
+
+-- Spending limits per user. A NULL limit is unlimited.
+CREATE TABLE IF NOT EXISTS public.budgets
+(
+    app_user integer NOT NULL,
+    daily_tokens bigint,
+    monthly_tokens bigint,
+    daily_cost double precision,
+    monthly_cost double precision,
+    updated_on timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT budgets_pkey PRIMARY KEY (app_user),
+    CONSTRAINT budgets_app_user_fkey FOREIGN KEY (app_user)
+    REFERENCES public.users (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS public.rate_limit_buckets;
+
+ALTER TABLE public.users
+    DROP COLUMN IF EXISTS tier;
@@ -0,0 +1,12 @@
+ALTER TABLE public.users
+    ADD COLUMN IF NOT EXISTS tier character varying(64) COLLATE pg_catalog."default" NOT NULL DEFAULT 'default';
+
+-- Token buckets shared by every instance when rate_limit_backend = "postgres".
+CREATE UNLOGGED TABLE IF NOT EXISTS public.rate_limit_buckets
+(
+    key text COLLATE pg_catalog."default" NOT NULL,
+    tokens double precision NOT NULL,
+    allowed boolean NOT NULL,
+    updated_on timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT rate_limit_buckets_pkey PRIMARY KEY (key)
+);
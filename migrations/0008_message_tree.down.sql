@@ -0,0 +1,5 @@
+ALTER TABLE public.chats
+    DROP COLUMN IF EXISTS active_message_id;
+
+ALTER TABLE public.messages
+    DROP COLUMN IF EXISTS parent_id;
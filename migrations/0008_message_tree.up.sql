@@ -0,0 +1,34 @@
+-- Messages form a tree: edits and regenerations add siblings instead of
+-- replacing messages. The chat's active leaf marks the branch being shown.
+ALTER TABLE public.messages
+    ADD COLUMN IF NOT EXISTS parent_id integer
+    REFERENCES public.messages (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE;
+
+ALTER TABLE public.chats
+    ADD COLUMN IF NOT EXISTS active_message_id integer
+    REFERENCES public.messages (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS messages_parent_id_idx
+    ON public.messages (parent_id);
+
+-- Existing chats become a single branch in their current order.
+UPDATE public.messages AS message
+SET parent_id = ordered.previous_id
+FROM (
+    SELECT id, lag(id) OVER (PARTITION BY chat_id_relation ORDER BY created_on, id) AS previous_id
+    FROM public.messages
+) AS ordered
+WHERE message.id = ordered.id;
+
+UPDATE public.chats AS chat
+SET active_message_id = (
+    SELECT id
+    FROM public.messages
+    WHERE chat_id_relation = chat.chat_id
+    ORDER BY created_on DESC, id DESC
+    LIMIT 1
+);
@@ -0,0 +1,5 @@
+-- Assistant messages may call tools instead of answering, and each tool's
+-- result is stored as a `tool` message answering one of those calls.
+ALTER TABLE public.messages
+    ADD COLUMN IF NOT EXISTS tool_calls jsonb,
+    ADD COLUMN IF NOT EXISTS tool_call_id character varying(255);
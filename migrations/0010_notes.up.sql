@@ -0,0 +1,13 @@
+-- Key/value notes the assistant keeps for each user through the notes tool.
+CREATE TABLE IF NOT EXISTS public.notes
+(
+    app_user integer NOT NULL,
+    key character varying(255) NOT NULL,
+    value text NOT NULL,
+    updated_on timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT notes_pkey PRIMARY KEY (app_user, key),
+    CONSTRAINT notes_app_user_fkey FOREIGN KEY (app_user)
+    REFERENCES public.users (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
@@ -0,0 +1,6 @@
+ALTER TABLE public.messages
+    DROP COLUMN IF EXISTS citations;
+
+DROP TABLE IF EXISTS public.document_chunks;
+
+DROP TABLE IF EXISTS public.documents;
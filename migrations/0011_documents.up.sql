@@ -0,0 +1,39 @@
+-- Documents attached to a chat, split into chunks with their embeddings for
+-- retrieval. Embeddings are plain float arrays; with pgvector installed they
+-- are cast to `vector` at query time.
+CREATE TABLE IF NOT EXISTS public.documents
+(
+    id SERIAL PRIMARY KEY,
+    chat_id integer NOT NULL,
+    name character varying(255) NOT NULL,
+    content_type character varying(255) NOT NULL,
+    created_on timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT documents_chat_id_fkey FOREIGN KEY (chat_id)
+    REFERENCES public.chats (chat_id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS documents_chat_id_idx
+    ON public.documents (chat_id);
+
+CREATE TABLE IF NOT EXISTS public.document_chunks
+(
+    id SERIAL PRIMARY KEY,
+    document_id integer NOT NULL,
+    chunk_index integer NOT NULL,
+    content text NOT NULL,
+    embedding real[] NOT NULL,
+    CONSTRAINT document_chunks_document_id_fkey FOREIGN KEY (document_id)
+    REFERENCES public.documents (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS document_chunks_document_id_idx
+    ON public.document_chunks (document_id);
+
+-- The document chunks an assistant reply was given, as
+-- [{document_id, document_name, chunk_id, chunk_index, score}].
+ALTER TABLE public.messages
+    ADD COLUMN IF NOT EXISTS citations jsonb;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS public.chats_chat_name_search_idx;
+
+DROP INDEX IF EXISTS public.messages_content_search_idx;
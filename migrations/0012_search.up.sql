@@ -0,0 +1,7 @@
+-- Full-text search over message contents and chat names. Queries must use the
+-- same expressions for the indexes to apply.
+CREATE INDEX IF NOT EXISTS messages_content_search_idx
+    ON public.messages USING gin (to_tsvector('english', content));
+
+CREATE INDEX IF NOT EXISTS chats_chat_name_search_idx
+    ON public.chats USING gin (to_tsvector('english', chat_name));
@@ -0,0 +1,12 @@
+-- Embeddings of user and assistant messages for semantic search, filled in
+-- the background after messages are saved.
+CREATE TABLE IF NOT EXISTS public.message_embeddings
+(
+    message_id integer PRIMARY KEY,
+    embedding real[] NOT NULL,
+    created_on timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT message_embeddings_message_id_fkey FOREIGN KEY (message_id)
+    REFERENCES public.messages (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
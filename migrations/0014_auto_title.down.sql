@@ -0,0 +1,2 @@
+ALTER TABLE public.chats
+    DROP COLUMN IF EXISTS auto_title;
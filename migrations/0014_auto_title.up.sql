@@ -0,0 +1,4 @@
+-- Whether the chat is named by the model after its first reply; NULL follows
+-- the auto_title setting.
+ALTER TABLE public.chats
+    ADD COLUMN IF NOT EXISTS auto_title boolean;
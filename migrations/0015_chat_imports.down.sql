@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS public.chats_app_user_import_id_idx;
+
+ALTER TABLE public.chats
+    DROP COLUMN IF EXISTS import_id;
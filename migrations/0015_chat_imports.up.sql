@@ -0,0 +1,7 @@
+-- Id of the conversation a chat was imported from, so the same export can be
+-- imported again without duplicating chats.
+ALTER TABLE public.chats
+    ADD COLUMN IF NOT EXISTS import_id character varying(255);
+
+CREATE UNIQUE INDEX IF NOT EXISTS chats_app_user_import_id_idx
+    ON public.chats (app_user, import_id);
@@ -0,0 +1,7 @@
+ALTER TABLE public.messages
+    DROP COLUMN IF EXISTS rating;
+
+DROP INDEX IF EXISTS public.chats_tags_idx;
+
+ALTER TABLE public.chats
+    DROP COLUMN IF EXISTS tags;
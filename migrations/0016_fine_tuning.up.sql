@@ -0,0 +1,10 @@
+-- Labels for curating chats, e.g. into fine-tuning datasets.
+ALTER TABLE public.chats
+    ADD COLUMN IF NOT EXISTS tags text[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS chats_tags_idx
+    ON public.chats USING gin (tags);
+
+-- Feedback on an assistant reply: 1 for good, -1 for bad.
+ALTER TABLE public.messages
+    ADD COLUMN IF NOT EXISTS rating smallint CHECK (rating IN (-1, 1));
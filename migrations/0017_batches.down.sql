@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS public.batch_items;
+DROP TABLE IF EXISTS public.batches;
@@ -0,0 +1,46 @@
+-- Files of chat requests run in the background. A batch is `running` until
+-- none of its items is pending or running, then `completed`.
+CREATE TABLE IF NOT EXISTS public.batches
+(
+    id SERIAL PRIMARY KEY,
+    app_user integer NOT NULL,
+    status character varying(16) NOT NULL DEFAULT 'running',
+    created_on timestamp with time zone NOT NULL DEFAULT now(),
+    finished_on timestamp with time zone,
+    CONSTRAINT batches_app_user_fkey FOREIGN KEY (app_user)
+    REFERENCES public.users (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS batches_app_user_idx
+    ON public.batches (app_user);
+
+-- One row per line of a batch file. `request` is NULL when the line could not
+-- be read, and the item is failed from the start. Items go from `pending` to
+-- `running` to `succeeded` or `failed`.
+CREATE TABLE IF NOT EXISTS public.batch_items
+(
+    batch_id integer NOT NULL,
+    line integer NOT NULL,
+    custom_id text,
+    request jsonb,
+    status character varying(16) NOT NULL DEFAULT 'pending',
+    attempts integer NOT NULL DEFAULT 0,
+    response jsonb,
+    error text,
+    prompt_tokens integer,
+    completion_tokens integer,
+    cost double precision,
+    started_on timestamp with time zone,
+    finished_on timestamp with time zone,
+    PRIMARY KEY (batch_id, line),
+    CONSTRAINT batch_items_batch_id_fkey FOREIGN KEY (batch_id)
+    REFERENCES public.batches (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS batch_items_unfinished_idx
+    ON public.batch_items (batch_id, line)
+    WHERE status IN ('pending', 'running');
@@ -0,0 +1,13 @@
+-- Messages the indexer could not embed, and when it may try them again, so
+-- they do not hold up the rest of the queue.
+CREATE TABLE IF NOT EXISTS public.message_embedding_failures
+(
+    message_id integer PRIMARY KEY,
+    attempts integer NOT NULL,
+    error text NOT NULL,
+    retry_after timestamp with time zone NOT NULL,
+    CONSTRAINT message_embedding_failures_message_id_fkey FOREIGN KEY (message_id)
+    REFERENCES public.messages (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE public.batch_items DROP COLUMN IF EXISTS claim_token;
@@ -0,0 +1,3 @@
+-- The run that claimed a running item. Only that run may save the item's
+-- outcome, so one that was taken over cannot overwrite the other's.
+ALTER TABLE public.batch_items ADD COLUMN IF NOT EXISTS claim_token character varying(36);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS public.rate_limit_buckets_full_on_idx;
+ALTER TABLE public.rate_limit_buckets DROP COLUMN IF EXISTS full_on;
@@ -0,0 +1,4 @@
+-- When each bucket will have refilled to capacity. Full buckets are the same
+-- as missing ones, so they are deleted from then on.
+ALTER TABLE public.rate_limit_buckets ADD COLUMN IF NOT EXISTS full_on timestamp with time zone NOT NULL DEFAULT now();
+CREATE INDEX IF NOT EXISTS rate_limit_buckets_full_on_idx ON public.rate_limit_buckets (full_on);
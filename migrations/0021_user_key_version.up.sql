@@ -0,0 +1,3 @@
+-- Bumped whenever the user's API key is replaced. Bearer tokens carry the
+-- version they were issued under and stop working once it changes.
+ALTER TABLE public.users ADD COLUMN IF NOT EXISTS key_version integer NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+INSERT INTO completions (app_user, chat_id, message_id, model, prompt_tokens, completion_tokens, finish_reason, latency_ms, cost)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+RETURNING *;
@@ -0,0 +1,3 @@
+INSERT INTO documents (chat_id, name, content_type)
+VALUES ($1, $2, $3)
+RETURNING id, chat_id, name, content_type, 0::bigint AS chunks, created_on;
@@ -1,2 +1,11 @@
-INSERT INTO messages (chat_id_relation, role, content)
-VALUES ($1, $2, $3) RETURNING id, created_on, role, content, chat_id_relation;
+-- The new message continues the chat's active branch and becomes its leaf.
+WITH inserted AS (
+    INSERT INTO messages (chat_id_relation, role, content, tool_calls, tool_call_id, citations, created_on, parent_id)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, (SELECT active_message_id FROM chats WHERE chat_id = $1))
+    RETURNING id, created_on, role, content, chat_id_relation, pinned, parent_id, tool_calls, tool_call_id, citations
+), activated AS (
+    UPDATE chats
+    SET active_message_id = (SELECT id FROM inserted)
+    WHERE chat_id = $1
+)
+SELECT * FROM inserted;
@@ -0,0 +1,6 @@
+WITH cleared AS (
+    DELETE FROM message_embedding_failures WHERE message_id = $1
+)
+INSERT INTO message_embeddings (message_id, embedding)
+VALUES ($1, $2)
+ON CONFLICT (message_id) DO NOTHING;
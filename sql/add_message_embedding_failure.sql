@@ -0,0 +1,6 @@
+INSERT INTO message_embedding_failures (message_id, attempts, error, retry_after)
+VALUES ($1, $2, $3, $4)
+ON CONFLICT (message_id) DO UPDATE
+SET attempts = EXCLUDED.attempts,
+    error = EXCLUDED.error,
+    retry_after = EXCLUDED.retry_after;
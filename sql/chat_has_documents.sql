@@ -0,0 +1 @@
+SELECT EXISTS (SELECT 1 FROM documents WHERE chat_id = $1);
@@ -0,0 +1 @@
+SELECT EXISTS (SELECT 1 FROM chats WHERE app_user = $1 AND import_id = $2);
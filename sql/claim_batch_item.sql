@@ -0,0 +1,18 @@
+-- The next pending item of the batch, marked running and claimed by run $3.
+-- Items another run left running for longer than $2 seconds belonged to a
+-- process that stopped, and are taken over. Concurrent workers never claim
+-- the same item.
+UPDATE batch_items
+SET status = 'running', started_on = now(), claim_token = $3
+WHERE (batch_id, line) = (
+    SELECT batch_id, line FROM batch_items
+    WHERE batch_id = $1
+      AND (status = 'pending'
+        OR (status = 'running'
+          AND claim_token IS DISTINCT FROM $3
+          AND started_on < now() - make_interval(secs => $2)))
+    ORDER BY line
+    LIMIT 1
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING *;
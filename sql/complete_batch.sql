@@ -0,0 +1,8 @@
+-- Completes the batch once no item is left to run.
+UPDATE batches
+SET status = 'completed', finished_on = now()
+WHERE id = $1 AND status = 'running'
+  AND NOT EXISTS (
+      SELECT 1 FROM batch_items
+      WHERE batch_id = $1 AND status IN ('pending', 'running')
+  );
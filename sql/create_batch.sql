@@ -0,0 +1,16 @@
+-- The batch and all its items at once. Lines that could not be read are
+-- failed from the start.
+WITH batch AS (
+    INSERT INTO batches (app_user)
+    VALUES ($1)
+    RETURNING id
+), items AS (
+    INSERT INTO batch_items (batch_id, line, custom_id, request, status, error, finished_on)
+    SELECT batch.id, item.line, item.custom_id, item.request,
+        CASE WHEN item.request IS NULL THEN 'failed' ELSE 'pending' END,
+        item.error,
+        CASE WHEN item.request IS NULL THEN now() END
+    FROM batch, unnest($2::integer[], $3::text[], $4::jsonb[], $5::text[])
+        AS item(line, custom_id, request, error)
+)
+SELECT id FROM batch;
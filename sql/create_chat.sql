@@ -1,3 +1,2 @@
 INSERT INTO chats (app_user, created_on)
-VALUES ($1, $2) RETURNING chat_id, app_user, created_on, chat_name;
-
+VALUES ($1, $2) RETURNING *;
@@ -0,0 +1,3 @@
+-- Imported chats keep their original name.
+INSERT INTO chats (app_user, created_on, chat_name, import_id, auto_title)
+VALUES ($1, $2, $3, $4, false) RETURNING *;
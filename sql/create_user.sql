@@ -0,0 +1,3 @@
+INSERT INTO users (username, api_key_hash)
+VALUES ($1, $2)
+RETURNING id, username, is_admin, tier, created_on, key_version;
@@ -0,0 +1,2 @@
+DELETE FROM documents
+WHERE chat_id = $1 AND id = $2;
@@ -0,0 +1,2 @@
+-- Buckets that have refilled to capacity, which behave like missing ones.
+DELETE FROM rate_limit_buckets WHERE full_on <= now();
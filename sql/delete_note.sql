@@ -0,0 +1,2 @@
+DELETE FROM notes
+WHERE app_user = $1 AND key = $2;
@@ -0,0 +1,12 @@
+-- Only the run still holding the claim saves the outcome.
+UPDATE batch_items
+SET status = $3,
+    response = $4,
+    error = $5,
+    prompt_tokens = $6,
+    completion_tokens = $7,
+    cost = $8,
+    attempts = $9,
+    finished_on = now()
+WHERE batch_id = $1 AND line = $2
+  AND status = 'running' AND claim_token = $10;
@@ -0,0 +1,10 @@
+-- A batch with its items counted by status.
+SELECT batch.*,
+    count(item.line) AS total,
+    count(item.line) FILTER (WHERE item.status IN ('pending', 'running')) AS pending,
+    count(item.line) FILTER (WHERE item.status = 'succeeded') AS succeeded,
+    count(item.line) FILTER (WHERE item.status = 'failed') AS failed
+FROM batches batch
+LEFT JOIN batch_items item ON item.batch_id = batch.id
+WHERE batch.id = $1
+GROUP BY batch.id;
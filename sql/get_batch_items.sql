@@ -0,0 +1,3 @@
+SELECT * FROM batch_items
+WHERE batch_id = $1
+ORDER BY line;
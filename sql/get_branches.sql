@@ -0,0 +1,22 @@
+-- Every leaf of the chat's message tree, with the length of its branch.
+WITH RECURSIVE path AS (
+    SELECT message.id AS leaf_id, message.id, message.parent_id, 1 AS length
+    FROM messages message
+    WHERE message.chat_id_relation = $1
+      AND NOT EXISTS (SELECT 1 FROM messages child WHERE child.parent_id = message.id)
+    UNION ALL
+    SELECT path.leaf_id, message.id, message.parent_id, path.length + 1
+    FROM messages message
+    JOIN path ON message.id = path.parent_id
+)
+SELECT leaf.id AS leaf_id,
+       leaf.role,
+       leaf.content,
+       leaf.created_on,
+       max(path.length)::integer AS length,
+       leaf.id = chat.active_message_id AS active
+FROM path
+JOIN messages leaf ON leaf.id = path.leaf_id
+JOIN chats chat ON chat.chat_id = leaf.chat_id_relation
+GROUP BY leaf.id, leaf.role, leaf.content, leaf.created_on, chat.active_message_id
+ORDER BY leaf.created_on ASC;
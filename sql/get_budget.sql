@@ -0,0 +1,3 @@
+SELECT app_user, daily_tokens, monthly_tokens, daily_cost, monthly_cost, updated_on
+FROM budgets
+WHERE app_user = $1;
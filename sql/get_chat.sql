@@ -0,0 +1 @@
+SELECT * FROM chats WHERE chat_id = $1;
@@ -0,0 +1,3 @@
+SELECT chat_id, content, last_message_id, created_on, updated_on
+FROM chat_summaries
+WHERE chat_id = $1;
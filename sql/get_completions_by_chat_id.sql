@@ -0,0 +1,6 @@
+SELECT *
+FROM completions
+WHERE chat_id = $1
+  AND ($2::timestamptz IS NULL OR created_on >= $2)
+  AND ($3::timestamptz IS NULL OR created_on < $3)
+ORDER BY created_on;
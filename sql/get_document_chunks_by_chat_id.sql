@@ -0,0 +1,4 @@
+SELECT chunk.id, chunk.document_id, document.name AS document_name, chunk.chunk_index, chunk.content, chunk.embedding
+FROM document_chunks chunk
+JOIN documents document ON document.id = chunk.document_id
+WHERE document.chat_id = $1;
@@ -0,0 +1,6 @@
+SELECT document.id, document.chat_id, document.name, document.content_type,
+       (SELECT count(*) FROM document_chunks chunk WHERE chunk.document_id = document.id) AS chunks,
+       document.created_on
+FROM documents document
+WHERE document.chat_id = $1
+ORDER BY document.created_on, document.id;
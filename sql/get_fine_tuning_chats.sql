@@ -0,0 +1,11 @@
+-- The user's chats matching every filter given: one of the ids in $2, one of
+-- the tags in $3, and, when $4 is set, a reply rated good.
+SELECT * FROM chats
+WHERE app_user = $1
+    AND (cardinality($2::integer[]) = 0 OR chat_id = ANY($2))
+    AND (cardinality($3::text[]) = 0 OR tags && $3)
+    AND (NOT $4 OR EXISTS (
+        SELECT 1 FROM messages
+        WHERE chat_id_relation = chats.chat_id AND rating = 1
+    ))
+ORDER BY created_on, chat_id;
@@ -0,0 +1,3 @@
+SELECT id, created_on, role, content, chat_id_relation, pinned, parent_id, tool_calls, tool_call_id, citations
+FROM messages
+WHERE chat_id_relation = $1 AND id = $2;
@@ -0,0 +1,6 @@
+SELECT message.id AS message_id, chat.chat_id, chat.chat_name, message.role, message.content,
+       message.created_on, embedding.embedding
+FROM message_embeddings embedding
+JOIN messages message ON message.id = embedding.message_id
+JOIN chats chat ON chat.chat_id = message.chat_id_relation
+WHERE chat.app_user = $1;
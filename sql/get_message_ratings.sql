@@ -0,0 +1,3 @@
+SELECT id, rating
+FROM messages
+WHERE chat_id_relation = $1 AND rating IS NOT NULL;
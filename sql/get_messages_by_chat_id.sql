@@ -1,4 +1,14 @@
-SELECT id, created_on, role, content, chat_id_relation
-FROM messages
-WHERE chat_id_relation = $1
-ORDER BY created_on ASC;
+-- The active branch, from the root to the chat's active leaf.
+WITH RECURSIVE path AS (
+    SELECT message.*, 0 AS depth
+    FROM messages message
+    JOIN chats chat ON chat.active_message_id = message.id
+    WHERE chat.chat_id = $1
+    UNION ALL
+    SELECT message.*, path.depth + 1
+    FROM messages message
+    JOIN path ON message.id = path.parent_id
+)
+SELECT id, created_on, role, content, chat_id_relation, pinned, parent_id, tool_calls, tool_call_id, citations
+FROM path
+ORDER BY depth DESC;
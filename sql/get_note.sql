@@ -0,0 +1,3 @@
+SELECT app_user, key, value, updated_on
+FROM notes
+WHERE app_user = $1 AND key = $2;
@@ -0,0 +1,4 @@
+SELECT app_user, key, value, updated_on
+FROM notes
+WHERE app_user = $1
+ORDER BY key;
@@ -0,0 +1 @@
+SELECT id FROM batches WHERE status = 'running' ORDER BY id;
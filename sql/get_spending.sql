@@ -0,0 +1,8 @@
+-- $2 is the start of the current day and $3 the start of the current month.
+SELECT coalesce(sum(prompt_tokens + completion_tokens) FILTER (WHERE created_on >= $2), 0)::bigint AS daily_tokens,
+       coalesce(sum(prompt_tokens + completion_tokens), 0)::bigint AS monthly_tokens,
+       coalesce(sum(cost) FILTER (WHERE created_on >= $2), 0) AS daily_cost,
+       coalesce(sum(cost), 0) AS monthly_cost
+FROM completions
+WHERE app_user = $1
+  AND created_on >= $3;
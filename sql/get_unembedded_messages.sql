@@ -0,0 +1,14 @@
+-- The oldest user and assistant messages with text that have no embedding yet,
+-- leaving out those that failed too often or must wait before another try.
+SELECT message.id, message.chat_id_relation AS chat_id, chat.app_user, message.content,
+       COALESCE(failure.attempts, 0) AS attempts
+FROM messages message
+JOIN chats chat ON chat.chat_id = message.chat_id_relation
+LEFT JOIN message_embeddings embedding ON embedding.message_id = message.id
+LEFT JOIN message_embedding_failures failure ON failure.message_id = message.id
+WHERE embedding.message_id IS NULL
+  AND message.role IN ('user', 'assistant')
+  AND message.content <> ''
+  AND (failure.message_id IS NULL OR (failure.attempts < $2 AND failure.retry_after <= now()))
+ORDER BY message.id
+LIMIT $1;
@@ -0,0 +1,11 @@
+SELECT model,
+       count(*) AS requests,
+       sum(prompt_tokens)::bigint AS prompt_tokens,
+       sum(completion_tokens)::bigint AS completion_tokens,
+       sum(cost) AS cost
+FROM completions
+WHERE chat_id = $1
+  AND ($2::timestamptz IS NULL OR created_on >= $2)
+  AND ($3::timestamptz IS NULL OR created_on < $3)
+GROUP BY model
+ORDER BY model;
@@ -0,0 +1,3 @@
+SELECT id, username, is_admin, tier, created_on, key_version
+FROM users
+WHERE api_key_hash = $1;
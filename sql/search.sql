@@ -0,0 +1,29 @@
+-- Chats and messages of user $1 matching the web search query $2, best first.
+-- Matches in snippets are wrapped in chr(2) and chr(3), which are replaced by
+-- tags once the snippet is escaped.
+WITH query AS (
+    SELECT websearch_to_tsquery('english', $2) AS query,
+           format('StartSel=%s, StopSel=%s, MaxFragments=2, MaxWords=30, MinWords=10', chr(2), chr(3)) AS options
+), hits AS (
+    SELECT 'chat' AS kind, chat.chat_id, chat.chat_name, NULL::integer AS message_id, NULL::varchar AS role,
+           ts_headline('english', chat.chat_name, query.query, query.options) AS snippet,
+           ts_rank(to_tsvector('english', chat.chat_name), query.query) AS rank,
+           chat.created_on
+    FROM chats chat, query
+    WHERE chat.app_user = $1
+      AND to_tsvector('english', chat.chat_name) @@ query.query
+    UNION ALL
+    SELECT 'message', chat.chat_id, chat.chat_name, message.id, message.role,
+           ts_headline('english', message.content, query.query, query.options),
+           ts_rank(to_tsvector('english', message.content), query.query),
+           message.created_on
+    FROM messages message
+    JOIN chats chat ON chat.chat_id = message.chat_id_relation, query
+    WHERE chat.app_user = $1
+      AND message.role IN ('user', 'assistant')
+      AND to_tsvector('english', message.content) @@ query.query
+)
+SELECT kind, chat_id, chat_name, message_id, role, snippet, rank, created_on, count(*) OVER () AS total
+FROM hits
+ORDER BY rank DESC, created_on DESC
+LIMIT $3 OFFSET $4;
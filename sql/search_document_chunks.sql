@@ -0,0 +1,8 @@
+-- Nearest chunks by cosine distance, computed by pgvector.
+SELECT chunk.id, chunk.document_id, document.name AS document_name, chunk.chunk_index, chunk.content,
+       1 - (chunk.embedding::vector <=> $2::real[]::vector) AS score
+FROM document_chunks chunk
+JOIN documents document ON document.id = chunk.document_id
+WHERE document.chat_id = $1
+ORDER BY chunk.embedding::vector <=> $2::real[]::vector
+LIMIT $3;
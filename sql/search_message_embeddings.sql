@@ -0,0 +1,9 @@
+-- Nearest messages by cosine distance, computed by pgvector.
+SELECT message.id AS message_id, chat.chat_id, chat.chat_name, message.role, message.content,
+       message.created_on, 1 - (embedding.embedding::vector <=> $2::real[]::vector) AS score
+FROM message_embeddings embedding
+JOIN messages message ON message.id = embedding.message_id
+JOIN chats chat ON chat.chat_id = message.chat_id_relation
+WHERE chat.app_user = $1
+ORDER BY embedding.embedding::vector <=> $2::real[]::vector
+LIMIT $3;
@@ -0,0 +1,9 @@
+-- Messages of the user's chats containing $2, newest first.
+SELECT message.id AS message_id, chat.chat_id, chat.chat_name, message.role, message.content, message.created_on
+FROM messages message
+JOIN chats chat ON chat.chat_id = message.chat_id_relation
+WHERE chat.app_user = $1
+  AND message.role IN ('user', 'assistant')
+  AND strpos(lower(message.content), lower($2)) > 0
+ORDER BY message.created_on DESC, message.id DESC
+LIMIT $3;
@@ -0,0 +1,3 @@
+UPDATE chats
+SET active_message_id = $2
+WHERE chat_id = $1;
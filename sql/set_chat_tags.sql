@@ -0,0 +1,3 @@
+UPDATE public.chats
+SET tags = $2
+WHERE chat_id = $1;
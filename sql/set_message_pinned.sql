@@ -0,0 +1,3 @@
+UPDATE public.messages
+SET pinned = $3
+WHERE chat_id_relation = $1 AND id = $2;
@@ -0,0 +1,4 @@
+-- Only replies are rated.
+UPDATE public.messages
+SET rating = $3
+WHERE chat_id_relation = $1 AND id = $2 AND role = 'assistant';
@@ -0,0 +1,15 @@
+-- Activates the newest leaf under message $2, so switching to a message shows
+-- the latest continuation of its branch.
+WITH RECURSIVE subtree AS (
+    SELECT id, created_on
+    FROM messages
+    WHERE chat_id_relation = $1 AND id = $2
+    UNION ALL
+    SELECT message.id, message.created_on
+    FROM messages message
+    JOIN subtree ON message.parent_id = subtree.id
+)
+UPDATE chats
+SET active_message_id = (SELECT id FROM subtree ORDER BY created_on DESC, id DESC LIMIT 1)
+WHERE chat_id = $1 AND EXISTS (SELECT 1 FROM subtree)
+RETURNING active_message_id;
@@ -0,0 +1,22 @@
+-- Refills the bucket $1 (capacity $2, $3 tokens per second) for the time since
+-- its last update, then takes one token if a whole one is left, and notes
+-- when it will be full again.
+INSERT INTO rate_limit_buckets AS bucket (key, tokens, allowed, updated_on, full_on)
+VALUES ($1, $2::float8 - 1, true, now(), now() + make_interval(secs => 1 / $3::float8))
+ON CONFLICT (key) DO UPDATE
+SET tokens = CASE
+        WHEN least($2::float8, bucket.tokens + extract(epoch FROM now() - bucket.updated_on)::float8 * $3::float8) >= 1
+        THEN least($2::float8, bucket.tokens + extract(epoch FROM now() - bucket.updated_on)::float8 * $3::float8) - 1
+        ELSE least($2::float8, bucket.tokens + extract(epoch FROM now() - bucket.updated_on)::float8 * $3::float8)
+    END,
+    allowed = least($2::float8, bucket.tokens + extract(epoch FROM now() - bucket.updated_on)::float8 * $3::float8) >= 1,
+    updated_on = now(),
+    full_on = now() + make_interval(secs => (
+        $2::float8 - least($2::float8, bucket.tokens + extract(epoch FROM now() - bucket.updated_on)::float8 * $3::float8)
+        + CASE
+            WHEN least($2::float8, bucket.tokens + extract(epoch FROM now() - bucket.updated_on)::float8 * $3::float8) >= 1
+            THEN 1
+            ELSE 0
+        END
+    ) / $3::float8)
+RETURNING tokens, allowed;
@@ -0,0 +1,14 @@
+UPDATE public.chats
+SET model = $2,
+    temperature = $3,
+    top_p = $4,
+    max_tokens = $5,
+    stop = $6,
+    presence_penalty = $7,
+    frequency_penalty = $8,
+    seed = $9,
+    response_format = $10,
+    context_strategy = $11,
+    context_last_n = $12,
+    auto_title = $13
+WHERE chat_id = $1;
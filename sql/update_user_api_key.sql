@@ -0,0 +1,3 @@
+UPDATE users
+SET api_key_hash = $2, key_version = key_version + 1
+WHERE id = $1;
@@ -0,0 +1,3 @@
+UPDATE users
+SET tier = $2
+WHERE id = $1;
@@ -0,0 +1,9 @@
+INSERT INTO budgets (app_user, daily_tokens, monthly_tokens, daily_cost, monthly_cost)
+VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (app_user) DO UPDATE
+SET daily_tokens = EXCLUDED.daily_tokens,
+    monthly_tokens = EXCLUDED.monthly_tokens,
+    daily_cost = EXCLUDED.daily_cost,
+    monthly_cost = EXCLUDED.monthly_cost,
+    updated_on = now()
+RETURNING app_user, daily_tokens, monthly_tokens, daily_cost, monthly_cost, updated_on;
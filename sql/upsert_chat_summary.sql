@@ -0,0 +1,7 @@
+INSERT INTO chat_summaries (chat_id, content, last_message_id)
+VALUES ($1, $2, $3)
+ON CONFLICT (chat_id) DO UPDATE
+SET content = EXCLUDED.content,
+    last_message_id = EXCLUDED.last_message_id,
+    updated_on = now()
+RETURNING chat_id, content, last_message_id, created_on, updated_on;
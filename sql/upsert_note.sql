@@ -0,0 +1,6 @@
+INSERT INTO notes (app_user, key, value)
+VALUES ($1, $2, $3)
+ON CONFLICT (app_user, key) DO UPDATE
+SET value = EXCLUDED.value,
+    updated_on = now()
+RETURNING app_user, key, value, updated_on;
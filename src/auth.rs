@@ -0,0 +1,250 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use chrono::{Duration, Utc};
+use deadpool_postgres::{Client, Pool};
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::db;
+use crate::errors::MyError;
+use crate::models::{Batch, Chat};
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// Tier of users that were not assigned one.
+pub const DEFAULT_TIER: &str = "default";
+
+/// Lifetime of the tokens issued by `POST /auth/token`.
+const TOKEN_TTL_HOURS: i64 = 24;
+
+/// The user a request was authenticated as, available to handlers as an
+/// extractor. Extraction fails with `401 Unauthorized` for anonymous requests.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: i32,
+    /// The user's rate limit tier.
+    pub tier: String,
+    /// The user's API key version, see [`Claims::ver`].
+    pub key_version: i32,
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = MyError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or(MyError::Unauthorized),
+        )
+    }
+}
+
+impl AuthenticatedUser {
+    /// Loads `chat_id` if it belongs to this user. Other users' chats are
+    /// reported as missing so their ids don't leak.
+    pub async fn owned_chat(&self, client: &Client, chat_id: i32) -> Result<Chat, MyError> {
+        let chat = db::get_chat(client, chat_id).await?;
+        if chat.app_user != self.id {
+            return Err(MyError::NotFound);
+        }
+        Ok(chat)
+    }
+
+    /// Loads `batch_id` if it belongs to this user, like [`Self::owned_chat`].
+    pub async fn owned_batch(&self, client: &Client, batch_id: i32) -> Result<Batch, MyError> {
+        let batch = db::get_batch(client, batch_id).await?;
+        if batch.app_user != self.id {
+            return Err(MyError::NotFound);
+        }
+        Ok(batch)
+    }
+
+    /// Rejects requests from users who are not admins.
+    pub async fn ensure_admin(&self, client: &Client) -> Result<(), MyError> {
+        if !db::get_user(client, self.id).await?.is_admin {
+            return Err(MyError::Forbidden);
+        }
+        Ok(())
+    }
+
+    /// Rejects requests that name a user other than the authenticated one.
+    pub fn ensure_is(&self, app_user: i32) -> Result<(), MyError> {
+        if app_user != self.id {
+            return Err(MyError::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// The user id.
+    pub sub: i32,
+    pub exp: i64,
+    /// The user's tier when the token was issued.
+    #[serde(default = "default_tier")]
+    pub tier: String,
+    /// The user's API key version when the token was issued. The token is
+    /// rejected once the key has been replaced.
+    #[serde(default)]
+    pub ver: i32,
+}
+
+fn default_tier() -> String {
+    DEFAULT_TIER.to_string()
+}
+
+pub fn hash_api_key(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+/// Generates a new random API key. Only its hash is stored.
+pub fn generate_api_key() -> String {
+    format!(
+        "hj_{}{}",
+        uuid::Uuid::new_v4().to_simple(),
+        uuid::Uuid::new_v4().to_simple()
+    )
+}
+
+/// Signs a bearer token for `user` with `secret`.
+pub fn issue_token(user: &AuthenticatedUser, secret: &str) -> Result<(String, i64), MyError> {
+    let exp = (Utc::now() + Duration::hours(TOKEN_TTL_HOURS)).timestamp();
+    let token = encode(
+        &Header::default(),
+        &Claims {
+            sub: user.id,
+            exp,
+            tier: user.tier.clone(),
+            ver: user.key_version,
+        },
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .map_err(|_| MyError::Unauthorized)?;
+    Ok((token, exp))
+}
+
+/// Resolves the credentials of a request, if any, to a user id.
+///
+/// Accepts an API key in the `X-API-Key` header or an HS256 JWT, signed with
+/// `JWT_SECRET`, in `Authorization: Bearer`. Tokens of deleted users, or
+/// issued before the user's API key was replaced, are rejected. Returns
+/// `Ok(None)` for anonymous requests and an error for invalid credentials.
+async fn authenticate(req: &ServiceRequest) -> Result<Option<AuthenticatedUser>, MyError> {
+    if let Some(api_key) = req.headers().get(API_KEY_HEADER) {
+        let api_key = api_key.to_str().map_err(|_| MyError::Unauthorized)?;
+        let pool = req
+            .app_data::<web::Data<Pool>>()
+            .ok_or(MyError::Unauthorized)?;
+        let client = pool.get().await?;
+        let user = db::get_user_by_api_key_hash(&client, &hash_api_key(api_key))
+            .await?
+            .ok_or(MyError::Unauthorized)?;
+        return Ok(Some(AuthenticatedUser {
+            id: user.id,
+            tier: user.tier,
+            key_version: user.key_version,
+        }));
+    }
+
+    if let Some(authorization) = req.headers().get(actix_web::http::header::AUTHORIZATION) {
+        let token = authorization
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(MyError::Unauthorized)?;
+        let secret = req
+            .app_data::<web::Data<Config>>()
+            .and_then(|config| config.jwt_secret.clone())
+            .ok_or(MyError::Unauthorized)?;
+        let claims = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::default(),
+        )
+        .map_err(|_| MyError::Unauthorized)?
+        .claims;
+        let pool = req
+            .app_data::<web::Data<Pool>>()
+            .ok_or(MyError::Unauthorized)?;
+        let client = pool.get().await?;
+        let user = match db::get_user(&client, claims.sub).await {
+            Ok(user) => user,
+            Err(MyError::NotFound) => return Err(MyError::Unauthorized),
+            Err(e) => return Err(e),
+        };
+        if user.key_version != claims.ver {
+            return Err(MyError::Unauthorized);
+        }
+        return Ok(Some(AuthenticatedUser {
+            id: claims.sub,
+            tier: claims.tier,
+            key_version: claims.ver,
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Middleware that authenticates every request and stores the
+/// [`AuthenticatedUser`] in the request extensions.
+pub struct Authentication;
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            match authenticate(&req).await {
+                Ok(Some(user)) => {
+                    req.extensions_mut().insert(user);
+                }
+                Ok(None) => {}
+                Err(e) => return Err(e.into()),
+            }
+            service.call(req).await
+        })
+    }
+}
+
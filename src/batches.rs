@@ -0,0 +1,427 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use deadpool_postgres::Pool;
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::budget;
+use crate::config::Config;
+use crate::db;
+use crate::errors::MyError;
+use crate::models::{BatchItem, NewBatchItem};
+use crate::providers::{ChatProvider, ChatRequestBody};
+use crate::usage::{CompletionOutcome, PendingCompletion};
+use crate::{upstream_error, ChatPromptRequestBody};
+
+/// Reads the chat request of a line: the messages and, optionally, chat
+/// settings, as sent to `/chat/{chat_id}`.
+fn chat_request(request: &Value) -> Result<ChatPromptRequestBody, MyError> {
+    let request = ChatPromptRequestBody::deserialize(request)
+        .map_err(|e| MyError::BadRequest(format!("invalid chat request: {}", e)))?;
+    if request.messages.is_empty() {
+        return Err(MyError::BadRequest(
+            "invalid chat request: messages is empty".to_string(),
+        ));
+    }
+    request.settings.validate()?;
+    Ok(request)
+}
+
+/// Reads a batch file: one chat request per line, as a JSON object with
+/// `messages` and any chat settings, optionally under `body` with a
+/// `custom_id` beside it as in OpenAI's batch files. Blank lines are ignored.
+/// A line that cannot be read becomes a failed item rather than failing the
+/// file.
+pub fn parse(file: &str) -> Vec<NewBatchItem> {
+    file.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let parsed: Result<Value, String> =
+                serde_json::from_str(line).map_err(|e| format!("invalid JSON: {}", e));
+            let custom_id = parsed.as_ref().ok().and_then(|line| match &line["custom_id"] {
+                Value::String(id) => Some(id.clone()),
+                Value::Number(id) => Some(id.to_string()),
+                _ => None,
+            });
+            let request = parsed.and_then(|mut line| {
+                let request = match line.get_mut("body") {
+                    Some(body) if body.is_object() => body.take(),
+                    _ => line,
+                };
+                chat_request(&request).map_err(|e| e.to_string())?;
+                Ok(request)
+            });
+            NewBatchItem {
+                line: index as i32 + 1,
+                custom_id,
+                request,
+            }
+        })
+        .collect()
+}
+
+/// Runs the batch on a separate task. Must be called from within the actix
+/// runtime.
+pub fn spawn(pool: Pool, provider: Arc<dyn ChatProvider>, config: Config, batch_id: i32) {
+    actix_web::rt::spawn(async move {
+        if let Err(e) = run(&pool, provider.as_ref(), &config, batch_id, false).await {
+            eprintln!("Error running batch {}: {}", batch_id, e);
+        }
+    });
+}
+
+/// Picks up the batches left running when the server stopped. Must be called
+/// from within the actix runtime.
+pub fn resume(pool: Pool, provider: Arc<dyn ChatProvider>, config: Config) {
+    actix_web::rt::spawn(async move {
+        let batches = match pool.get().await {
+            Ok(client) => db::get_running_batches(&client).await,
+            Err(e) => Err(e.into()),
+        };
+        match batches {
+            Ok(batches) => {
+                for batch_id in batches {
+                    spawn(pool.clone(), provider.clone(), config.clone(), batch_id);
+                }
+            }
+            Err(e) => eprintln!("Error resuming batches: {}", e),
+        }
+    });
+}
+
+/// Runs the items of the batch that are left, `batch_concurrency` at a time,
+/// until none is, then marks the batch completed. Progress is saved after
+/// every item, so a batch that was stopped continues where it was.
+///
+/// Other processes may run the same batch at the same time; each item is run
+/// by one of them. Items another run left running are taken over once they
+/// look abandoned, or right away with `takeover`, for when that run is known
+/// to have stopped.
+pub async fn run(
+    pool: &Pool,
+    provider: &dyn ChatProvider,
+    config: &Config,
+    batch_id: i32,
+    takeover: bool,
+) -> Result<(), MyError> {
+    let app_user = db::get_batch(&pool.get().await?, batch_id).await?.app_user;
+    let stale_after = if takeover {
+        0.0
+    } else {
+        stale_after(config).as_secs_f64()
+    };
+    let claim_token = uuid::Uuid::new_v4().to_string();
+    let claim_token = claim_token.as_str();
+
+    stream::iter(0..config.batch_concurrency)
+        .for_each_concurrent(None, |_| async move {
+            loop {
+                let item = match pool.get().await {
+                    Ok(client) => db::claim_batch_item(&client, batch_id, stale_after, claim_token).await,
+                    Err(e) => Err(e.into()),
+                };
+                let item = match item {
+                    Ok(Some(item)) => item,
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("Error claiming an item of batch {}: {}", batch_id, e);
+                        break;
+                    }
+                };
+                let line = item.line;
+                if let Err(e) = run_item(pool, provider, config, app_user, item).await {
+                    eprintln!("Error saving line {} of batch {}: {}", line, batch_id, e);
+                }
+            }
+        })
+        .await;
+
+    db::complete_batch(&pool.get().await?, batch_id).await
+}
+
+/// How long an item may run before another worker takes it over: longer than
+/// every attempt with all of its retries can take.
+fn stale_after(config: &Config) -> Duration {
+    let request = Duration::from_secs(config.request_timeout_secs + config.retry_max_delay_secs);
+    request * (config.max_retries + 1) * config.batch_max_attempts + Duration::from_secs(60)
+}
+
+/// Runs one item, trying again while the provider fails or is unreachable,
+/// and saves its outcome.
+async fn run_item(
+    pool: &Pool,
+    provider: &dyn ChatProvider,
+    config: &Config,
+    app_user: i32,
+    mut item: BatchItem,
+) -> Result<(), MyError> {
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        match complete(pool, provider, config, app_user, &item).await {
+            Err(e) if attempts < config.batch_max_attempts && is_transient(&e) => {
+                tokio::time::sleep(retry_delay(config, attempts)).await;
+            }
+            result => break result,
+        }
+    };
+
+    item.attempts = attempts as i32;
+    match result {
+        Ok((response, prompt_tokens, completion_tokens, cost)) => {
+            item.status = "succeeded".to_string();
+            item.response = Some(response);
+            item.error = None;
+            item.prompt_tokens = Some(prompt_tokens);
+            item.completion_tokens = Some(completion_tokens);
+            item.cost = cost;
+        }
+        Err(e) => {
+            item.status = "failed".to_string();
+            item.error = Some(e.to_string());
+        }
+    }
+    if !db::finish_batch_item(&pool.get().await?, &item).await? {
+        eprintln!(
+            "Line {} of batch {} was taken over by another run; its result is dropped",
+            item.line, item.batch_id
+        );
+    }
+    Ok(())
+}
+
+/// Sends the item's request and records its usage. Returns the provider's
+/// response with the prompt and completion tokens and the cost.
+async fn complete(
+    pool: &Pool,
+    provider: &dyn ChatProvider,
+    config: &Config,
+    app_user: i32,
+    item: &BatchItem,
+) -> Result<(Value, i32, i32, Option<f64>), MyError> {
+    let request = item
+        .request
+        .as_ref()
+        .ok_or_else(|| MyError::BadRequest("the line has no request".to_string()))?;
+    let ChatPromptRequestBody { messages, settings } = chat_request(request)?;
+    budget::check(&pool.get().await?, app_user).await?;
+
+    let request = ChatRequestBody::new(messages, settings, &config.default_model);
+    let pending = PendingCompletion::start(app_user, None, &request);
+    let response = provider.chat_completion(&request).await?;
+    let response: Value = serde_json::from_str(&response)
+        .map_err(|e| upstream_error(format!("invalid JSON in completion: {}", e)))?;
+
+    let reply = response["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap_or_default();
+    let mut outcome = CompletionOutcome::default();
+    outcome.update(&response);
+    let completion = pending.finish(None, outcome, reply, &config.pricing);
+    let usage = (completion.prompt_tokens, completion.completion_tokens, completion.cost);
+    if let Err(e) = db::add_completion(&pool.get().await?, completion).await {
+        eprintln!("Error recording completion usage: {}", e);
+    }
+
+    Ok((response, usage.0, usage.1, usage.2))
+}
+
+/// Failures that may not happen again: the provider being unreachable, slow,
+/// rate limited or failing on its side, and the database being unavailable.
+fn is_transient(e: &MyError) -> bool {
+    match e {
+        MyError::Upstream { status, .. } => {
+            status.is_none_or(|status| status == 429 || status >= 500)
+        }
+        MyError::PoolError(_) => true,
+        _ => false,
+    }
+}
+
+/// Wait before attempt `attempts + 1`, doubling from `retry_base_delay_ms`
+/// up to `retry_max_delay_secs`.
+fn retry_delay(config: &Config, attempts: u32) -> Duration {
+    let delay = Duration::from_millis(config.retry_base_delay_ms)
+        .saturating_mul(2u32.saturating_pow(attempts));
+    delay.min(Duration::from_secs(config.retry_max_delay_secs))
+}
+
+/// The outcome of every item, one JSON object per line in file order:
+/// `line`, `custom_id`, `status` and `attempts`, then `response` and `usage`
+/// for a success or `error` for a failure.
+pub fn results(items: &[BatchItem]) -> String {
+    let mut results = String::new();
+    for item in items {
+        let mut result = json!({
+            "line": item.line,
+            "custom_id": item.custom_id,
+            "status": item.status,
+            "attempts": item.attempts,
+        });
+        if let Some(response) = &item.response {
+            result["response"] = response.clone();
+            result["usage"] = json!({
+                "prompt_tokens": item.prompt_tokens,
+                "completion_tokens": item.completion_tokens,
+                "cost": item.cost,
+            });
+        }
+        if let Some(error) = &item.error {
+            result["error"] = json!(error);
+        }
+        results.push_str(&result.to_string());
+        results.push('\n');
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(line: i32, status: &str) -> BatchItem {
+        BatchItem {
+            batch_id: 1,
+            line,
+            custom_id: None,
+            request: Some(json!({ "messages": [] })),
+            status: status.to_string(),
+            attempts: 1,
+            response: None,
+            error: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+            cost: None,
+            started_on: None,
+            finished_on: None,
+            claim_token: None,
+        }
+    }
+
+    #[test]
+    fn parses_plain_and_openai_style_lines() {
+        let file = concat!(
+            r#"{"messages": [{"role": "user", "content": "Hi"}], "temperature": 0.5}"#,
+            "\n",
+            r#"{"custom_id": "req-1", "method": "POST", "url": "/v1/chat/completions", "body": {"messages": [{"role": "user", "content": "Hello"}]}}"#,
+            "\n",
+            r#"{"custom_id": 7, "body": {"messages": [{"role": "user", "content": "Hey"}]}}"#,
+        );
+        let items = parse(file);
+        assert_eq!(items.len(), 3);
+
+        assert_eq!(items[0].line, 1);
+        assert_eq!(items[0].custom_id, None);
+        let request = items[0].request.as_ref().unwrap();
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["messages"][0]["content"], "Hi");
+
+        assert_eq!(items[1].custom_id.as_deref(), Some("req-1"));
+        let request = items[1].request.as_ref().unwrap();
+        assert_eq!(request["messages"][0]["content"], "Hello");
+        assert!(request.get("url").is_none());
+
+        assert_eq!(items[2].custom_id.as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn skips_blank_lines_but_keeps_line_numbers() {
+        let line = r#"{"messages": [{"role": "user", "content": "Hi"}]}"#;
+        let file = format!("\n{}\n   \n{}\n\n", line, line);
+        let lines: Vec<i32> = parse(&file).iter().map(|item| item.line).collect();
+        assert_eq!(lines, [2, 4]);
+    }
+
+    #[test]
+    fn turns_unreadable_lines_into_failed_items() {
+        let file = concat!(
+            "not json\n",
+            r#"{"custom_id": "empty", "body": {"messages": []}}"#,
+            "\n",
+            r#"{"messages": "Hi"}"#,
+            "\n",
+            r#"{"messages": [{"role": "user", "content": "Hi"}], "temperature": 5}"#,
+        );
+        let items = parse(file);
+        assert_eq!(items.len(), 4);
+        assert!(items[0].request.as_ref().unwrap_err().starts_with("invalid JSON"));
+        assert_eq!(items[1].custom_id.as_deref(), Some("empty"));
+        assert!(items[1].request.as_ref().unwrap_err().contains("messages is empty"));
+        assert!(items[2].request.as_ref().unwrap_err().contains("invalid chat request"));
+        assert!(items[3].request.is_err());
+    }
+
+    #[test]
+    fn writes_one_result_per_item() {
+        let mut succeeded = item(1, "succeeded");
+        succeeded.custom_id = Some("req-1".to_string());
+        succeeded.response = Some(json!({ "choices": [] }));
+        succeeded.prompt_tokens = Some(10);
+        succeeded.completion_tokens = Some(5);
+        succeeded.cost = Some(0.001);
+        let mut failed = item(3, "failed");
+        failed.attempts = 3;
+        failed.error = Some("upstream error: boom".to_string());
+
+        let results = results(&[succeeded, failed]);
+        let lines: Vec<Value> = results
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines[0],
+            json!({
+                "line": 1,
+                "custom_id": "req-1",
+                "status": "succeeded",
+                "attempts": 1,
+                "response": { "choices": [] },
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5, "cost": 0.001 },
+            })
+        );
+        assert_eq!(
+            lines[1],
+            json!({
+                "line": 3,
+                "custom_id": null,
+                "status": "failed",
+                "attempts": 3,
+                "error": "upstream error: boom",
+            })
+        );
+        assert!(results.ends_with('\n'));
+        assert_eq!(super::results(&[]), "");
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_max() {
+        let config = Config {
+            retry_base_delay_ms: 250,
+            retry_max_delay_secs: 2,
+            ..Default::default()
+        };
+        let delays: Vec<u128> = (0..6)
+            .map(|attempts| retry_delay(&config, attempts).as_millis())
+            .collect();
+        assert_eq!(delays, [250, 500, 1000, 2000, 2000, 2000]);
+        assert_eq!(retry_delay(&config, u32::MAX), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn retries_only_transient_errors() {
+        let upstream = |status| MyError::Upstream {
+            status,
+            code: None,
+            message: String::new(),
+            timed_out: false,
+        };
+        assert!(is_transient(&upstream(None)));
+        assert!(is_transient(&upstream(Some(429))));
+        assert!(is_transient(&upstream(Some(503))));
+        assert!(!is_transient(&upstream(Some(400))));
+        assert!(!is_transient(&MyError::BadRequest(String::new())));
+    }
+}
@@ -0,0 +1,124 @@
+use actix_web::HttpResponseBuilder;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use deadpool_postgres::Client;
+use serde::Serialize;
+
+use crate::db;
+use crate::errors::MyError;
+use crate::models::{Budget, Spending};
+
+/// What is left of a user's budget. Limits that are not set are `None`.
+#[derive(Debug, Default, Serialize)]
+pub struct Remaining {
+    pub daily_tokens: Option<i64>,
+    pub monthly_tokens: Option<i64>,
+    pub daily_cost: Option<f64>,
+    pub monthly_cost: Option<f64>,
+}
+
+impl Remaining {
+    fn new(budget: &Budget, spending: &Spending) -> Self {
+        Self {
+            daily_tokens: budget
+                .daily_tokens
+                .map(|limit| (limit - spending.daily_tokens).max(0)),
+            monthly_tokens: budget
+                .monthly_tokens
+                .map(|limit| (limit - spending.monthly_tokens).max(0)),
+            daily_cost: budget
+                .daily_cost
+                .map(|limit| (limit - spending.daily_cost).max(0.0)),
+            monthly_cost: budget
+                .monthly_cost
+                .map(|limit| (limit - spending.monthly_cost).max(0.0)),
+        }
+    }
+
+    fn daily_exhausted(&self) -> bool {
+        self.daily_tokens == Some(0) || self.daily_cost.is_some_and(|cost| cost <= 0.0)
+    }
+
+    fn monthly_exhausted(&self) -> bool {
+        self.monthly_tokens == Some(0) || self.monthly_cost.is_some_and(|cost| cost <= 0.0)
+    }
+
+    /// Adds an `X-Budget-Remaining-*` header for every limit that is set.
+    pub fn insert_headers(&self, response: &mut HttpResponseBuilder) {
+        if let Some(tokens) = self.daily_tokens {
+            response.insert_header(("X-Budget-Remaining-Tokens-Daily", tokens.to_string()));
+        }
+        if let Some(tokens) = self.monthly_tokens {
+            response.insert_header(("X-Budget-Remaining-Tokens-Monthly", tokens.to_string()));
+        }
+        if let Some(cost) = self.daily_cost {
+            response.insert_header(("X-Budget-Remaining-Cost-Daily", format!("{:.4}", cost)));
+        }
+        if let Some(cost) = self.monthly_cost {
+            response.insert_header(("X-Budget-Remaining-Cost-Monthly", format!("{:.4}", cost)));
+        }
+    }
+}
+
+/// The user's limits, what they spent and what is left, or `None` when no
+/// budget is set.
+pub async fn status(
+    client: &Client,
+    app_user: i32,
+) -> Result<Option<(Budget, Spending, Remaining)>, MyError> {
+    let budget = match db::get_budget(client, app_user).await? {
+        Some(budget) => budget,
+        None => return Ok(None),
+    };
+
+    let now = Utc::now();
+    let spending = db::get_spending(client, app_user, day_start(now), month_start(now)).await?;
+    let remaining = Remaining::new(&budget, &spending);
+
+    Ok(Some((budget, spending, remaining)))
+}
+
+/// Rejects the request with `budget_exceeded` when the user has used up a
+/// daily or monthly limit, and otherwise returns what is left.
+///
+/// The check runs before the model is called, so the last request of a period
+/// may overshoot the limit by its own cost.
+pub async fn check(client: &Client, app_user: i32) -> Result<Remaining, MyError> {
+    let remaining = match status(client, app_user).await? {
+        Some((_, _, remaining)) => remaining,
+        None => return Ok(Remaining::default()),
+    };
+
+    let now = Utc::now();
+    let (period, reset) = if remaining.monthly_exhausted() {
+        ("monthly", next_month_start(now))
+    } else if remaining.daily_exhausted() {
+        ("daily", day_start(now) + chrono::Duration::days(1))
+    } else {
+        return Ok(remaining);
+    };
+
+    Err(MyError::BudgetExceeded {
+        message: format!("{} budget exhausted, resets at {}", period, reset.to_rfc3339()),
+        retry_after: Some((reset - now).num_seconds().max(1) as u64),
+    })
+}
+
+fn day_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    midnight(now.date_naive())
+}
+
+fn month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    midnight(now.date_naive().with_day(1).unwrap_or_else(|| now.date_naive()))
+}
+
+fn next_month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = match now.month() {
+        12 => (now.year() + 1, 1),
+        month => (now.year(), month + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1).map_or(now, midnight)
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+}
@@ -1,36 +1,452 @@
+use config::Source;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 
 use dotenv::dotenv;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+
+use crate::tools::BUILTIN_TOOLS;
 
 #[derive(Debug, Default, Deserialize, Clone)]
 pub struct Config {
     pub server_addr: String,
+    pub workers: usize,
+    pub cors_origins: Vec<String>,
     pub pg: deadpool_postgres::Config,
     pub api_key: String,
+    pub provider: String,
+    pub provider_base_url: Option<String>,
+    pub provider_auth_header: Option<String>,
+    pub default_model: String,
+    pub image_model: String,
+    pub embedding_model: String,
+    pub connect_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_secs: u64,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown_secs: u64,
+    pub pricing: HashMap<String, ModelPrice>,
+    pub rate_limits: Vec<RateLimitRule>,
+    pub rate_limit_backend: String,
+    pub max_tool_rounds: u32,
+    pub builtin_tools: Vec<String>,
+    pub document_chunk_tokens: usize,
+    pub retrieval_top_k: usize,
+    pub vector_search: String,
+    pub semantic_search: bool,
+    pub auto_title: bool,
+    pub max_document_bytes: usize,
+    pub max_import_bytes: usize,
+    pub max_batch_bytes: usize,
+    pub batch_concurrency: usize,
+    pub batch_max_attempts: u32,
+    pub max_request_bytes: usize,
+    pub max_images_per_request: u32,
+    pub jwt_secret: Option<String>,
+    pub allow_signup: bool,
+    pub auto_migrate: bool,
+}
+
+/// Price of a model in dollars per 1000 tokens, or per image for image
+/// models.
+#[derive(Debug, Default, Deserialize, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+    pub per_image: f64,
+}
+
+/// A token bucket applied to the requests matching `route` from users in
+/// `tier` (any tier when unset).
+///
+/// `route` is a route pattern as registered, e.g. `/chat/{chat_id}`, a prefix
+/// ending in `*`, or `*` for every route. Each user (or client address, for
+/// anonymous requests) gets their own bucket per rule.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRule {
+    pub route: String,
+    pub tier: Option<String>,
+    /// Requests allowed in a burst.
+    pub capacity: u32,
+    /// Requests added back to the bucket per minute.
+    pub per_minute: f64,
+}
+
+impl RateLimitRule {
+    fn new(route: &str, capacity: u32, per_minute: f64) -> Self {
+        Self {
+            route: route.to_string(),
+            tier: None,
+            capacity,
+            per_minute,
+        }
+    }
+}
+
+/// Every problem found while loading the configuration, so they can all be
+/// fixed in one go.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads typed values out of the merged sources, recording a problem for each
+/// key that is missing or fails to parse instead of stopping at the first.
+struct Loader {
+    source: config::Config,
+    problems: Vec<String>,
+    /// Every top-level key asked for, to tell the unknown ones apart.
+    known: HashSet<String>,
+}
+
+impl Loader {
+    fn get<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        self.known.insert(key.to_string());
+        match self.source.get::<T>(key) {
+            Ok(value) => Some(value),
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(e) => {
+                self.problems.push(format!("{}: {}", key, e));
+                None
+            }
+        }
+    }
+
+    fn get_or<T: DeserializeOwned>(&mut self, key: &str, default: T) -> T {
+        self.get(key).unwrap_or(default)
+    }
+
+    fn require<T: DeserializeOwned + Default>(&mut self, key: &str) -> T {
+        let value = self.get(key);
+        let prefix = format!("{}:", key);
+        if value.is_none() && !self.problems.iter().any(|problem| problem.starts_with(&prefix)) {
+            self.problems.push(format!("{}: missing", key));
+        }
+        value.unwrap_or_default()
+    }
+
+    fn check(&mut self, ok: bool, problem: impl Into<String>) {
+        if !ok {
+            self.problems.push(problem.into());
+        }
+    }
+
+    /// Records a problem for each top-level key of `values` that was never
+    /// asked for, or key under `pg` that the pool does not have, as it is most
+    /// likely misspelled.
+    fn check_unknown(&mut self, values: config::Map<String, config::Value>) {
+        let pg_fields = serde_json::to_value(deadpool_postgres::Config {
+            manager: Some(Default::default()),
+            pool: Some(Default::default()),
+            ..Default::default()
+        })
+        .unwrap_or_default();
+
+        let mut unknown = Vec::new();
+        for (key, value) in values {
+            let key = key.to_lowercase();
+            if !self.known.contains(&key) {
+                unknown.push(key);
+            } else if key == "pg" {
+                unknown_fields(&key, value, &pg_fields, &mut unknown);
+            }
+        }
+        unknown.sort();
+        for key in unknown {
+            self.problems.push(format!("{}: unknown key", key));
+        }
+    }
+}
+
+/// Adds to `unknown` the path of each key in the table `value` that is not a
+/// field of `fields`, looking into nested tables.
+fn unknown_fields(
+    path: &str,
+    value: config::Value,
+    fields: &serde_json::Value,
+    unknown: &mut Vec<String>,
+) {
+    let (Ok(table), Some(fields)) = (value.into_table(), fields.as_object()) else {
+        return;
+    };
+    for (key, value) in table {
+        let key = key.to_lowercase();
+        let path = format!("{}.{}", path, key);
+        match fields.get(&key) {
+            Some(fields) => unknown_fields(&path, value, fields, unknown),
+            None => unknown.push(path),
+        }
+    }
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+    /// Loads the configuration from, in increasing order of precedence:
+    ///
+    /// 1. built-in defaults,
+    /// 2. `hjowdy.toml` / `hjowdy.yaml` in the working directory, if present,
+    /// 3. the file named by `HJOWDY_CONFIG`, if set,
+    /// 4. environment variables (and `.env`), using `.` to separate nested
+    ///    keys, e.g. `PG.POOL.MAX_SIZE`.
+    ///
+    /// Unknown top-level keys in the files are reported as problems. The
+    /// environment is not checked, as it holds many unrelated variables.
+    pub fn load() -> Result<Self, ConfigError> {
         dotenv().ok();
-        let server_addr = env::var("SERVER_ADDR")?;
-        let api_key = env::var("OPENAI_API_KEY").unwrap();
-        let pg = deadpool_postgres::Config {
-            user: Some(env::var("PG.USER")?),
-            password: Some(env::var("PG.PASSWORD")?),
-            host: Some(env::var("PG.HOST")?),
-            port: Some(env::var("PG.PORT")?.parse::<u16>()?),
-            dbname: Some(env::var("PG.DBNAME")?),
-            pool: Some(deadpool_postgres::PoolConfig {
-                max_size: env::var("PG.POOL.MAX_SIZE")?.parse::<usize>()?,
-                ..Default::default()
-            }),
-            ..Default::default()
+
+        let mut builder = config::Config::builder()
+            .add_source(config::File::with_name("hjowdy").required(false));
+        if let Ok(path) = env::var("HJOWDY_CONFIG") {
+            builder = builder.add_source(config::File::with_name(&path));
+        }
+        let to_config_error = |e: config::ConfigError| ConfigError {
+            problems: vec![e.to_string()],
         };
+        let files = builder.build().map_err(to_config_error)?;
+        let file_values = files.collect().map_err(to_config_error)?;
+        let source = config::Config::builder()
+            .add_source(files)
+            .add_source(
+                config::Environment::default()
+                    .separator(".")
+                    .try_parsing(true)
+                    .list_separator(",")
+                    .with_list_parse_key("cors_origins")
+                    .with_list_parse_key("builtin_tools"),
+            )
+            .build()
+            .map_err(to_config_error)?;
+
+        let mut loader = Loader {
+            source,
+            problems: Vec::new(),
+            known: HashSet::new(),
+        };
+
+        let server_addr = loader.get_or("server_addr", "127.0.0.1:8080".to_string());
+        let workers = loader.get_or("workers", num_workers());
+        let cors_origins = loader.get_or("cors_origins", vec!["*".to_string()]);
+        let pg: deadpool_postgres::Config = loader.require("pg");
+        // Both are read so neither is reported as unknown; `api_key` wins.
+        let api_key: Option<String> = loader.get("api_key");
+        let openai_api_key: Option<String> = loader.get("openai_api_key");
+        let api_key = api_key.or(openai_api_key).unwrap_or_default();
+        let provider = loader.get_or("provider", "openai".to_string());
+        let provider_base_url: Option<String> = loader.get("provider_base_url");
+        let provider_auth_header = loader.get("provider_auth_header");
+        let default_model = loader.get_or("default_model", "gpt-4".to_string());
+        let image_model = loader.get_or("image_model", "dall-e-2".to_string());
+        let embedding_model = loader.get_or("embedding_model", "text-embedding-ada-002".to_string());
+        let connect_timeout_secs = loader.get_or("connect_timeout_secs", 10);
+        let request_timeout_secs = loader.get_or("request_timeout_secs", 120);
+        let max_retries = loader.get_or("max_retries", 3);
+        let retry_base_delay_ms = loader.get_or("retry_base_delay_ms", 500);
+        let retry_max_delay_secs = loader.get_or("retry_max_delay_secs", 30);
+        let circuit_breaker_threshold = loader.get_or("circuit_breaker_threshold", 5);
+        let circuit_breaker_cooldown_secs = loader.get_or("circuit_breaker_cooldown_secs", 30);
+        let pricing: HashMap<String, ModelPrice> = loader.get_or("pricing", HashMap::new());
+        let rate_limits = loader.get_or(
+            "rate_limits",
+            vec![
+                RateLimitRule::new("/chat/{chat_id}", 20, 10.0),
+                RateLimitRule::new("/chat/{chat_id}/stream", 20, 10.0),
+                RateLimitRule::new("/images/generations", 5, 2.0),
+            ],
+        );
+        let rate_limit_backend = loader.get_or("rate_limit_backend", "memory".to_string());
+        let max_tool_rounds = loader.get_or("max_tool_rounds", 5);
+        let builtin_tools: Vec<String> = loader.get_or(
+            "builtin_tools",
+            BUILTIN_TOOLS.iter().map(|name| name.to_string()).collect(),
+        );
+        let document_chunk_tokens = loader.get_or("document_chunk_tokens", 500);
+        let retrieval_top_k = loader.get_or("retrieval_top_k", 4);
+        let vector_search = loader.get_or("vector_search", "array".to_string());
+        let semantic_search = loader.get_or("semantic_search", true);
+        let auto_title = loader.get_or("auto_title", true);
+        let max_document_bytes = loader.get_or("max_document_bytes", 10 * 1024 * 1024);
+        let max_import_bytes = loader.get_or("max_import_bytes", 100 * 1024 * 1024);
+        let max_batch_bytes = loader.get_or("max_batch_bytes", 10 * 1024 * 1024);
+        let batch_concurrency = loader.get_or("batch_concurrency", 4);
+        let batch_max_attempts = loader.get_or("batch_max_attempts", 3);
+        let max_request_bytes = loader.get_or("max_request_bytes", 1024 * 1024);
+        let max_images_per_request = loader.get_or("max_images_per_request", 4);
+        let jwt_secret: Option<String> = loader.get("jwt_secret");
+        let allow_signup = loader.get_or("allow_signup", false);
+        let auto_migrate = loader.get_or("auto_migrate", true);
+        loader.check_unknown(file_values);
+
+        loader.check(
+            server_addr.parse::<SocketAddr>().is_ok(),
+            format!("server_addr: \"{}\" is not a socket address", server_addr),
+        );
+        loader.check(workers > 0, "workers: must be at least 1");
+        loader.check(pg.user.is_some(), "pg.user: missing");
+        loader.check(pg.host.is_some(), "pg.host: missing");
+        loader.check(pg.dbname.is_some(), "pg.dbname: missing");
+        match provider.as_str() {
+            "openai" => loader.check(
+                !api_key.is_empty(),
+                "api_key: missing (set OPENAI_API_KEY)",
+            ),
+            "compatible" => loader.check(
+                provider_base_url.is_some(),
+                "provider_base_url: required when provider is \"compatible\"",
+            ),
+            other => loader.check(
+                false,
+                format!(
+                    "provider: unknown provider \"{}\", expected \"openai\" or \"compatible\"",
+                    other
+                ),
+            ),
+        }
+        loader.check(!default_model.is_empty(), "default_model: must not be empty");
+        loader.check(
+            connect_timeout_secs > 0,
+            "connect_timeout_secs: must be at least 1",
+        );
+        loader.check(
+            request_timeout_secs > 0,
+            "request_timeout_secs: must be at least 1",
+        );
+        loader.check(
+            retry_base_delay_ms > 0,
+            "retry_base_delay_ms: must be at least 1",
+        );
+        loader.check(
+            circuit_breaker_cooldown_secs > 0,
+            "circuit_breaker_cooldown_secs: must be at least 1",
+        );
+        for (model, price) in &pricing {
+            loader.check(
+                price.prompt_per_1k >= 0.0
+                    && price.completion_per_1k >= 0.0
+                    && price.per_image >= 0.0,
+                format!("pricing.{}: prices must not be negative", model),
+            );
+        }
+        for rule in &rate_limits {
+            loader.check(
+                rule.capacity > 0 && rule.per_minute > 0.0,
+                format!(
+                    "rate_limits: rule for \"{}\" needs a capacity and per_minute above 0",
+                    rule.route
+                ),
+            );
+        }
+        loader.check(
+            matches!(rate_limit_backend.as_str(), "memory" | "postgres"),
+            format!(
+                "rate_limit_backend: unknown backend \"{}\", expected \"memory\" or \"postgres\"",
+                rate_limit_backend
+            ),
+        );
+        for name in &builtin_tools {
+            loader.check(
+                BUILTIN_TOOLS.contains(&name.as_str()),
+                format!(
+                    "builtin_tools: unknown tool \"{}\", expected one of {}",
+                    name,
+                    BUILTIN_TOOLS.join(", ")
+                ),
+            );
+        }
+        loader.check(
+            document_chunk_tokens >= 50,
+            "document_chunk_tokens: must be at least 50",
+        );
+        loader.check(
+            matches!(vector_search.as_str(), "array" | "pgvector"),
+            format!(
+                "vector_search: unknown method \"{}\", expected \"array\" or \"pgvector\"",
+                vector_search
+            ),
+        );
+        loader.check(max_document_bytes > 0, "max_document_bytes: must be at least 1");
+        loader.check(max_import_bytes > 0, "max_import_bytes: must be at least 1");
+        loader.check(max_batch_bytes > 0, "max_batch_bytes: must be at least 1");
+        loader.check(batch_concurrency > 0, "batch_concurrency: must be at least 1");
+        loader.check(batch_max_attempts > 0, "batch_max_attempts: must be at least 1");
+        loader.check(max_request_bytes > 0, "max_request_bytes: must be at least 1");
+        loader.check(
+            max_images_per_request > 0,
+            "max_images_per_request: must be at least 1",
+        );
+        if let Some(jwt_secret) = &jwt_secret {
+            loader.check(
+                jwt_secret.len() >= 32,
+                "jwt_secret: must be at least 32 characters",
+            );
+        }
+
+        if !loader.problems.is_empty() {
+            return Err(ConfigError {
+                problems: loader.problems,
+            });
+        }
+
         Ok(Self {
             server_addr,
+            workers,
+            cors_origins,
             pg,
             api_key,
+            provider,
+            provider_base_url,
+            provider_auth_header,
+            default_model,
+            image_model,
+            embedding_model,
+            connect_timeout_secs,
+            request_timeout_secs,
+            max_retries,
+            retry_base_delay_ms,
+            retry_max_delay_secs,
+            circuit_breaker_threshold,
+            circuit_breaker_cooldown_secs,
+            pricing,
+            rate_limits,
+            rate_limit_backend,
+            max_tool_rounds,
+            builtin_tools,
+            document_chunk_tokens,
+            retrieval_top_k,
+            vector_search,
+            semantic_search,
+            auto_title,
+            max_document_bytes,
+            max_import_bytes,
+            max_batch_bytes,
+            batch_concurrency,
+            batch_max_attempts,
+            max_request_bytes,
+            max_images_per_request,
+            jwt_secret,
+            allow_signup,
+            auto_migrate,
         })
     }
 }
+
+fn num_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+
+use crate::errors::MyError;
+use crate::models::{ChatSettings, Message};
+use crate::tokenizer::{count_message_tokens, count_prompt_tokens};
+use crate::ChatCompletionMessage;
+
+/// Strategies for fitting a chat history into a model's context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextStrategy {
+    /// Keeps as many of the most recent messages as fit.
+    SlidingWindow,
+    /// Keeps the system prompt plus at most the last `n` other messages.
+    SystemAndLastN(usize),
+    /// Keeps the system prompt and every pinned message, then fills the rest
+    /// of the window with the most recent messages.
+    Pinned,
+}
+
+impl ContextStrategy {
+    pub const NAMES: [&'static str; 3] = ["sliding_window", "system_and_last_n", "pinned"];
+    const DEFAULT_LAST_N: usize = 10;
+
+    /// Reads the strategy stored in a chat's settings, defaulting to a sliding
+    /// window.
+    pub fn from_settings(settings: &ChatSettings) -> Result<Self, MyError> {
+        let last_n = settings
+            .context_last_n
+            .map(|n| n as usize)
+            .unwrap_or(Self::DEFAULT_LAST_N);
+
+        match settings.context_strategy.as_deref() {
+            None | Some("sliding_window") => Ok(ContextStrategy::SlidingWindow),
+            Some("system_and_last_n") => Ok(ContextStrategy::SystemAndLastN(last_n)),
+            Some("pinned") => Ok(ContextStrategy::Pinned),
+            Some(other) => Err(MyError::BadRequest(format!(
+                "context_strategy must be one of {}, got \"{}\"",
+                Self::NAMES.join(", "),
+                other
+            ))),
+        }
+    }
+}
+
+/// Selects the messages to send upstream so the prompt fits in `budget`
+/// tokens, keeping them in chronological order.
+///
+/// The newest message is always kept, even if it alone exceeds the budget.
+pub fn build_context(
+    messages: Vec<Message>,
+    strategy: ContextStrategy,
+    budget: usize,
+) -> Vec<ChatCompletionMessage> {
+    let last = match messages.len().checked_sub(1) {
+        Some(last) => last,
+        None => return Vec::new(),
+    };
+    let is_system = |message: &Message| message.role == "system";
+
+    let required: Vec<bool> = messages
+        .iter()
+        .enumerate()
+        .map(|(index, message)| {
+            index == last
+                || match strategy {
+                    ContextStrategy::SlidingWindow => false,
+                    ContextStrategy::SystemAndLastN(_) => is_system(message),
+                    ContextStrategy::Pinned => is_system(message) || message.pinned,
+                }
+        })
+        .collect();
+
+    // Optional messages, newest first, in the order they are added until the
+    // budget runs out.
+    let mut candidates: Vec<usize> = (0..last).rev().filter(|&index| !required[index]).collect();
+    if let ContextStrategy::SystemAndLastN(n) = strategy {
+        candidates.truncate(n.saturating_sub(1));
+    }
+
+    let completion_messages: Vec<ChatCompletionMessage> = messages
+        .into_iter()
+        .map(ChatCompletionMessage::from)
+        .collect();
+
+    let mut included = required;
+    let mut used = count_prompt_tokens(
+        &completion_messages
+            .iter()
+            .zip(&included)
+            .filter(|(_, included)| **included)
+            .map(|(message, _)| message.clone())
+            .collect::<Vec<_>>(),
+    );
+
+    for index in candidates {
+        let tokens = count_message_tokens(&completion_messages[index]);
+        if used + tokens > budget {
+            break;
+        }
+        used += tokens;
+        included[index] = true;
+    }
+
+    drop_broken_tool_calls(
+        completion_messages
+            .into_iter()
+            .zip(included)
+            .filter(|(_, included)| *included)
+            .map(|(message, _)| message)
+            .collect(),
+    )
+}
+
+/// Drops tool calls that trimming cut apart: `tool` messages whose assistant
+/// message was left out, and assistant messages whose tool results were not
+/// all kept. The API rejects both.
+fn drop_broken_tool_calls(messages: Vec<ChatCompletionMessage>) -> Vec<ChatCompletionMessage> {
+    let mut kept = Vec::with_capacity(messages.len());
+    let mut index = 0;
+    while index < messages.len() {
+        let message = &messages[index];
+        if message.role == "tool" {
+            index += 1;
+            continue;
+        }
+
+        let call_ids: Vec<&str> = message
+            .tool_calls
+            .as_ref()
+            .and_then(|tool_calls| tool_calls.as_array())
+            .map(|calls| calls.iter().filter_map(|call| call["id"].as_str()).collect())
+            .unwrap_or_default();
+        let results = messages[index + 1..]
+            .iter()
+            .take_while(|message| message.role == "tool")
+            .count();
+        let answered: HashSet<&str> = messages[index + 1..=index + results]
+            .iter()
+            .filter_map(|message| message.tool_call_id.as_deref())
+            .collect();
+
+        if call_ids.is_empty() {
+            kept.push(message.clone());
+            index += 1;
+        } else {
+            if call_ids.iter().all(|id| answered.contains(id)) {
+                kept.extend_from_slice(&messages[index..=index + results]);
+            }
+            index += results + 1;
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            id: None,
+            created_on: Utc::now(),
+            role: role.to_string(),
+            content: content.to_string(),
+            chat_id_relation: 1,
+            pinned: false,
+            parent_id: None,
+            tool_calls: None,
+            tool_call_id: None,
+            citations: None,
+        }
+    }
+
+    fn pinned(role: &str, content: &str) -> Message {
+        Message {
+            pinned: true,
+            ..message(role, content)
+        }
+    }
+
+    fn calling(ids: &[&str]) -> Message {
+        let calls: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                json!({
+                    "id": id,
+                    "type": "function",
+                    "function": { "name": "calculator", "arguments": "{}" },
+                })
+            })
+            .collect();
+        Message {
+            tool_calls: Some(json!(calls)),
+            ..message("assistant", "")
+        }
+    }
+
+    fn result(id: &str) -> Message {
+        Message {
+            tool_call_id: Some(id.to_string()),
+            ..message("tool", "4")
+        }
+    }
+
+    fn contents(messages: &[ChatCompletionMessage]) -> Vec<&str> {
+        messages.iter().map(|message| message.content.as_str()).collect()
+    }
+
+    /// The budget that fits exactly `messages`.
+    fn budget_for<'a>(messages: impl IntoIterator<Item = &'a Message>) -> usize {
+        count_prompt_tokens(
+            &messages
+                .into_iter()
+                .map(|message| ChatCompletionMessage {
+                    role: message.role.clone(),
+                    content: message.content.clone(),
+                    tool_calls: message.tool_calls.clone(),
+                    tool_call_id: message.tool_call_id.clone(),
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn history() -> Vec<Message> {
+        vec![
+            message("system", "You are terse."),
+            message("user", "one"),
+            message("assistant", "two"),
+            message("user", "three"),
+            message("assistant", "four"),
+            message("user", "five"),
+        ]
+    }
+
+    #[test]
+    fn sliding_window_keeps_the_newest_messages_that_fit() {
+        let messages = history();
+        let budget = budget_for(&messages[3..]);
+        let context = build_context(messages, ContextStrategy::SlidingWindow, budget);
+        assert_eq!(contents(&context), ["three", "four", "five"]);
+    }
+
+    #[test]
+    fn sliding_window_keeps_everything_that_fits() {
+        let messages = history();
+        let budget = budget_for(&messages);
+        let context = build_context(messages, ContextStrategy::SlidingWindow, budget);
+        assert_eq!(context.len(), 6);
+    }
+
+    #[test]
+    fn stops_at_the_first_message_over_budget() {
+        let messages = vec![
+            message("user", "old"),
+            message("assistant", &"long ".repeat(200)),
+            message("user", "new"),
+        ];
+        let budget = budget_for(&[message("user", "old"), message("user", "new")]);
+        let context = build_context(messages, ContextStrategy::SlidingWindow, budget);
+        assert_eq!(contents(&context), ["new"]);
+    }
+
+    #[test]
+    fn keeps_the_newest_message_over_budget() {
+        for strategy in [
+            ContextStrategy::SlidingWindow,
+            ContextStrategy::SystemAndLastN(3),
+            ContextStrategy::Pinned,
+        ] {
+            let messages = vec![message("user", "old"), message("user", &"long ".repeat(200))];
+            let context = build_context(messages, strategy, 10);
+            assert_eq!(context.len(), 1, "{:?}", strategy);
+            assert!(context[0].content.starts_with("long"), "{:?}", strategy);
+        }
+        assert!(build_context(Vec::new(), ContextStrategy::SlidingWindow, 10).is_empty());
+    }
+
+    #[test]
+    fn system_and_last_n_keeps_the_system_prompt_and_last_n() {
+        let context = build_context(history(), ContextStrategy::SystemAndLastN(2), 100_000);
+        assert_eq!(contents(&context), ["You are terse.", "four", "five"]);
+
+        // The system prompt is kept even when the window is too small for it.
+        let context = build_context(history(), ContextStrategy::SystemAndLastN(3), 0);
+        assert_eq!(contents(&context), ["You are terse.", "five"]);
+    }
+
+    #[test]
+    fn sliding_window_drops_the_system_prompt_first() {
+        let context = build_context(history(), ContextStrategy::SlidingWindow, 0);
+        assert_eq!(contents(&context), ["five"]);
+    }
+
+    #[test]
+    fn pinned_keeps_system_and_pinned_messages_then_the_newest() {
+        let mut messages = history();
+        messages[1] = pinned("user", "one");
+        let budget = budget_for([&messages[0], &messages[1], &messages[4], &messages[5]]);
+        let context = build_context(messages, ContextStrategy::Pinned, budget);
+        assert_eq!(contents(&context), ["You are terse.", "one", "four", "five"]);
+    }
+
+    #[test]
+    fn reads_strategy_from_settings() {
+        let mut settings = ChatSettings::default();
+        assert_eq!(
+            ContextStrategy::from_settings(&settings).unwrap(),
+            ContextStrategy::SlidingWindow
+        );
+
+        settings.context_strategy = Some("system_and_last_n".to_string());
+        assert_eq!(
+            ContextStrategy::from_settings(&settings).unwrap(),
+            ContextStrategy::SystemAndLastN(10)
+        );
+        settings.context_last_n = Some(4);
+        assert_eq!(
+            ContextStrategy::from_settings(&settings).unwrap(),
+            ContextStrategy::SystemAndLastN(4)
+        );
+
+        settings.context_strategy = Some("pinned".to_string());
+        assert_eq!(
+            ContextStrategy::from_settings(&settings).unwrap(),
+            ContextStrategy::Pinned
+        );
+
+        settings.context_strategy = Some("newest".to_string());
+        assert!(matches!(
+            ContextStrategy::from_settings(&settings),
+            Err(MyError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_tool_calls_with_all_their_results() {
+        let messages = vec![
+            message("user", "2+2 and 3+1?"),
+            calling(&["a", "b"]),
+            result("a"),
+            result("b"),
+            message("assistant", "4 and 4"),
+        ];
+        let context = build_context(messages, ContextStrategy::SlidingWindow, 100_000);
+        assert_eq!(context.len(), 5);
+    }
+
+    #[test]
+    fn drops_results_whose_call_was_trimmed() {
+        let messages = vec![
+            message("user", "2+2?"),
+            calling(&["a"]),
+            result("a"),
+            message("assistant", "4"),
+            message("user", "thanks"),
+        ];
+        let budget = budget_for(&messages[2..]);
+        let context = build_context(messages, ContextStrategy::SlidingWindow, budget);
+        assert_eq!(contents(&context), ["4", "thanks"]);
+    }
+
+    #[test]
+    fn drops_calls_missing_a_result() {
+        let messages = vec![
+            message("user", "2+2 and 3+1?"),
+            calling(&["a", "b"]),
+            result("a"),
+            message("user", "well?"),
+        ];
+        let context = drop_broken_tool_calls(
+            messages.into_iter().map(ChatCompletionMessage::from).collect(),
+        );
+        assert_eq!(contents(&context), ["2+2 and 3+1?", "well?"]);
+    }
+}
@@ -1,9 +1,15 @@
+use std::collections::HashMap;
+
 use chrono::{DateTime, Utc};
 use deadpool_postgres::{Client, PoolError};
 use tokio_pg_mapper::FromTokioPostgresRow;
 
 use crate::errors::MyError;
-use crate::models::{Chat, Message, Image};
+use crate::models::{
+    Batch, BatchItem, Branch, Budget, BudgetLimits, Chat, ChatSettings, ChatSummary, ChunkMatch,
+    Completion, Document, DocumentChunk, Image, Message, MessageEmbedding, MessageMatch,
+    ModelUsage, NewBatchItem, Note, SearchHit, SimilarMessage, Spending, UnembeddedMessage, User,
+};
 
 pub async fn delete_chat(client: &Client, chat_id: i32) -> Result<(), MyError> {
     let stmt = client
@@ -21,7 +27,7 @@ pub async fn delete_chat(client: &Client, chat_id: i32) -> Result<(), MyError> {
 
 pub async fn get_images_by_chat_id(client: &Client, chat_id: i32) -> Result<Vec<Image>, MyError> {
     let statement = client
-        .prepare("SELECT id, chat_id, url, created_on FROM generated_images WHERE chat_id = $1")
+        .prepare("SELECT id, chat_id, url, created_on FROM images WHERE chat_id = $1")
         .await?;
 
     let rows = client.query(&statement, &[&chat_id]).await?;
@@ -46,38 +52,35 @@ pub async fn get_messages_by_chat_id(
     let stmt = client
         .prepare(include_str!("../sql/get_messages_by_chat_id.sql"))
         .await
-        .unwrap();
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
 
-    let messages = client
+    client
         .query(&stmt, &[&chat_id])
         .await?
         .iter()
-        .map(|row| Message::from_row_ref(row).unwrap())
-        .collect::<Vec<Message>>();
-
-    Ok(messages)
+        .map(|row| Ok(Message::from_row_ref(row)?))
+        .collect()
 }
 
 pub async fn get_chats(client: &Client, app_user: i32) -> Result<Vec<Chat>, MyError> {
     let _stmt = include_str!("../sql/get_chats.sql");
     let stmt = client
-        .prepare(&_stmt)
+        .prepare(_stmt)
         .await
         .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
 
-    let chats = client
+    client
         .query(&stmt, &[&app_user])
         .await?
         .iter()
-        .map(|row| Chat::from_row_ref(row).unwrap())
-        .collect::<Vec<Chat>>();
-    Ok(chats)
+        .map(|row| Ok(Chat::from_row_ref(row)?))
+        .collect()
 }
 
 pub async fn create_chat(client: &Client, app_user: i32) -> Result<Chat, MyError> {
     let _stmt = include_str!("../sql/create_chat.sql");
     let stmt = client
-        .prepare(&_stmt)
+        .prepare(_stmt)
         .await
         .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
 
@@ -88,17 +91,140 @@ pub async fn create_chat(client: &Client, app_user: i32) -> Result<Chat, MyError
         .await
         .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
 
-    Ok(Chat {
-        chat_id: row.get(0),
-        app_user: row.get(1),
-        created_on: row.get(2),
-        chat_name: row.get(3),
-    })
+    Ok(Chat::from_row_ref(&row)?)
+}
+
+/// Adds an imported chat and its messages in one transaction, so a chat that
+/// fails part way leaves nothing behind. Each message comes with the index of
+/// its parent among the ones before it, and `active` is the index of the leaf
+/// to open.
+pub async fn add_imported_chat(
+    client: &mut Client,
+    app_user: i32,
+    created_on: DateTime<Utc>,
+    chat_name: &str,
+    import_id: Option<&str>,
+    messages: &[(Option<usize>, Message)],
+    active: Option<usize>,
+) -> Result<Chat, MyError> {
+    let transaction = client.transaction().await?;
+    let chat_stmt = transaction
+        .prepare(include_str!("../sql/create_imported_chat.sql"))
+        .await?;
+    let message_stmt = transaction.prepare(include_str!("../sql/add_message.sql")).await?;
+    let active_stmt = transaction
+        .prepare(include_str!("../sql/set_active_message.sql"))
+        .await?;
+
+    let row = transaction
+        .query_one(&chat_stmt, &[&app_user, &created_on, &chat_name, &import_id])
+        .await?;
+    let chat = Chat::from_row_ref(&row)?;
+
+    // A new message follows the active leaf, so only branches need a switch.
+    let mut ids: Vec<i32> = Vec::with_capacity(messages.len());
+    let mut leaf: Option<i32> = None;
+    for (parent, message) in messages {
+        let parent_id = parent.map(|parent| ids[parent]);
+        if parent_id != leaf {
+            transaction.execute(&active_stmt, &[&chat.chat_id, &parent_id]).await?;
+        }
+        let row = transaction
+            .query_one(
+                &message_stmt,
+                &[
+                    &chat.chat_id,
+                    &message.role,
+                    &message.content,
+                    &message.tool_calls,
+                    &message.tool_call_id,
+                    &message.citations,
+                    &message.created_on,
+                ],
+            )
+            .await?;
+        ids.push(row.get(0));
+        leaf = Some(row.get(0));
+    }
+
+    let active = active.map(|active| ids[active]);
+    if active != leaf {
+        transaction.execute(&active_stmt, &[&chat.chat_id, &active]).await?;
+    }
+    transaction.commit().await?;
+
+    Ok(chat)
+}
+
+/// Whether `app_user` already has a chat imported from `import_id`.
+pub async fn chat_import_exists(
+    client: &Client,
+    app_user: i32,
+    import_id: &str,
+) -> Result<bool, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/chat_import_exists.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(client.query_one(&stmt, &[&app_user, &import_id]).await?.get(0))
+}
+
+pub async fn get_chat(client: &Client, chat_id: i32) -> Result<Chat, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_chat.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_opt(&stmt, &[&chat_id])
+        .await?
+        .ok_or(MyError::NotFound)?;
+
+    Ok(Chat::from_row_ref(&row)?)
+}
+
+pub async fn update_chat_settings(
+    client: &Client,
+    chat_id: i32,
+    settings: &ChatSettings,
+) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/update_chat_settings.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .execute(
+            &stmt,
+            &[
+                &chat_id,
+                &settings.model,
+                &settings.temperature,
+                &settings.top_p,
+                &settings.max_tokens,
+                &settings.stop,
+                &settings.presence_penalty,
+                &settings.frequency_penalty,
+                &settings.seed,
+                &settings.response_format,
+                &settings.context_strategy,
+                &settings.context_last_n,
+                &settings.auto_title,
+            ],
+        )
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(())
 }
 
 pub async fn add_message(client: &Client, message_info: Message) -> Result<Message, MyError> {
     let _stmt = include_str!("../sql/add_message.sql");
-    let stmt = client.prepare(&_stmt).await.unwrap();
+    let stmt = client
+        .prepare(_stmt)
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
 
     let row = client
         .query_one(
@@ -107,6 +233,10 @@ pub async fn add_message(client: &Client, message_info: Message) -> Result<Messa
                 &message_info.chat_id_relation,
                 &message_info.role,
                 &message_info.content,
+                &message_info.tool_calls,
+                &message_info.tool_call_id,
+                &message_info.citations,
+                &message_info.created_on,
             ],
         )
         .await?;
@@ -117,9 +247,273 @@ pub async fn add_message(client: &Client, message_info: Message) -> Result<Messa
         role: row.get(2),
         content: row.get(3),
         chat_id_relation: row.get(4),
+        pinned: row.get(5),
+        parent_id: row.get(6),
+        tool_calls: row.get(7),
+        tool_call_id: row.get(8),
+        citations: row.get(9),
     })
 }
 
+pub async fn add_completion(client: &Client, completion: Completion) -> Result<Completion, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/add_completion.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_one(
+            &stmt,
+            &[
+                &completion.app_user,
+                &completion.chat_id,
+                &completion.message_id,
+                &completion.model,
+                &completion.prompt_tokens,
+                &completion.completion_tokens,
+                &completion.finish_reason,
+                &completion.latency_ms,
+                &completion.cost,
+            ],
+        )
+        .await?;
+
+    Ok(Completion::from_row_ref(&row)?)
+}
+
+pub async fn get_completions_by_chat_id(
+    client: &Client,
+    chat_id: i32,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Vec<Completion>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_completions_by_chat_id.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&chat_id, &from, &to])
+        .await?
+        .iter()
+        .map(|row| Ok(Completion::from_row_ref(row)?))
+        .collect()
+}
+
+pub async fn get_usage_by_chat_id(
+    client: &Client,
+    chat_id: i32,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Vec<ModelUsage>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_usage_by_chat_id.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&chat_id, &from, &to])
+        .await?
+        .iter()
+        .map(|row| Ok(ModelUsage::from_row_ref(row)?))
+        .collect()
+}
+
+pub async fn get_usage_by_user(
+    client: &Client,
+    app_user: i32,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Vec<ModelUsage>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_usage_by_user.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&app_user, &from, &to])
+        .await?
+        .iter()
+        .map(|row| Ok(ModelUsage::from_row_ref(row)?))
+        .collect()
+}
+
+pub async fn get_message(client: &Client, chat_id: i32, message_id: i32) -> Result<Message, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_message.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_opt(&stmt, &[&chat_id, &message_id])
+        .await?
+        .ok_or(MyError::NotFound)?;
+
+    Ok(Message::from_row_ref(&row)?)
+}
+
+/// Every message of the chat, across all branches, oldest first.
+pub async fn get_message_tree(client: &Client, chat_id: i32) -> Result<Vec<Message>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_message_tree.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&chat_id])
+        .await?
+        .iter()
+        .map(|row| Ok(Message::from_row_ref(row)?))
+        .collect()
+}
+
+pub async fn get_branches(client: &Client, chat_id: i32) -> Result<Vec<Branch>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_branches.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&chat_id])
+        .await?
+        .iter()
+        .map(|row| Ok(Branch::from_row_ref(row)?))
+        .collect()
+}
+
+/// Makes `message_id` the chat's active leaf, so the next message continues
+/// from it. `None` starts a new root.
+pub async fn set_active_message(
+    client: &Client,
+    chat_id: i32,
+    message_id: Option<i32>,
+) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/set_active_message.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .execute(&stmt, &[&chat_id, &message_id])
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(())
+}
+
+/// Activates the newest leaf under `message_id` and returns its id.
+pub async fn switch_branch(client: &Client, chat_id: i32, message_id: i32) -> Result<i32, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/switch_branch.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_opt(&stmt, &[&chat_id, &message_id])
+        .await?
+        .ok_or(MyError::NotFound)?;
+
+    Ok(row.get(0))
+}
+
+pub async fn set_message_pinned(
+    client: &Client,
+    chat_id: i32,
+    message_id: i32,
+    pinned: bool,
+) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/set_message_pinned.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let updated = client
+        .execute(&stmt, &[&chat_id, &message_id, &pinned])
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    if updated == 0 {
+        return Err(MyError::NotFound);
+    }
+
+    Ok(())
+}
+
+/// Rates an assistant reply: 1 for good, -1 for bad, or `None` to clear it.
+pub async fn set_message_rating(
+    client: &Client,
+    chat_id: i32,
+    message_id: i32,
+    rating: Option<i16>,
+) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/set_message_rating.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let updated = client
+        .execute(&stmt, &[&chat_id, &message_id, &rating])
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    if updated == 0 {
+        return Err(MyError::NotFound);
+    }
+
+    Ok(())
+}
+
+/// The ratings of the rated messages of a chat, by message id.
+pub async fn get_message_ratings(
+    client: &Client,
+    chat_id: i32,
+) -> Result<HashMap<i32, i16>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_message_ratings.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(client
+        .query(&stmt, &[&chat_id])
+        .await?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
+
+pub async fn set_chat_tags(client: &Client, chat_id: i32, tags: &[String]) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/set_chat_tags.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client.execute(&stmt, &[&chat_id, &tags]).await?;
+
+    Ok(())
+}
+
+/// The chats of `app_user` to build a fine-tuning dataset from, oldest
+/// first. Empty `chat_ids` or `tags` do not filter.
+pub async fn get_fine_tuning_chats(
+    client: &Client,
+    app_user: i32,
+    chat_ids: &[i32],
+    tags: &[String],
+    rated: bool,
+) -> Result<Vec<Chat>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_fine_tuning_chats.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&app_user, &chat_ids, &tags, &rated])
+        .await?
+        .iter()
+        .map(|row| Ok(Chat::from_row_ref(row)?))
+        .collect()
+}
+
 pub async fn update_chat_name(
     client: &Client,
     chat_id: i32,
@@ -139,10 +533,26 @@ pub async fn update_chat_name(
 }
 
 
+/// Turns automatic titles on or off for the chat; `None` follows the
+/// `auto_title` setting.
+pub async fn set_chat_auto_title(
+    client: &Client,
+    chat_id: i32,
+    auto_title: Option<bool>,
+) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/set_chat_auto_title.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client.execute(&stmt, &[&chat_id, &auto_title]).await?;
+    Ok(())
+}
+
 pub async fn save_generated_image(client: &Client, chat_id: i32, url: String) -> Result<Image, MyError> {
     let _stmt = include_str!("../sql/save_generated_image.sql");
     let stmt = client
-        .prepare(&_stmt)
+        .prepare(_stmt)
         .await
         .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
 
@@ -160,3 +570,647 @@ pub async fn save_generated_image(client: &Client, chat_id: i32, url: String) ->
         created_on: row.get(3),
     })
 }
+
+pub async fn get_chat_summary(
+    client: &Client,
+    chat_id: i32,
+) -> Result<Option<ChatSummary>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_chat_summary.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client.query_opt(&stmt, &[&chat_id]).await?;
+
+    Ok(row.map(|row| ChatSummary::from_row_ref(&row)).transpose()?)
+}
+
+pub async fn upsert_chat_summary(
+    client: &Client,
+    chat_id: i32,
+    content: &str,
+    last_message_id: i32,
+) -> Result<ChatSummary, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/upsert_chat_summary.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_one(&stmt, &[&chat_id, &content, &last_message_id])
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(ChatSummary::from_row_ref(&row)?)
+}
+
+pub async fn create_user(
+    client: &Client,
+    username: &str,
+    api_key_hash: &str,
+) -> Result<User, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/create_user.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_one(&stmt, &[&username, &api_key_hash])
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(User::from_row_ref(&row)?)
+}
+
+pub async fn get_user(client: &Client, user_id: i32) -> Result<User, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_user.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_opt(&stmt, &[&user_id])
+        .await?
+        .ok_or(MyError::NotFound)?;
+
+    Ok(User::from_row_ref(&row)?)
+}
+
+pub async fn get_user_by_api_key_hash(
+    client: &Client,
+    api_key_hash: &str,
+) -> Result<Option<User>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_user_by_api_key_hash.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client.query_opt(&stmt, &[&api_key_hash]).await?;
+
+    Ok(row.map(|row| User::from_row_ref(&row)).transpose()?)
+}
+
+pub async fn update_user_api_key(
+    client: &Client,
+    user_id: i32,
+    api_key_hash: &str,
+) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/update_user_api_key.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .execute(&stmt, &[&user_id, &api_key_hash])
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(())
+}
+
+pub async fn get_budget(client: &Client, app_user: i32) -> Result<Option<Budget>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_budget.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client.query_opt(&stmt, &[&app_user]).await?;
+
+    Ok(row.map(|row| Budget::from_row_ref(&row)).transpose()?)
+}
+
+pub async fn upsert_budget(
+    client: &Client,
+    app_user: i32,
+    limits: &BudgetLimits,
+) -> Result<Budget, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/upsert_budget.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_one(
+            &stmt,
+            &[
+                &app_user,
+                &limits.daily_tokens,
+                &limits.monthly_tokens,
+                &limits.daily_cost,
+                &limits.monthly_cost,
+            ],
+        )
+        .await?;
+
+    Ok(Budget::from_row_ref(&row)?)
+}
+
+/// What `app_user` spent since `day_start` and since `month_start`.
+pub async fn get_spending(
+    client: &Client,
+    app_user: i32,
+    day_start: DateTime<Utc>,
+    month_start: DateTime<Utc>,
+) -> Result<Spending, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_spending.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_one(&stmt, &[&app_user, &day_start, &month_start])
+        .await?;
+
+    Ok(Spending::from_row_ref(&row)?)
+}
+
+pub async fn update_user_tier(client: &Client, user_id: i32, tier: &str) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/update_user_tier.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let updated = client
+        .execute(&stmt, &[&user_id, &tier])
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    if updated == 0 {
+        return Err(MyError::NotFound);
+    }
+
+    Ok(())
+}
+
+/// Takes a token from the shared bucket `key` and returns the tokens left and
+/// whether one was taken.
+pub async fn take_rate_limit_token(
+    client: &Client,
+    key: &str,
+    capacity: f64,
+    per_second: f64,
+) -> Result<(f64, bool), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/take_rate_limit_token.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_one(&stmt, &[&key, &capacity, &per_second])
+        .await?;
+
+    Ok((row.get(0), row.get(1)))
+}
+
+/// Deletes the rate limit buckets that have refilled to capacity. Returns how
+/// many were deleted.
+pub async fn delete_full_rate_limit_buckets(client: &Client) -> Result<u64, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/delete_full_rate_limit_buckets.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(client.execute(&stmt, &[]).await?)
+}
+
+/// The newest user and assistant messages of `app_user`'s chats that contain
+/// `query`, ignoring case.
+pub async fn search_user_messages(
+    client: &Client,
+    app_user: i32,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<MessageMatch>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/search_user_messages.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&app_user, &query, &limit])
+        .await?
+        .iter()
+        .map(|row| Ok(MessageMatch::from_row_ref(row)?))
+        .collect()
+}
+
+pub async fn get_notes(client: &Client, app_user: i32) -> Result<Vec<Note>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_notes.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&app_user])
+        .await?
+        .iter()
+        .map(|row| Ok(Note::from_row_ref(row)?))
+        .collect()
+}
+
+pub async fn get_note(client: &Client, app_user: i32, key: &str) -> Result<Option<Note>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_note.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client.query_opt(&stmt, &[&app_user, &key]).await?;
+
+    Ok(row.map(|row| Note::from_row_ref(&row)).transpose()?)
+}
+
+pub async fn upsert_note(
+    client: &Client,
+    app_user: i32,
+    key: &str,
+    value: &str,
+) -> Result<Note, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/upsert_note.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client.query_one(&stmt, &[&app_user, &key, &value]).await?;
+
+    Ok(Note::from_row_ref(&row)?)
+}
+
+/// Deletes a note and returns whether it existed.
+pub async fn delete_note(client: &Client, app_user: i32, key: &str) -> Result<bool, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/delete_note.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let deleted = client.execute(&stmt, &[&app_user, &key]).await?;
+
+    Ok(deleted > 0)
+}
+
+/// Adds a document and its chunks, with their embeddings, in one transaction.
+pub async fn add_document(
+    client: &mut Client,
+    chat_id: i32,
+    name: &str,
+    content_type: &str,
+    chunks: &[(String, Vec<f32>)],
+) -> Result<Document, MyError> {
+    let transaction = client.transaction().await?;
+    let document_stmt = transaction.prepare(include_str!("../sql/add_document.sql")).await?;
+    let chunk_stmt = transaction
+        .prepare(include_str!("../sql/add_document_chunk.sql"))
+        .await?;
+
+    let row = transaction
+        .query_one(&document_stmt, &[&chat_id, &name, &content_type])
+        .await?;
+    let mut document = Document::from_row_ref(&row)?;
+
+    for (index, (content, embedding)) in chunks.iter().enumerate() {
+        transaction
+            .execute(&chunk_stmt, &[&document.id, &(index as i32), content, embedding])
+            .await?;
+    }
+    transaction.commit().await?;
+
+    document.chunks = chunks.len() as i64;
+    Ok(document)
+}
+
+pub async fn get_documents_by_chat_id(client: &Client, chat_id: i32) -> Result<Vec<Document>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_documents_by_chat_id.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&chat_id])
+        .await?
+        .iter()
+        .map(|row| Ok(Document::from_row_ref(row)?))
+        .collect()
+}
+
+pub async fn delete_document(client: &Client, chat_id: i32, id: i32) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/delete_document.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let deleted = client.execute(&stmt, &[&chat_id, &id]).await?;
+    if deleted == 0 {
+        return Err(MyError::NotFound);
+    }
+
+    Ok(())
+}
+
+pub async fn chat_has_documents(client: &Client, chat_id: i32) -> Result<bool, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/chat_has_documents.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client.query_one(&stmt, &[&chat_id]).await?;
+
+    Ok(row.get(0))
+}
+
+/// Every chunk of the chat's documents, with its embedding.
+pub async fn get_document_chunks_by_chat_id(
+    client: &Client,
+    chat_id: i32,
+) -> Result<Vec<DocumentChunk>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_document_chunks_by_chat_id.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&chat_id])
+        .await?
+        .iter()
+        .map(|row| Ok(DocumentChunk::from_row_ref(row)?))
+        .collect()
+}
+
+/// The `limit` chunks of the chat's documents closest to `embedding`, using
+/// pgvector.
+pub async fn search_document_chunks(
+    client: &Client,
+    chat_id: i32,
+    embedding: &[f32],
+    limit: i64,
+) -> Result<Vec<ChunkMatch>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/search_document_chunks.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&chat_id, &embedding, &limit])
+        .await?
+        .iter()
+        .map(|row| Ok(ChunkMatch::from_row_ref(row)?))
+        .collect()
+}
+
+/// A page of the chats and messages of `app_user` matching `query`, in web
+/// search syntax, best first.
+pub async fn search(
+    client: &Client,
+    app_user: i32,
+    query: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<SearchHit>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/search.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&app_user, &query, &limit, &offset])
+        .await?
+        .iter()
+        .map(|row| Ok(SearchHit::from_row_ref(row)?))
+        .collect()
+}
+
+/// Up to `limit` messages that still need an embedding, oldest first.
+pub async fn get_unembedded_messages(
+    client: &Client,
+    limit: i64,
+    max_attempts: i32,
+) -> Result<Vec<UnembeddedMessage>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_unembedded_messages.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&limit, &max_attempts])
+        .await?
+        .iter()
+        .map(|row| Ok(UnembeddedMessage::from_row_ref(row)?))
+        .collect()
+}
+
+/// Stores the embedding of a message, unless it already has one.
+pub async fn add_message_embedding(
+    client: &Client,
+    message_id: i32,
+    embedding: &[f32],
+) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/add_message_embedding.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client.execute(&stmt, &[&message_id, &embedding]).await?;
+    Ok(())
+}
+
+/// Records that a message could not be embedded, and when to try it again.
+pub async fn add_message_embedding_failure(
+    client: &Client,
+    message_id: i32,
+    attempts: i32,
+    error: &str,
+    retry_after: DateTime<Utc>,
+) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/add_message_embedding_failure.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .execute(&stmt, &[&message_id, &attempts, &error, &retry_after])
+        .await?;
+    Ok(())
+}
+
+pub async fn get_message_embeddings_by_user(
+    client: &Client,
+    app_user: i32,
+) -> Result<Vec<MessageEmbedding>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_message_embeddings_by_user.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&app_user])
+        .await?
+        .iter()
+        .map(|row| Ok(MessageEmbedding::from_row_ref(row)?))
+        .collect()
+}
+
+/// The `limit` messages of `app_user` closest to `embedding`, using pgvector.
+pub async fn search_message_embeddings(
+    client: &Client,
+    app_user: i32,
+    embedding: &[f32],
+    limit: i64,
+) -> Result<Vec<SimilarMessage>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/search_message_embeddings.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&app_user, &embedding, &limit])
+        .await?
+        .iter()
+        .map(|row| Ok(SimilarMessage::from_row_ref(row)?))
+        .collect()
+}
+
+/// Creates a batch of `app_user` with its items and returns its id.
+pub async fn create_batch(
+    client: &Client,
+    app_user: i32,
+    items: &[NewBatchItem],
+) -> Result<i32, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/create_batch.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let lines: Vec<i32> = items.iter().map(|item| item.line).collect();
+    let custom_ids: Vec<Option<&str>> = items.iter().map(|item| item.custom_id.as_deref()).collect();
+    let requests: Vec<Option<&serde_json::Value>> =
+        items.iter().map(|item| item.request.as_ref().ok()).collect();
+    let errors: Vec<Option<&str>> = items
+        .iter()
+        .map(|item| item.request.as_ref().err().map(String::as_str))
+        .collect();
+
+    let row = client
+        .query_one(&stmt, &[&app_user, &lines, &custom_ids, &requests, &errors])
+        .await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn get_batch(client: &Client, batch_id: i32) -> Result<Batch, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_batch.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let row = client
+        .query_opt(&stmt, &[&batch_id])
+        .await?
+        .ok_or(MyError::NotFound)?;
+
+    Ok(Batch::from_row_ref(&row)?)
+}
+
+/// The batches of `app_user`, newest first.
+pub async fn get_batches(client: &Client, app_user: i32) -> Result<Vec<Batch>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_batches.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&app_user])
+        .await?
+        .iter()
+        .map(|row| Ok(Batch::from_row_ref(row)?))
+        .collect()
+}
+
+/// Ids of the batches that still have items to run.
+pub async fn get_running_batches(client: &Client) -> Result<Vec<i32>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_running_batches.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    Ok(client
+        .query(&stmt, &[])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect())
+}
+
+/// Marks the next item of the batch to run as running and returns it, taking
+/// over items that have been running for more than `stale_after_secs`.
+pub async fn claim_batch_item(
+    client: &Client,
+    batch_id: i32,
+    stale_after_secs: f64,
+    claim_token: &str,
+) -> Result<Option<BatchItem>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/claim_batch_item.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query_opt(&stmt, &[&batch_id, &stale_after_secs, &claim_token])
+        .await?
+        .map(|row| Ok(BatchItem::from_row_ref(&row)?))
+        .transpose()
+}
+
+/// Saves the outcome of an item: its status, response or error, usage and
+/// attempts. Returns `false`, saving nothing, when another run has taken the
+/// item over.
+pub async fn finish_batch_item(client: &Client, item: &BatchItem) -> Result<bool, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/finish_batch_item.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    let updated = client
+        .execute(
+            &stmt,
+            &[
+                &item.batch_id,
+                &item.line,
+                &item.status,
+                &item.response,
+                &item.error,
+                &item.prompt_tokens,
+                &item.completion_tokens,
+                &item.cost,
+                &item.attempts,
+                &item.claim_token,
+            ],
+        )
+        .await?;
+
+    Ok(updated > 0)
+}
+
+/// Marks the batch completed if none of its items is left to run.
+pub async fn complete_batch(client: &Client, batch_id: i32) -> Result<(), MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/complete_batch.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client.execute(&stmt, &[&batch_id]).await?;
+
+    Ok(())
+}
+
+/// Every item of the batch, in file order.
+pub async fn get_batch_items(client: &Client, batch_id: i32) -> Result<Vec<BatchItem>, MyError> {
+    let stmt = client
+        .prepare(include_str!("../sql/get_batch_items.sql"))
+        .await
+        .map_err(|e| MyError::PoolError(PoolError::Backend(e)))?;
+
+    client
+        .query(&stmt, &[&batch_id])
+        .await?
+        .iter()
+        .map(|row| Ok(BatchItem::from_row_ref(row)?))
+        .collect()
+}
@@ -0,0 +1,272 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use actix_web::web;
+use bytes::Bytes;
+use deadpool_postgres::Client;
+use serde_json::json;
+
+use crate::config::Config;
+use crate::db;
+use crate::errors::MyError;
+use crate::models::{ChunkMatch, DocumentChunk};
+use crate::providers::{EmbeddingProvider, EmbeddingRequestBody};
+use crate::tokenizer::count_tokens;
+use crate::usage::PendingCompletion;
+use crate::ChatCompletionMessage;
+
+/// Content types documents can be uploaded as.
+pub const CONTENT_TYPES: [&str; 3] = ["text/plain", "text/markdown", "application/pdf"];
+
+/// Inputs sent in one embeddings request.
+const EMBEDDING_BATCH: usize = 100;
+
+/// The content type of an upload: the declared one when it is supported,
+/// otherwise the one matching the file name's extension.
+pub fn content_type(declared: Option<&str>, name: &str) -> Result<&'static str, MyError> {
+    if let Some(content_type) = declared
+        .and_then(|declared| CONTENT_TYPES.iter().find(|supported| **supported == declared))
+    {
+        return Ok(content_type);
+    }
+
+    let extension = name.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("txt") => Ok("text/plain"),
+        Some("md" | "markdown") => Ok("text/markdown"),
+        Some("pdf") => Ok("application/pdf"),
+        _ => Err(MyError::BadRequest(format!(
+            "unsupported document type, expected one of {}",
+            CONTENT_TYPES.join(", ")
+        ))),
+    }
+}
+
+/// Extracts the text of a document. PDFs are parsed on a blocking thread.
+pub async fn extract_text(content_type: &str, body: Bytes) -> Result<String, MyError> {
+    if content_type != "application/pdf" {
+        return String::from_utf8(body.to_vec())
+            .map_err(|_| MyError::BadRequest("document is not valid UTF-8".to_string()));
+    }
+
+    // The PDF parser panics on some malformed files.
+    let text = web::block(move || {
+        catch_unwind(AssertUnwindSafe(|| pdf_extract::extract_text_from_mem(&body)))
+    })
+    .await
+    .map_err(|e| MyError::Internal(e.to_string()))?;
+
+    match text {
+        Ok(Ok(text)) => Ok(text),
+        Ok(Err(e)) => Err(MyError::BadRequest(format!("could not read the PDF: {}", e))),
+        Err(_) => Err(MyError::BadRequest("could not read the PDF".to_string())),
+    }
+}
+
+/// Splits `text` into chunks of at most about `max_tokens` tokens, keeping
+/// paragraphs together when they fit.
+pub fn chunk_text(text: &str, max_tokens: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_tokens = 0;
+
+    for piece in pieces(text, max_tokens) {
+        let tokens = count_tokens(&piece);
+        if chunk_tokens + tokens > max_tokens && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_tokens = 0;
+        }
+        if !chunk.is_empty() {
+            chunk.push_str("\n\n");
+        }
+        chunk.push_str(&piece);
+        chunk_tokens += tokens;
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+/// The paragraphs of `text`, with paragraphs longer than `max_tokens` split
+/// between words.
+fn pieces(text: &str, max_tokens: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|paragraph| !paragraph.is_empty()) {
+        if count_tokens(paragraph) <= max_tokens {
+            pieces.push(paragraph.to_string());
+            continue;
+        }
+
+        let mut piece = String::new();
+        let mut piece_tokens = 0;
+        for word in paragraph.split_whitespace() {
+            let tokens = count_tokens(word) + 1;
+            if piece_tokens + tokens > max_tokens && !piece.is_empty() {
+                pieces.push(std::mem::take(&mut piece));
+                piece_tokens = 0;
+            }
+            if !piece.is_empty() {
+                piece.push(' ');
+            }
+            piece.push_str(word);
+            piece_tokens += tokens;
+        }
+        if !piece.is_empty() {
+            pieces.push(piece);
+        }
+    }
+    pieces
+}
+
+/// Embeds `inputs` with `config.embedding_model`, recording the usage of each
+/// call against `chat_id` when given.
+pub async fn embed(
+    client: &Client,
+    provider: &dyn EmbeddingProvider,
+    config: &Config,
+    app_user: i32,
+    chat_id: Option<i32>,
+    inputs: Vec<String>,
+) -> Result<Vec<Vec<f32>>, MyError> {
+    let mut embeddings = Vec::with_capacity(inputs.len());
+
+    for batch in inputs.chunks(EMBEDDING_BATCH) {
+        let request = EmbeddingRequestBody {
+            model: config.embedding_model.clone(),
+            input: batch.to_vec(),
+        };
+        let pending = PendingCompletion::start_embedding(app_user, chat_id, &request.model, batch);
+        let body = provider.embed(&request).await?;
+
+        let response: serde_json::Value = serde_json::from_str(&body)
+            .map_err(|e| crate::upstream_error(format!("invalid JSON in embeddings: {}", e)))?;
+        if let Err(e) = db::add_completion(client, pending.finish_embedding(&response, &config.pricing)).await {
+            eprintln!("Error recording embedding usage: {}", e);
+        }
+
+        let mut data: Vec<&serde_json::Value> = response["data"]
+            .as_array()
+            .map(|data| data.iter().collect())
+            .unwrap_or_default();
+        if data.len() != batch.len() {
+            return Err(crate::upstream_error(format!(
+                "expected {} embeddings, got {}",
+                batch.len(),
+                data.len()
+            )));
+        }
+        data.sort_by_key(|item| item["index"].as_u64());
+
+        for item in data {
+            let embedding = item["embedding"]
+                .as_array()
+                .map(|values| values.iter().filter_map(|value| value.as_f64()).map(|value| value as f32).collect())
+                .ok_or_else(|| crate::upstream_error("embedding is not an array".to_string()))?;
+            embeddings.push(embedding);
+        }
+    }
+
+    Ok(embeddings)
+}
+
+/// Finds the chunks of the chat's documents most relevant to `query` and
+/// returns them as a system message, along with the citations to record on
+/// the reply. Returns `None` when the chat has no documents.
+pub async fn retrieve(
+    client: &Client,
+    provider: &dyn EmbeddingProvider,
+    config: &Config,
+    app_user: i32,
+    chat_id: i32,
+    query: &str,
+) -> Result<Option<(ChatCompletionMessage, serde_json::Value)>, MyError> {
+    if config.retrieval_top_k == 0 || !db::chat_has_documents(client, chat_id).await? {
+        return Ok(None);
+    }
+
+    let embedding = embed(client, provider, config, app_user, Some(chat_id), vec![query.to_string()])
+        .await?
+        .pop()
+        .unwrap_or_default();
+    let matches = match config.vector_search.as_str() {
+        "pgvector" => {
+            db::search_document_chunks(client, chat_id, &embedding, config.retrieval_top_k as i64)
+                .await?
+        }
+        _ => nearest(
+            db::get_document_chunks_by_chat_id(client, chat_id).await?,
+            &embedding,
+            config.retrieval_top_k,
+        ),
+    };
+    if matches.is_empty() {
+        return Ok(None);
+    }
+
+    let mut content = String::from(
+        "Excerpts from the documents attached to this chat, which may help with the next \
+         message. When you use one, cite it by its number in brackets, e.g. [1].",
+    );
+    for (number, chunk) in matches.iter().enumerate() {
+        content.push_str(&format!(
+            "\n\n[{}] {}, part {}:\n{}",
+            number + 1,
+            chunk.document_name,
+            chunk.chunk_index + 1,
+            chunk.content
+        ));
+    }
+    let citations = matches
+        .iter()
+        .enumerate()
+        .map(|(number, chunk)| {
+            json!({
+                "number": number + 1,
+                "document_id": chunk.document_id,
+                "document_name": chunk.document_name,
+                "chunk_id": chunk.id,
+                "chunk_index": chunk.chunk_index,
+                "score": chunk.score,
+            })
+        })
+        .collect();
+
+    Ok(Some((
+        ChatCompletionMessage::new("system", content),
+        serde_json::Value::Array(citations),
+    )))
+}
+
+/// The `k` chunks most similar to `embedding`, best first.
+fn nearest(chunks: Vec<DocumentChunk>, embedding: &[f32], k: usize) -> Vec<ChunkMatch> {
+    let mut matches: Vec<ChunkMatch> = chunks
+        .into_iter()
+        .map(|chunk| ChunkMatch {
+            score: cosine_similarity(&chunk.embedding, embedding),
+            id: chunk.id,
+            document_id: chunk.document_id,
+            document_name: chunk.document_name,
+            chunk_index: chunk.chunk_index,
+            content: chunk.content,
+        })
+        .collect();
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+    matches.truncate(k);
+    matches
+}
+
+/// Cosine similarity of two vectors; 0 when either is empty or all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0f64, 0.0f64, 0.0f64);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (*x as f64, *y as f64);
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
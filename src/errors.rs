@@ -1,26 +1,193 @@
+use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
 use deadpool_postgres::PoolError;
 use derive_more::{Display, From};
+use serde_json::json;
 use tokio_pg_mapper::Error as PGMError;
 use tokio_postgres::error::Error as PGError;
 
+use crate::providers::ProviderError;
+use crate::request_id;
+
 #[derive(Display, From, Debug)]
 pub enum MyError {
     NotFound,
+    Unauthorized,
+    Forbidden,
+    #[from(ignore)]
+    BadRequest(String),
+    /// The client exceeded a rate limit or budget; `retry_after` is in seconds.
+    #[display(fmt = "{}", message)]
+    #[from(ignore)]
+    RateLimited {
+        message: String,
+        retry_after: Option<u64>,
+    },
+    /// The user's spending budget is used up until `retry_after` seconds from
+    /// now.
+    #[display(fmt = "{}", message)]
+    #[from(ignore)]
+    BudgetExceeded {
+        message: String,
+        retry_after: Option<u64>,
+    },
+    /// The model provider failed or returned an error. `status` and `code` are
+    /// the provider's own HTTP status and error code, when it sent them.
+    #[display(fmt = "upstream error: {}", message)]
+    #[from(ignore)]
+    Upstream {
+        status: Option<u16>,
+        code: Option<String>,
+        message: String,
+        timed_out: bool,
+    },
+    #[from(ignore)]
+    Internal(String),
     PGError(PGError),
     PGMError(PGMError),
     PoolError(PoolError),
 }
 impl std::error::Error for MyError {}
 
+impl MyError {
+    /// Stable, machine-readable identifier of the error kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            MyError::NotFound => "not_found",
+            MyError::Unauthorized => "unauthorized",
+            MyError::Forbidden => "forbidden",
+            MyError::BadRequest(_) => "validation_error",
+            MyError::RateLimited { .. } => "rate_limited",
+            MyError::BudgetExceeded { .. } => "budget_exceeded",
+            MyError::Upstream {
+                status: Some(429), ..
+            } => "upstream_rate_limited",
+            MyError::Upstream {
+                timed_out: true, ..
+            } => "upstream_timeout",
+            MyError::Upstream { .. } => "upstream_error",
+            MyError::Internal(_) => "internal_error",
+            MyError::PGError(_) | MyError::PGMError(_) | MyError::PoolError(_) => {
+                "database_error"
+            }
+        }
+    }
+
+    /// Message shown to clients. Database details stay in the server log.
+    fn public_message(&self) -> String {
+        match self {
+            MyError::NotFound => "resource not found".to_string(),
+            MyError::Unauthorized => "missing or invalid credentials".to_string(),
+            MyError::Forbidden => "not allowed".to_string(),
+            MyError::Internal(_) => "internal server error".to_string(),
+            MyError::PGError(_) | MyError::PGMError(_) | MyError::PoolError(_) => {
+                "database error".to_string()
+            }
+            other => other.to_string(),
+        }
+    }
+}
+
+impl From<ProviderError> for MyError {
+    fn from(e: ProviderError) -> Self {
+        match e {
+            ProviderError::Status { status, body } => {
+                // OpenAI-style bodies look like {"error": {"message", "type", "code"}}.
+                let parsed: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+                let error = &parsed["error"];
+                let code = error["code"]
+                    .as_str()
+                    .or_else(|| error["type"].as_str())
+                    .map(str::to_string);
+                let message = error["message"]
+                    .as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("provider returned status {}", status));
+                MyError::Upstream {
+                    status: Some(status),
+                    code,
+                    message,
+                    timed_out: false,
+                }
+            }
+            ProviderError::Http(e) => MyError::Upstream {
+                status: e.status().map(|status| status.as_u16()),
+                code: None,
+                message: e.to_string(),
+                timed_out: e.is_timeout(),
+            },
+            ProviderError::Stalled { .. } => MyError::Upstream {
+                status: None,
+                code: None,
+                message: e.to_string(),
+                timed_out: true,
+            },
+            ProviderError::CircuitOpen { .. } => MyError::Upstream {
+                status: None,
+                code: Some("circuit_open".to_string()),
+                message: e.to_string(),
+                timed_out: false,
+            },
+            other => MyError::Upstream {
+                status: None,
+                code: None,
+                message: other.to_string(),
+                timed_out: false,
+            },
+        }
+    }
+}
+
 impl ResponseError for MyError {
-    fn error_response(&self) -> HttpResponse {
-        match *self {
-            MyError::NotFound => HttpResponse::NotFound().finish(),
-            MyError::PoolError(ref err) => {
-                HttpResponse::InternalServerError().body(err.to_string())
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MyError::NotFound => StatusCode::NOT_FOUND,
+            MyError::Unauthorized => StatusCode::UNAUTHORIZED,
+            MyError::Forbidden => StatusCode::FORBIDDEN,
+            MyError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            MyError::RateLimited { .. } | MyError::BudgetExceeded { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
             }
-            _ => HttpResponse::InternalServerError().finish(),
+            MyError::Upstream {
+                status: Some(429), ..
+            } => StatusCode::TOO_MANY_REQUESTS,
+            MyError::Upstream {
+                timed_out: true, ..
+            } => StatusCode::GATEWAY_TIMEOUT,
+            MyError::Upstream { .. } => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Renders every error as
+    /// `{"error": {"code", "message", "request_id", ...}}`.
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        if status.is_server_error() {
+            eprintln!("{}: {}", self.code(), self);
+        }
+
+        let mut error = json!({
+            "code": self.code(),
+            "message": self.public_message(),
+            "request_id": request_id::current(),
+        });
+        if let MyError::Upstream { status, code, .. } = self {
+            error["upstream"] = json!({ "status": status, "code": code });
+        }
+
+        let mut response = HttpResponse::build(status);
+        if let MyError::RateLimited {
+            retry_after: Some(retry_after),
+            ..
+        }
+        | MyError::BudgetExceeded {
+            retry_after: Some(retry_after),
+            ..
+        } = self
+        {
+            response.insert_header(("Retry-After", retry_after.to_string()));
         }
+        response.json(json!({ "error": error }))
     }
 }
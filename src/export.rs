@@ -0,0 +1,438 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use serde::Deserialize;
+use serde_json::json;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::SyntaxSet;
+
+use crate::models::{Chat, Image, Message};
+
+/// Theme of highlighted code blocks in HTML exports.
+const CODE_THEME: &str = "InspiredGitHub";
+
+const HTML_STYLE: &str = "body{max-width:48rem;margin:2rem auto;padding:0 1rem;\
+font-family:-apple-system,'Segoe UI',Helvetica,Arial,sans-serif;line-height:1.5;color:#1f2328}\
+header{border-bottom:1px solid #d0d7de;margin-bottom:1.5rem}\
+header p,.message h2 time,.tools,.sources{color:#59636e;font-size:.875rem}\
+.message{margin-bottom:1.5rem}\
+.message h2{font-size:1rem;margin:0 0 .25rem}\
+.message.user{background:#f6f8fa;border-radius:6px;padding:.5rem 1rem}\
+pre{padding:.75rem;border-radius:6px;overflow-x:auto;font-size:.875rem}\
+code{font-family:ui-monospace,SFMono-Regular,Menlo,Consolas,monospace}\
+img{max-width:100%}\
+table{border-collapse:collapse}td,th{border:1px solid #d0d7de;padding:.25rem .5rem}";
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    #[serde(alias = "md")]
+    Markdown,
+    Html,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Markdown => "text/markdown; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Markdown => "md",
+            Format::Html => "html",
+        }
+    }
+}
+
+/// A message or generated image, in the order they were created.
+enum Entry<'a> {
+    Message(&'a Message),
+    Image(&'a Image),
+}
+
+impl Entry<'_> {
+    fn created_on(&self) -> DateTime<Utc> {
+        match self {
+            Entry::Message(message) => message.created_on,
+            Entry::Image(image) => image.created_on,
+        }
+    }
+}
+
+/// The user and assistant messages of `messages` and the `images`, oldest
+/// first. Tool results and system messages are left out.
+fn entries<'a>(messages: &'a [Message], images: &'a [Image]) -> Vec<Entry<'a>> {
+    let mut entries: Vec<Entry> = messages
+        .iter()
+        .filter(|message| matches!(message.role.as_str(), "user" | "assistant"))
+        .map(Entry::Message)
+        .chain(images.iter().map(Entry::Image))
+        .collect();
+    entries.sort_by_key(Entry::created_on);
+    entries
+}
+
+/// Renders the chat with `messages`, its active branch, and its `images`.
+pub fn render(format: Format, chat: &Chat, messages: &[Message], images: &[Image]) -> String {
+    match format {
+        Format::Json => to_json(chat, messages, images),
+        Format::Markdown => to_markdown(chat, messages, images),
+        Format::Html => to_html(chat, messages, images),
+    }
+}
+
+/// Everything stored, tool calls and results included, for re-import or
+/// processing.
+fn to_json(chat: &Chat, messages: &[Message], images: &[Image]) -> String {
+    json!({
+        "exported_on": Utc::now(),
+        "chat": chat,
+        "messages": messages,
+        "images": images,
+    })
+    .to_string()
+}
+
+fn to_markdown(chat: &Chat, messages: &[Message], images: &[Image]) -> String {
+    let mut markdown = format!("# {}\n\n{}\n", chat.chat_name, byline(chat));
+
+    for entry in entries(messages, images) {
+        match entry {
+            Entry::Message(message) => {
+                markdown.push_str(&format!(
+                    "\n## {} · {}\n\n",
+                    role_name(&message.role),
+                    timestamp(message.created_on)
+                ));
+                if !message.content.is_empty() {
+                    markdown.push_str(message.content.trim_end());
+                    markdown.push_str("\n\n");
+                }
+                if let Some(tools) = tool_names(message) {
+                    markdown.push_str(&format!("*Used tools: {}*\n\n", tools));
+                }
+                if let Some(sources) = sources(message) {
+                    markdown.push_str(&format!("*Sources: {}*\n\n", sources));
+                }
+            }
+            Entry::Image(image) => {
+                markdown.push_str(&format!(
+                    "\n![Generated image, {}]({})\n",
+                    timestamp(image.created_on),
+                    image.url
+                ));
+            }
+        }
+    }
+
+    markdown
+}
+
+/// A standalone page: styles are inline and code is highlighted with inline
+/// colors, so it renders the same wherever it is opened. Images link to the
+/// provider's URLs.
+fn to_html(chat: &Chat, messages: &[Message], images: &[Image]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{title}</title>\n<style>{style}</style>\n</head>\n<body>\n\
+         <header>\n<h1>{title}</h1>\n<p>{byline}</p>\n</header>\n",
+        title = escape_html(&chat.chat_name),
+        style = HTML_STYLE,
+        byline = escape_html(&byline(chat)),
+    );
+
+    for entry in entries(messages, images) {
+        match entry {
+            Entry::Message(message) => {
+                html.push_str(&format!(
+                    "<section class=\"message {}\">\n<h2>{} <time datetime=\"{}\">{}</time></h2>\n",
+                    escape_html(&message.role),
+                    role_name(&message.role),
+                    message.created_on.to_rfc3339(),
+                    timestamp(message.created_on)
+                ));
+                html.push_str(&markdown_to_html(&message.content));
+                if let Some(tools) = tool_names(message) {
+                    html.push_str(&format!(
+                        "<p class=\"tools\">Used tools: {}</p>\n",
+                        escape_html(&tools)
+                    ));
+                }
+                if let Some(sources) = sources(message) {
+                    html.push_str(&format!(
+                        "<p class=\"sources\">Sources: {}</p>\n",
+                        escape_html(&sources)
+                    ));
+                }
+                html.push_str("</section>\n");
+            }
+            Entry::Image(image) => {
+                html.push_str(&format!(
+                    "<figure>\n<img src=\"{}\" alt=\"Generated image\">\n\
+                     <figcaption><time datetime=\"{}\">{}</time></figcaption>\n</figure>\n",
+                    escape_html(&image.url),
+                    image.created_on.to_rfc3339(),
+                    timestamp(image.created_on)
+                ));
+            }
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Renders a message's Markdown as HTML, highlighting fenced code blocks.
+/// Raw HTML in the message is shown as text rather than interpreted, and
+/// links and images that could run scripts point nowhere instead.
+fn markdown_to_html(markdown: &str) -> String {
+    let mut events = Vec::new();
+    let mut code: Option<(String, String)> = None;
+
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    for event in Parser::new_ext(markdown, options) {
+        match (event, &mut code) {
+            (Event::Start(Tag::CodeBlock(kind)), None) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or_default().to_string()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((language, String::new()));
+            }
+            (Event::Text(text), Some((_, source))) => source.push_str(&text),
+            (Event::End(TagEnd::CodeBlock), Some(_)) => {
+                let (language, source) = code.take().unwrap_or_default();
+                events.push(Event::Html(highlight_code(&source, &language).into()));
+            }
+            (Event::Html(raw) | Event::InlineHtml(raw), _) => events.push(Event::Text(raw)),
+            (Event::Start(Tag::Link { link_type, dest_url, title, id }), _) => {
+                let dest_url = if is_safe_url(&dest_url) { dest_url } else { "#".into() };
+                events.push(Event::Start(Tag::Link { link_type, dest_url, title, id }));
+            }
+            (Event::Start(Tag::Image { link_type, dest_url, title, id }), _) => {
+                let dest_url = if is_safe_url(&dest_url) { dest_url } else { "#".into() };
+                events.push(Event::Start(Tag::Image { link_type, dest_url, title, id }));
+            }
+            (event, _) => events.push(event),
+        }
+    }
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    html
+}
+
+/// Whether `url` is relative or uses a scheme that cannot run scripts. The URL
+/// is read the way browsers do: leading spaces and control characters are
+/// ignored, tabs and newlines anywhere are dropped and schemes are not case
+/// sensitive.
+fn is_safe_url(url: &str) -> bool {
+    let url: String = url
+        .trim_start_matches(|c: char| c <= ' ')
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    let scheme = match url.find(':') {
+        Some(end) => &url[..end],
+        None => return true,
+    };
+    // A colon after a path, query or fragment starts is not a scheme's.
+    if scheme.contains(['/', '?', '#']) {
+        return true;
+    }
+    matches!(
+        scheme.to_ascii_lowercase().as_str(),
+        "http" | "https" | "mailto" | "tel"
+    )
+}
+
+/// `source` as a `<pre>` block with inline colors, highlighted as `language`
+/// when it is known.
+fn highlight_code(source: &str, language: &str) -> String {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    let syntaxes = SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines);
+    let theme = THEME.get_or_init(|| {
+        ThemeSet::load_defaults()
+            .themes
+            .remove(CODE_THEME)
+            .unwrap_or_default()
+    });
+
+    let syntax = syntaxes
+        .find_syntax_by_token(language)
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    highlighted_html_for_string(source, syntaxes, syntax, theme)
+        .unwrap_or_else(|_| format!("<pre><code>{}</code></pre>\n", escape_html(source)))
+}
+
+fn byline(chat: &Chat) -> String {
+    let mut byline = format!("Started {}", timestamp(chat.created_on));
+    if let Some(model) = &chat.model {
+        byline.push_str(&format!(" · {}", model));
+    }
+    byline.push_str(&format!(" · exported {}", timestamp(Utc::now())));
+    byline
+}
+
+fn role_name(role: &str) -> &str {
+    match role {
+        "user" => "User",
+        "assistant" => "Assistant",
+        role => role,
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+/// Names of the tools an assistant message called, comma-separated.
+fn tool_names(message: &Message) -> Option<String> {
+    let names: Vec<&str> = message
+        .tool_calls
+        .as_ref()?
+        .as_array()?
+        .iter()
+        .filter_map(|call| call["function"]["name"].as_str())
+        .collect();
+    (!names.is_empty()).then(|| names.join(", "))
+}
+
+/// The document excerpts an assistant reply was given, as `[1] name, part 2`.
+fn sources(message: &Message) -> Option<String> {
+    let sources: Vec<String> = message
+        .citations
+        .as_ref()?
+        .as_array()?
+        .iter()
+        .map(|citation| {
+            format!(
+                "[{}] {}, part {}",
+                citation["number"],
+                citation["document_name"].as_str().unwrap_or_default(),
+                citation["chunk_index"].as_i64().unwrap_or_default() + 1
+            )
+        })
+        .collect();
+    (!sources.is_empty()).then(|| sources.join("; "))
+}
+
+/// `text` with the characters that are special in HTML escaped, safe in
+/// element content and quoted attributes.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A file name for the export: the chat's name reduced to ASCII letters,
+/// digits and dashes.
+pub fn file_name(chat: &Chat, format: Format) -> String {
+    let mut stem = String::new();
+    for c in chat.chat_name.chars() {
+        if c.is_ascii_alphanumeric() {
+            stem.push(c.to_ascii_lowercase());
+        } else if !stem.is_empty() && !stem.ends_with('-') {
+            stem.push('-');
+        }
+    }
+    let stem = stem.trim_end_matches('-');
+    let stem = if stem.is_empty() {
+        format!("chat-{}", chat.chat_id)
+    } else {
+        stem.to_string()
+    };
+    format!("{}.{}", stem, format.extension())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_html_special_characters() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn shows_raw_html_as_text() {
+        let html = markdown_to_html("<script>alert(1)</script>\n\nHi <img src=x onerror=alert(1)>");
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(!html.contains("<img"), "{}", html);
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"), "{}", html);
+        assert!(html.contains("Hi &lt;img src=x onerror=alert(1)&gt;"), "{}", html);
+    }
+
+    #[test]
+    fn neutralizes_script_links() {
+        for markdown in [
+            "[a](javascript:alert(1))",
+            "[a](JaVaScRiPt:alert(1))",
+            "[a](  javascript:alert(1))",
+            "[a](<\u{1}javascript:alert(1)>)",
+            "[a](java&#9;script:alert(1))",
+            "[a](java&#10;script:alert(1))",
+            "[a](&#106;avascript:alert(1))",
+            "[a](vbscript:msgbox(1))",
+            "[a](data:text/html;base64,PHNjcmlwdD4=)",
+            "<javascript:alert(1)>",
+            "[a][r]\n\n[r]: javascript:alert(1)",
+        ] {
+            let html = markdown_to_html(markdown);
+            assert!(html.contains(r##"<a href="#">"##), "{}: {}", markdown, html);
+        }
+    }
+
+    #[test]
+    fn neutralizes_script_images() {
+        for markdown in [
+            "![x](javascript:alert(1))",
+            "![x](JAVASCRIPT:alert(1))",
+            "![x](data:image/svg+xml;base64,PHN2Zz4=)",
+        ] {
+            let html = markdown_to_html(markdown);
+            assert!(html.contains(r##"<img src="#""##), "{}: {}", markdown, html);
+        }
+    }
+
+    #[test]
+    fn keeps_other_links_and_images() {
+        for (markdown, expected) in [
+            ("[a](https://example.com/x)", r#"<a href="https://example.com/x">"#),
+            ("[a](HTTP://example.com)", r#"<a href="HTTP://example.com">"#),
+            ("[a](mailto:me@example.com)", r#"<a href="mailto:me@example.com">"#),
+            ("[a](/docs/setup)", r#"<a href="/docs/setup">"#),
+            ("[a](page?time=10:30)", r#"<a href="page?time=10:30">"#),
+            ("[a](#notes)", r##"<a href="#notes">"##),
+            ("![x](https://example.com/x.png)", r#"<img src="https://example.com/x.png""#),
+        ] {
+            let html = markdown_to_html(markdown);
+            assert!(html.contains(expected), "{}: {}", markdown, html);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::MyError;
+use crate::models::Message;
+use crate::tokenizer::count_prompt_tokens;
+use crate::ChatCompletionMessage;
+
+/// Longest example kept unless the request sets `max_tokens`: the context
+/// window of gpt-3.5-turbo, the smallest model OpenAI fine-tunes.
+const DEFAULT_MAX_TOKENS: usize = 16385;
+
+/// Which chats go into a dataset and how their messages are cleaned up.
+#[derive(Deserialize)]
+pub struct DatasetOptions {
+    /// Only these chats.
+    #[serde(default)]
+    pub chat_ids: Vec<i32>,
+    /// Only chats with one of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Only chats with a reply rated good.
+    #[serde(default)]
+    pub rated: bool,
+    /// Leave out system messages.
+    #[serde(default)]
+    pub strip_system: bool,
+    /// Leave out turns, a user message and what answers it, whose reply was
+    /// rated bad.
+    #[serde(default)]
+    pub drop_low_rated: bool,
+    /// Examples longer than this, in tokens, are left out.
+    pub max_tokens: Option<usize>,
+    /// Report what the dataset would hold instead of building it.
+    #[serde(default)]
+    pub validate_only: bool,
+}
+
+impl DatasetOptions {
+    pub fn validate(&self) -> Result<(), MyError> {
+        if self.max_tokens == Some(0) {
+            return Err(MyError::BadRequest(
+                "max_tokens: must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS)
+    }
+}
+
+/// What became of one chat of a dataset.
+#[derive(Serialize)]
+pub struct ExampleReport {
+    pub chat_id: i32,
+    /// `included`, or `skipped` with a `reason`.
+    pub status: &'static str,
+    pub messages: usize,
+    pub tokens: usize,
+    pub dropped_turns: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Builds the training example of a chat from `messages`, its active branch,
+/// as a line of OpenAI's fine-tuning JSONL. Returns `None` for the line when
+/// the chat cannot make a valid example, with the reason in the report.
+pub fn example(
+    chat_id: i32,
+    messages: Vec<Message>,
+    ratings: &HashMap<i32, i16>,
+    options: &DatasetOptions,
+) -> (Option<String>, ExampleReport) {
+    let (mut messages, dropped_turns) = turns(messages, ratings, options);
+
+    // Training teaches the last reply, so the example ends with one.
+    while messages.last().is_some_and(|message| message.role != "assistant") {
+        messages.pop();
+    }
+    let messages: Vec<ChatCompletionMessage> = messages.into_iter().map(Into::into).collect();
+    let tokens = count_prompt_tokens(&messages);
+
+    let mut report = ExampleReport {
+        chat_id,
+        status: "included",
+        messages: messages.len(),
+        tokens,
+        dropped_turns,
+        reason: None,
+    };
+    let reason = if messages.is_empty() {
+        Some("no assistant reply".to_string())
+    } else if tokens > options.max_tokens() {
+        Some(format!(
+            "{} tokens, more than the limit of {}",
+            tokens,
+            options.max_tokens()
+        ))
+    } else {
+        None
+    };
+    if reason.is_some() {
+        report.status = "skipped";
+        report.reason = reason;
+        return (None, report);
+    }
+
+    let line = serde_json::json!({ "messages": messages }).to_string();
+    (Some(line), report)
+}
+
+/// The messages to train on, without system messages and low-rated turns
+/// when asked, and how many turns were dropped. A turn starts at a user
+/// message and runs until the next one.
+fn turns(
+    messages: Vec<Message>,
+    ratings: &HashMap<i32, i16>,
+    options: &DatasetOptions,
+) -> (Vec<Message>, usize) {
+    let mut kept = Vec::with_capacity(messages.len());
+    let mut turn: Vec<Message> = Vec::new();
+    let mut dropped_turns = 0;
+
+    let mut end_turn = |turn: &mut Vec<Message>, kept: &mut Vec<Message>| {
+        let low_rated = turn.iter().any(|message| {
+            message.id.and_then(|id| ratings.get(&id)).is_some_and(|rating| *rating < 0)
+        });
+        if options.drop_low_rated && low_rated {
+            dropped_turns += 1;
+            turn.clear();
+        } else {
+            kept.append(turn);
+        }
+    };
+
+    for message in messages {
+        if options.strip_system && message.role == "system" {
+            continue;
+        }
+        if message.role == "user" {
+            end_turn(&mut turn, &mut kept);
+        }
+        turn.push(message);
+    }
+    end_turn(&mut turn, &mut kept);
+
+    (kept, dropped_turns)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn message(id: i32, role: &str, content: &str) -> Message {
+        Message {
+            id: Some(id),
+            created_on: Utc::now(),
+            role: role.to_string(),
+            content: content.to_string(),
+            chat_id_relation: 1,
+            pinned: false,
+            parent_id: None,
+            tool_calls: None,
+            tool_call_id: None,
+            citations: None,
+        }
+    }
+
+    fn options(strip_system: bool, drop_low_rated: bool, max_tokens: Option<usize>) -> DatasetOptions {
+        DatasetOptions {
+            chat_ids: Vec::new(),
+            tags: Vec::new(),
+            rated: false,
+            strip_system,
+            drop_low_rated,
+            max_tokens,
+            validate_only: false,
+        }
+    }
+
+    fn chat() -> Vec<Message> {
+        vec![
+            message(1, "system", "Be brief."),
+            message(2, "user", "2+2?"),
+            message(3, "assistant", "4"),
+            message(4, "user", "3+3?"),
+            message(5, "assistant", "7"),
+            message(6, "user", "Thanks"),
+        ]
+    }
+
+    fn roles(line: &str) -> Vec<String> {
+        let example: serde_json::Value = serde_json::from_str(line).unwrap();
+        example["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["role"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ends_the_example_with_an_assistant_reply() {
+        let (line, report) = example(1, chat(), &HashMap::new(), &options(false, false, None));
+        let line = line.unwrap();
+        assert_eq!(
+            roles(&line),
+            ["system", "user", "assistant", "user", "assistant"]
+        );
+        assert!(line.contains(r#""content":"7""#));
+        assert_eq!(report.status, "included");
+        assert_eq!(report.messages, 5);
+        assert_eq!(report.dropped_turns, 0);
+        assert!(report.tokens > 0);
+    }
+
+    #[test]
+    fn strips_system_messages_when_asked() {
+        let (line, report) = example(1, chat(), &HashMap::new(), &options(true, false, None));
+        assert_eq!(roles(&line.unwrap()), ["user", "assistant", "user", "assistant"]);
+        assert_eq!(report.messages, 4);
+    }
+
+    #[test]
+    fn drops_turns_with_a_bad_rating_when_asked() {
+        let ratings = HashMap::from([(3, 1), (5, -1)]);
+
+        let (messages, dropped) = turns(chat(), &ratings, &options(false, true, None));
+        let ids: Vec<_> = messages.iter().filter_map(|message| message.id).collect();
+        assert_eq!(ids, [1, 2, 3, 6]);
+        assert_eq!(dropped, 1);
+
+        let (messages, dropped) = turns(chat(), &ratings, &options(false, false, None));
+        assert_eq!(messages.len(), 6);
+        assert_eq!(dropped, 0);
+
+        let (line, report) = example(1, chat(), &ratings, &options(false, true, None));
+        assert_eq!(roles(&line.unwrap()), ["system", "user", "assistant"]);
+        assert_eq!(report.dropped_turns, 1);
+    }
+
+    #[test]
+    fn skips_chats_without_an_assistant_reply() {
+        let messages = vec![message(1, "system", "Be brief."), message(2, "user", "Hi")];
+        let (line, report) = example(1, messages, &HashMap::new(), &options(false, false, None));
+        assert!(line.is_none());
+        assert_eq!(report.status, "skipped");
+        assert_eq!(report.reason.as_deref(), Some("no assistant reply"));
+
+        // Dropping every answered turn leaves nothing to train on either.
+        let ratings = HashMap::from([(3, -1), (5, -1)]);
+        let (line, _) = example(1, chat(), &ratings, &options(true, true, None));
+        assert!(line.is_none());
+    }
+
+    #[test]
+    fn skips_examples_over_the_token_limit() {
+        let (_, report) = example(1, chat(), &HashMap::new(), &options(false, false, None));
+        let tokens = report.tokens;
+
+        let (line, _) = example(1, chat(), &HashMap::new(), &options(false, false, Some(tokens)));
+        assert!(line.is_some());
+
+        let (line, report) =
+            example(1, chat(), &HashMap::new(), &options(false, false, Some(tokens - 1)));
+        assert!(line.is_none());
+        assert_eq!(report.status, "skipped");
+        assert_eq!(
+            report.reason,
+            Some(format!("{} tokens, more than the limit of {}", tokens, tokens - 1))
+        );
+    }
+
+    #[test]
+    fn rejects_a_zero_token_limit() {
+        assert!(options(false, false, Some(0)).validate().is_err());
+        assert!(options(false, false, Some(1)).validate().is_ok());
+        assert!(options(false, false, None).validate().is_ok());
+    }
+}
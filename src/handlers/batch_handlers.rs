@@ -0,0 +1,88 @@
+use crate::auth::AuthenticatedUser;
+use crate::batches;
+use crate::budget;
+use crate::config::Config;
+use crate::db::{create_batch, get_batch, get_batch_items, get_batches};
+use crate::errors::MyError;
+use crate::providers::ChatProvider;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse};
+use bytes::Bytes;
+use deadpool_postgres::{Client, Pool};
+
+/// Starts a batch from a JSONL file of chat requests, one per line, and
+/// returns it. The requests run in the background; poll the batch for
+/// progress and download the results when it is completed.
+pub async fn create_batch_handler(
+    db_pool: web::Data<Pool>,
+    provider: web::Data<dyn ChatProvider>,
+    config: web::Data<Config>,
+    user: AuthenticatedUser,
+    body: Bytes,
+) -> Result<HttpResponse, MyError> {
+    let file = String::from_utf8(body.to_vec())
+        .map_err(|_| MyError::BadRequest("batch file is not valid UTF-8".to_string()))?;
+    let items = web::block(move || batches::parse(&file))
+        .await
+        .map_err(|e| MyError::Internal(e.to_string()))?;
+    if items.is_empty() {
+        return Err(MyError::BadRequest("batch file has no requests".to_string()));
+    }
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let remaining = budget::check(&client, user.id).await?;
+    let batch_id = create_batch(&client, user.id, &items).await?;
+    batches::spawn(
+        db_pool.get_ref().clone(),
+        provider.into_inner(),
+        config.get_ref().clone(),
+        batch_id,
+    );
+
+    let mut response = HttpResponse::Accepted();
+    remaining.insert_headers(&mut response);
+    Ok(response.json(get_batch(&client, batch_id).await?))
+}
+
+/// The user's batches, newest first, with their progress.
+pub async fn get_batches_handler(
+    db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    Ok(HttpResponse::Ok().json(get_batches(&client, user.id).await?))
+}
+
+pub async fn get_batch_handler(
+    db_pool: web::Data<Pool>,
+    batch_id: web::Path<i32>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    Ok(HttpResponse::Ok().json(user.owned_batch(&client, batch_id.into_inner()).await?))
+}
+
+/// Downloads the outcome of every line of a batch as JSONL. Lines still
+/// pending or running are included with that status.
+pub async fn get_batch_results_handler(
+    db_pool: web::Data<Pool>,
+    batch_id: web::Path<i32>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let batch = user.owned_batch(&client, batch_id.into_inner()).await?;
+    let items = get_batch_items(&client, batch.id).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/jsonl")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "batch-{}-results.jsonl",
+                batch.id
+            ))],
+        })
+        .body(batches::results(&items)))
+}
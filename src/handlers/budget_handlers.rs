@@ -0,0 +1,50 @@
+use crate::auth::AuthenticatedUser;
+use crate::budget;
+use crate::db::{get_user, upsert_budget};
+use crate::errors::MyError;
+use crate::models::BudgetLimits;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::{Client, Pool};
+use serde_json::json;
+
+/// A user's limits, what they spent this day and month, and what is left.
+/// Users can see their own budget; admins can see anyone's.
+pub async fn get_budget_handler(
+    db_pool: web::Data<Pool>,
+    app_user: web::Path<i32>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    if user.id != *app_user {
+        user.ensure_admin(&client).await?;
+    }
+
+    let body = match budget::status(&client, *app_user).await? {
+        Some((limits, spent, remaining)) => json!({
+            "limits": limits,
+            "spent": spent,
+            "remaining": remaining,
+        }),
+        None => json!({ "limits": null, "spent": null, "remaining": null }),
+    };
+
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// Sets a user's limits. Admins only.
+pub async fn update_budget_handler(
+    db_pool: web::Data<Pool>,
+    app_user: web::Path<i32>,
+    limits: web::Json<BudgetLimits>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    limits.validate()?;
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    user.ensure_admin(&client).await?;
+    get_user(&client, *app_user).await?;
+
+    let budget = upsert_budget(&client, *app_user, &limits).await?;
+
+    Ok(HttpResponse::Ok().json(budget))
+}
@@ -1,22 +1,44 @@
-use crate::db::{create_chat, delete_chat, get_chats, update_chat_name};
+use crate::auth::AuthenticatedUser;
+use crate::budget;
+use crate::config::Config;
+use crate::db::{
+    create_chat, delete_chat, get_chat_summary, get_chats, get_messages_by_chat_id,
+    set_chat_auto_title, set_chat_tags, update_chat_name, update_chat_settings,
+};
 use crate::errors::MyError;
+use crate::models::ChatSettings;
+use crate::providers::ChatProvider;
+use crate::titles::retitle;
 use actix_web::{web, Error, HttpResponse};
 use deadpool_postgres::{Client, Pool};
 use serde::Deserialize;
+use serde_json::json;
+
+/// Longest tag accepted, in characters.
+const MAX_TAG_CHARS: usize = 50;
+
 #[derive(Deserialize)]
 pub struct UpdateChatName {
     chat_id: i32,
     new_chat_name: String,
 }
 
+#[derive(Deserialize)]
+pub struct ChatTags {
+    tags: Vec<String>,
+}
+
 
 pub async fn delete_chat_handler(
     db_pool: web::Data<Pool>,
     chat_id: web::Path<i32>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, Error> {
     let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let chat_id = chat_id.into_inner();
 
-    delete_chat(&client, chat_id.into_inner()).await?;
+    user.owned_chat(&client, chat_id).await?;
+    delete_chat(&client, chat_id).await?;
 
     Ok(HttpResponse::Ok().finish())
 }
@@ -24,20 +46,85 @@ pub async fn delete_chat_handler(
 pub async fn update_chat_name_handler(
     db_pool: web::Data<Pool>,
     update_chat_info: web::Json<UpdateChatName>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, MyError> {
     let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
     let chat_id = update_chat_info.chat_id;
     let new_chat_name = update_chat_info.new_chat_name.clone();
 
+    user.owned_chat(&client, chat_id).await?;
+
     update_chat_name(&client, chat_id, new_chat_name).await?;
+    // A name given by hand is not replaced by an automatic title.
+    set_chat_auto_title(&client, chat_id, Some(false)).await?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
+/// Replaces the tags of a chat and returns them, trimmed, without duplicates
+/// and sorted.
+pub async fn update_chat_tags_handler(
+    db_pool: web::Data<Pool>,
+    chat_id: web::Path<i32>,
+    body: web::Json<ChatTags>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let mut tags: Vec<String> = body
+        .tags
+        .iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    if tags.iter().any(|tag| tag.chars().count() > MAX_TAG_CHARS) {
+        return Err(MyError::BadRequest(format!(
+            "tags: must be at most {} characters each",
+            MAX_TAG_CHARS
+        )));
+    }
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let chat_id = chat_id.into_inner();
+
+    user.owned_chat(&client, chat_id).await?;
+    set_chat_tags(&client, chat_id, &tags).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "tags": tags })))
+}
+
+/// Names the chat after the start of its active branch, whether or not it has
+/// automatic titles on, and returns the new name.
+pub async fn retitle_chat_handler(
+    db_pool: web::Data<Pool>,
+    provider: web::Data<dyn ChatProvider>,
+    config: web::Data<Config>,
+    chat_id: web::Path<i32>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let chat_id = chat_id.into_inner();
+
+    let chat = user.owned_chat(&client, chat_id).await?;
+    let remaining = budget::check(&client, user.id).await?;
+    let messages = get_messages_by_chat_id(&client, chat_id).await?;
+    let chat_name = retitle(&client, provider.get_ref(), &config, user.id, &chat, &messages).await?;
+
+    let mut response = HttpResponse::Ok();
+    remaining.insert_headers(&mut response);
+    Ok(response.json(json!({
+        "chat_id": chat_id,
+        "chat_name": chat_name,
+    })))
+}
+
 pub async fn get_chats_handler(
     app_user: web::Path<i32>,
     db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, Error> {
+    user.ensure_is(*app_user)?;
+
     let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
     let new_chat = get_chats(&client, *app_user).await?;
@@ -48,19 +135,60 @@ pub async fn get_chats_handler(
 pub async fn create_chat_handler(
     db_pool: web::Data<Pool>,
     app_user: web::Path<i32>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, Error> {
+    user.ensure_is(*app_user)?;
+
     let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-    //  let new_chat = create_chat(&client, app_user.to_string()).await?;
-    match create_chat(&client, *app_user).await {
-        Ok(new_chat) => Ok(HttpResponse::Ok()
-            .content_type("application/json")
-            .json(new_chat)),
-        Err(e) => {
-            eprintln!("Error creating chat: {:?}", e);
-            Err(actix_web::error::ErrorInternalServerError(e))
-        }
-    }
+    let new_chat = create_chat(&client, *app_user).await?;
+
+    Ok(HttpResponse::Ok().json(new_chat))
+}
+
+pub async fn get_chat_settings_handler(
+    db_pool: web::Data<Pool>,
+    chat_id: web::Path<i32>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    let chat = user.owned_chat(&client, chat_id.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(chat.settings()))
+}
+
+/// Replaces the stored model settings of a chat. Fields left out of the body
+/// are cleared and fall back to the server defaults.
+pub async fn update_chat_settings_handler(
+    db_pool: web::Data<Pool>,
+    chat_id: web::Path<i32>,
+    settings: web::Json<ChatSettings>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    settings.validate()?;
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let chat_id = chat_id.into_inner();
+
+    user.owned_chat(&client, chat_id).await?;
+    update_chat_settings(&client, chat_id, &settings).await?;
+
+    Ok(HttpResponse::Ok().json(settings.into_inner()))
 }
 
+pub async fn get_chat_summary_handler(
+    db_pool: web::Data<Pool>,
+    chat_id: web::Path<i32>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let chat_id = chat_id.into_inner();
+
+    user.owned_chat(&client, chat_id).await?;
+    let summary = get_chat_summary(&client, chat_id)
+        .await?
+        .ok_or(MyError::NotFound)?;
 
+    Ok(HttpResponse::Ok().json(summary))
+}
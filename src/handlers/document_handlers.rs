@@ -0,0 +1,99 @@
+use crate::auth::AuthenticatedUser;
+use crate::budget;
+use crate::db;
+use crate::documents;
+use crate::errors::MyError;
+use crate::ChatContext;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use deadpool_postgres::{Client, Pool};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct DocumentUpload {
+    name: String,
+}
+
+/// Attaches a document to a chat. The body is the file itself, typed by its
+/// `Content-Type` or else by the extension of `name`. The text is split into
+/// chunks and embedded, which counts toward the user's budget.
+pub async fn upload_document_handler(
+    chat_id: web::Path<i32>,
+    upload: web::Query<DocumentUpload>,
+    request: HttpRequest,
+    body: web::Bytes,
+    context: ChatContext,
+) -> Result<HttpResponse, MyError> {
+    let ChatContext {
+        db_pool,
+        embedder,
+        user,
+        config,
+        ..
+    } = &context;
+    let chat_id = chat_id.into_inner();
+    let name = upload.name.trim();
+    if name.is_empty() || name.chars().count() > 255 {
+        return Err(MyError::BadRequest(
+            "name must be between 1 and 255 characters".to_string(),
+        ));
+    }
+    if body.is_empty() {
+        return Err(MyError::BadRequest("the document is empty".to_string()));
+    }
+
+    let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    user.owned_chat(&client, chat_id).await?;
+    let remaining = budget::check(&client, user.id).await?;
+
+    let declared = Some(request.content_type()).filter(|content_type| !content_type.is_empty());
+    let content_type = documents::content_type(declared, name)?;
+    let text = documents::extract_text(content_type, body).await?;
+    let chunks = documents::chunk_text(&text, config.document_chunk_tokens);
+    if chunks.is_empty() {
+        return Err(MyError::BadRequest("the document has no text".to_string()));
+    }
+
+    let embeddings = documents::embed(
+        &client,
+        embedder.get_ref(),
+        config,
+        user.id,
+        Some(chat_id),
+        chunks.clone(),
+    )
+    .await?;
+    let chunks: Vec<(String, Vec<f32>)> = chunks.into_iter().zip(embeddings).collect();
+    let document = db::add_document(&mut client, chat_id, name, content_type, &chunks).await?;
+
+    let mut response = HttpResponse::Created();
+    remaining.insert_headers(&mut response);
+    Ok(response.json(document))
+}
+
+pub async fn get_documents_handler(
+    chat_id: web::Path<i32>,
+    db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let chat_id = chat_id.into_inner();
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    user.owned_chat(&client, chat_id).await?;
+
+    let documents = db::get_documents_by_chat_id(&client, chat_id).await?;
+
+    Ok(HttpResponse::Ok().json(documents))
+}
+
+pub async fn delete_document_handler(
+    path: web::Path<(i32, i32)>,
+    db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let (chat_id, document_id) = path.into_inner();
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    user.owned_chat(&client, chat_id).await?;
+
+    db::delete_document(&client, chat_id, document_id).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use crate::auth::AuthenticatedUser;
+use crate::db::{
+    get_fine_tuning_chats, get_images_by_chat_id, get_message_ratings, get_messages_by_chat_id,
+};
+use crate::errors::MyError;
+use crate::export::{self, Format};
+use crate::fine_tuning::{self, DatasetOptions};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::{Client, Pool};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: Format,
+}
+
+/// Downloads the active branch of a chat and its generated images as JSON,
+/// Markdown or a standalone HTML page.
+pub async fn export_chat_handler(
+    db_pool: web::Data<Pool>,
+    chat_id: web::Path<i32>,
+    query: web::Query<ExportQuery>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let chat_id = chat_id.into_inner();
+
+    let chat = user.owned_chat(&client, chat_id).await?;
+    let messages = get_messages_by_chat_id(&client, chat_id).await?;
+    let images = get_images_by_chat_id(&client, chat_id).await?;
+    drop(client);
+
+    let format = query.format;
+    let file_name = export::file_name(&chat, format);
+    // Highlighting code in a long chat takes a while.
+    let body = web::block(move || export::render(format, &chat, &messages, &images).into_bytes())
+        .await
+        .map_err(|e| MyError::Internal(e.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        })
+        .body(body))
+}
+
+/// Builds an OpenAI fine-tuning dataset from the user's chats: one line of
+/// JSONL per chat that matches the filters, with the messages of its active
+/// branch. Chats that make no valid example are left out and counted in
+/// `X-Fine-Tuning-Skipped`; `validate_only` returns what became of each chat
+/// instead.
+pub async fn fine_tuning_dataset_handler(
+    db_pool: web::Data<Pool>,
+    options: web::Json<DatasetOptions>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    options.validate()?;
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    let chats =
+        get_fine_tuning_chats(&client, user.id, &options.chat_ids, &options.tags, options.rated)
+            .await?;
+
+    let mut lines = Vec::new();
+    let mut reports = Vec::with_capacity(chats.len());
+    for chat in chats {
+        let messages = get_messages_by_chat_id(&client, chat.chat_id).await?;
+        let ratings = if options.drop_low_rated {
+            get_message_ratings(&client, chat.chat_id).await?
+        } else {
+            HashMap::new()
+        };
+        let (line, report) = fine_tuning::example(chat.chat_id, messages, &ratings, &options);
+        lines.extend(line);
+        reports.push(report);
+    }
+    let skipped = reports.len() - lines.len();
+
+    if options.validate_only {
+        return Ok(HttpResponse::Ok().json(json!({
+            "examples": lines.len(),
+            "skipped": skipped,
+            "tokens": reports
+                .iter()
+                .filter(|report| report.reason.is_none())
+                .map(|report| report.tokens)
+                .sum::<usize>(),
+            "chats": reports,
+        })));
+    }
+
+    let mut body = lines.join("\n");
+    if !body.is_empty() {
+        body.push('\n');
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("application/jsonl")
+        .insert_header(("X-Fine-Tuning-Examples", lines.len().to_string()))
+        .insert_header(("X-Fine-Tuning-Skipped", skipped.to_string()))
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("fine-tuning.jsonl".to_string())],
+        })
+        .body(body))
+}
@@ -1,9 +1,12 @@
+use crate::auth::AuthenticatedUser;
+use crate::budget;
+use crate::config::Config;
 use crate::db;
-use actix_web::{web, HttpResponse, Responder};
+use crate::errors::MyError;
+use crate::providers::{ImageProvider, ImageRequestBody};
+use crate::usage::PendingCompletion;
+use actix_web::{web, HttpResponse};
 use serde::Deserialize;
-use reqwest::header::{HeaderValue, CONTENT_TYPE, AUTHORIZATION};
-use reqwest::Client;
-use serde_json::json;
 use crate::models::Image;
 use deadpool_postgres::Pool;
 
@@ -19,77 +22,64 @@ pub struct ImageGenerationRequest {
 pub async fn get_images_by_chat_id(
     chat_id: web::Path<i32>,
     pool: web::Data<Pool>,
-) -> Result<impl Responder, actix_web::Error> {
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
     let chat_id = chat_id.into_inner();
-    let client = pool.get().await.map_err(|e| {
-        actix_web::error::InternalError::new(e, actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
-    })?;
+    let client = pool.get().await?;
+
+    user.owned_chat(&client, chat_id).await?;
 
-    let images: Vec<Image> = db::get_images_by_chat_id(&client, chat_id)
-        .await
-        .map_err(|e| {
-            actix_web::error::InternalError::new(e, actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
-        })?;
+    let images: Vec<Image> = db::get_images_by_chat_id(&client, chat_id).await?;
 
     Ok(HttpResponse::Ok().json(images))
 }
 
 pub async fn generate_image(
     image_generation_request: web::Json<ImageGenerationRequest>,
-    config: web::Data<crate::config::Config>,
+    provider: web::Data<dyn ImageProvider>,
+    config: web::Data<Config>,
     pool: web::Data<deadpool_postgres::Pool>,
-    ) -> Result<impl Responder, actix_web::Error> {
-    println!("{:?}", image_generation_request);
-    let client = Client::new();
-    let url = "https://api.openai.com/v1/images/generations";
-
-
-    let request_body = json!({
-        "prompt": image_generation_request.prompt,
-        "n": image_generation_request.n.unwrap_or(1),
-        "size": image_generation_request.size.as_ref().unwrap_or(&"1024x1024".to_string()),
-        "response_format": image_generation_request.response_format.as_ref().unwrap_or(&"url".to_string()),
-    });
-    let response = client
-        .post(url)
-        .header(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", config.api_key)).map_err(|e| {
-                actix_web::error::InternalError::new(e, actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
-            })?,
-            )
-        .header(CONTENT_TYPE, "application/json")
-        .body(request_body.to_string())
-        .send()
-        .await
-        .map_err(|e| {
-            actix_web::error::InternalError::new(e, actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
-        })?;
-
-    if response.status().is_success() {
-        let body = response.text().await.map_err(|e| {
-            actix_web::error::InternalError::new(e, actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
-        })?;
+    user: AuthenticatedUser,
+    ) -> Result<HttpResponse, MyError> {
+    let n = image_generation_request.n.unwrap_or(1);
+    if n == 0 || n > config.max_images_per_request {
+        return Err(MyError::BadRequest(format!(
+            "n must be between 1 and {}",
+            config.max_images_per_request
+        )));
+    }
 
-        let json_body: serde_json::Value = serde_json::from_str(&body)?;
-        let image_url = json_body["data"][0]["url"].as_str().unwrap_or_default().to_string();
-        let chat_id = image_generation_request.chat_id;
+    let client = pool.get().await?;
+    user.owned_chat(&client, image_generation_request.chat_id).await?;
+    let remaining = budget::check(&client, user.id).await?;
 
-        let client = pool.get().await.map_err(|e| {
-            actix_web::error::InternalError::new(e, actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
-        })?;
+    let request_body = ImageRequestBody {
+        model: config.image_model.clone(),
+        prompt: image_generation_request.prompt.clone(),
+        n,
+        size: image_generation_request.size.clone().unwrap_or_else(|| "1024x1024".to_string()),
+        response_format: image_generation_request.response_format.clone().unwrap_or_else(|| "url".to_string()),
+    };
 
-        db::save_generated_image(&client, chat_id, image_url).await.map_err(|e| {
-            actix_web::error::InternalError::new(e, actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
-        })?;
+    let chat_id = image_generation_request.chat_id;
+    let pending = PendingCompletion::start_image(user.id, chat_id, &request_body.model);
+    let body = provider.generate_image(&request_body).await?;
 
-        Ok(HttpResponse::Ok().body(body))
-    } else {
+    let json_body: serde_json::Value = serde_json::from_str(&body).map_err(|e| MyError::Upstream {
+        status: None,
+        code: None,
+        message: format!("invalid JSON in image response: {}", e),
+        timed_out: false,
+    })?;
+    let image_url = json_body["data"][0]["url"].as_str().unwrap_or_default().to_string();
+    let generated = json_body["data"].as_array().map_or(n, |data| data.len() as u32);
 
-        let error_msg = format!("Error in image generation: {}", response.status());
-        Err(actix_web::error::InternalError::new(
-                error_msg,
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-                ).into())
+    if let Err(e) = db::add_completion(&client, pending.finish_image(generated, &config.pricing)).await {
+        eprintln!("Error recording image usage: {}", e);
     }
+    db::save_generated_image(&client, chat_id, image_url).await?;
+
+    let mut response = HttpResponse::Ok();
+    remaining.insert_headers(&mut response);
+    Ok(response.content_type("application/json").body(body))
 }
@@ -0,0 +1,63 @@
+use crate::auth::AuthenticatedUser;
+use crate::db::get_user;
+use crate::errors::MyError;
+use crate::import::{self, ImportResult};
+use actix_web::{web, HttpResponse};
+use bytes::Bytes;
+use deadpool_postgres::{Client, Pool};
+use serde_json::json;
+
+/// Imports the `conversations.json` of a ChatGPT data export as chats, every
+/// branch included. Conversations imported before are skipped, and one that
+/// cannot be read or saved does not stop the others. Users can import into
+/// their own account; admins into anyone's.
+pub async fn import_chats_handler(
+    db_pool: web::Data<Pool>,
+    app_user: web::Path<i32>,
+    user: AuthenticatedUser,
+    body: Bytes,
+) -> Result<HttpResponse, MyError> {
+    let mut client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let app_user = app_user.into_inner();
+    if user.id != app_user {
+        user.ensure_admin(&client).await?;
+    }
+    get_user(&client, app_user).await?;
+
+    // Exports of a few years of history are large.
+    let conversations = web::block(move || {
+        let conversations: Vec<serde_json::Value> = serde_json::from_slice(&body).map_err(|e| {
+            MyError::BadRequest(format!(
+                "expected the conversations.json of a ChatGPT export: {}",
+                e
+            ))
+        })?;
+        let parsed = conversations
+            .iter()
+            .enumerate()
+            .map(|(index, conversation)| {
+                import::parse(conversation)
+                    .map_err(|e| ImportResult::failed(index, conversation, &e))
+            })
+            .collect::<Vec<_>>();
+        Ok::<_, MyError>(parsed)
+    })
+    .await
+    .map_err(|e| MyError::Internal(e.to_string()))??;
+
+    let mut results = Vec::with_capacity(conversations.len());
+    for (index, conversation) in conversations.into_iter().enumerate() {
+        results.push(match conversation {
+            Ok(conversation) => import::save(&mut client, app_user, index, conversation).await,
+            Err(failed) => failed,
+        });
+    }
+
+    let count = |status| results.iter().filter(|result| result.status == status).count();
+    Ok(HttpResponse::Ok().json(json!({
+        "imported": count("imported"),
+        "skipped": count("skipped"),
+        "failed": count("failed"),
+        "conversations": results,
+    })))
+}
@@ -1,15 +1,62 @@
-use crate::db::{add_message, get_messages_by_chat_id};
+use crate::auth::AuthenticatedUser;
+use crate::db::{
+    add_message, get_branches, get_message, get_message_tree, get_messages_by_chat_id,
+    set_active_message, set_message_pinned, set_message_rating, switch_branch,
+};
 use crate::errors::MyError;
-use crate::models::Message;
+use crate::message_index;
+use crate::models::{ChatSettings, Message};
+use crate::ChatContext;
 use actix_web::{web, Error, HttpResponse};
+use chrono::Utc;
 use deadpool_postgres::{Client, Pool};
+use serde::Deserialize;
 
+#[derive(Deserialize)]
+pub struct PinMessage {
+    pinned: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RateMessage {
+    rating: Option<i16>,
+}
+
+#[derive(Deserialize)]
+pub struct MessagesQuery {
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SwitchBranch {
+    message_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct EditMessage {
+    content: String,
+    #[serde(flatten)]
+    settings: ChatSettings,
+}
+
+/// The messages of the chat's active branch, or with `?all=true` every
+/// message of every branch.
 pub async fn get_messages_by_chat_id_endpoint(
     chat_id: web::Path<i32>,
+    query: web::Query<MessagesQuery>,
     db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
 ) -> Result<HttpResponse, Error> {
     let chat_id_value = chat_id.into_inner();
-    let messages = get_messages_by_chat_id_handler(db_pool.clone(), chat_id_value).await?;
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    user.owned_chat(&client, chat_id_value).await?;
+    let messages = if query.all {
+        get_message_tree(&client, chat_id_value).await?
+    } else {
+        get_messages_by_chat_id(&client, chat_id_value).await?
+    };
     Ok(HttpResponse::Ok().json(messages))
 }
 
@@ -24,15 +71,166 @@ pub async fn get_messages_by_chat_id_handler(
     Ok(messages)
 }
 
-pub async fn add_message_handler(
+/// Pins or unpins a message. Pinned messages are always sent upstream by the
+/// `pinned` context strategy.
+pub async fn pin_message_handler(
+    path: web::Path<(i32, i32)>,
+    pin: web::Json<PinMessage>,
     db_pool: web::Data<Pool>,
-    message: web::Json<Message>,
-) -> Result<HttpResponse, Error> {
-    let message_info: Message = message.into_inner();
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let (chat_id, message_id) = path.into_inner();
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    user.owned_chat(&client, chat_id).await?;
+
+    set_message_pinned(&client, chat_id, message_id, pin.pinned).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Rates an assistant reply good (`1`) or bad (`-1`), or clears its rating
+/// (`null`). Ratings select chats and turns for fine-tuning datasets.
+pub async fn rate_message_handler(
+    path: web::Path<(i32, i32)>,
+    rate: web::Json<RateMessage>,
+    db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    if rate.rating.is_some_and(|rating| rating != 1 && rating != -1) {
+        return Err(MyError::BadRequest(
+            "rating: must be 1, -1 or null".to_string(),
+        ));
+    }
+
+    let (chat_id, message_id) = path.into_inner();
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    user.owned_chat(&client, chat_id).await?;
+
+    set_message_rating(&client, chat_id, message_id, rate.rating).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Every branch of the chat, identified by its leaf message.
+pub async fn get_branches_handler(
+    chat_id: web::Path<i32>,
+    db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let chat_id = chat_id.into_inner();
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    user.owned_chat(&client, chat_id).await?;
+    let branches = get_branches(&client, chat_id).await?;
 
+    Ok(HttpResponse::Ok().json(branches))
+}
+
+/// Switches the chat to the branch through `message_id`, continuing at the
+/// newest leaf below it, and returns the messages of that branch.
+pub async fn switch_branch_handler(
+    chat_id: web::Path<i32>,
+    branch: web::Json<SwitchBranch>,
+    db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let chat_id = chat_id.into_inner();
     let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
 
-    let new_message = add_message(&client, message_info).await?;
+    user.owned_chat(&client, chat_id).await?;
+    switch_branch(&client, chat_id, branch.message_id).await?;
+    let messages = get_messages_by_chat_id(&client, chat_id).await?;
+
+    Ok(HttpResponse::Ok().json(messages))
+}
+
+/// Edits a user message by adding the new content as a sibling branch, then
+/// answers it. The original message and its replies stay in their own branch.
+/// Model settings may be sent alongside `content`, as with
+/// `POST /chat/{chat_id}`.
+pub async fn edit_message_handler(
+    path: web::Path<(i32, i32)>,
+    edit: web::Json<EditMessage>,
+    context: ChatContext,
+) -> Result<HttpResponse, MyError> {
+    let ChatContext { db_pool, user, .. } = &context;
+    let (chat_id, message_id) = path.into_inner();
+    edit.settings.validate()?;
+    if edit.content.trim().is_empty() {
+        return Err(MyError::BadRequest("content must not be empty".to_string()));
+    }
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    user.owned_chat(&client, chat_id).await?;
+    let original = get_message(&client, chat_id, message_id).await?;
+    if original.role != "user" {
+        return Err(MyError::BadRequest(
+            "only user messages can be edited".to_string(),
+        ));
+    }
+    let remaining = crate::check_budget(db_pool, user).await?;
+
+    set_active_message(&client, chat_id, original.parent_id).await?;
+    add_message(
+        &client,
+        Message {
+            id: None,
+            created_on: Utc::now(),
+            role: original.role,
+            content: edit.content.clone(),
+            chat_id_relation: chat_id,
+            pinned: original.pinned,
+            parent_id: original.parent_id,
+            tool_calls: None,
+            tool_call_id: None,
+            citations: None,
+        },
+    )
+    .await?;
+    message_index::wake();
+    drop(client);
+
+    let prepared = crate::prepare_chat_request(&context, chat_id, &edit.settings, None).await?;
+
+    let body = crate::complete_chat(&context, chat_id, prepared).await?;
+    context.auto_title(chat_id).spawn();
+
+    Ok(crate::completion_response(body, &remaining))
+}
+
+/// Adds a new assistant reply next to the last one of the active branch and
+/// switches to it. When the branch ends with a user message (e.g. after a
+/// failed completion), that message is answered instead. The optional JSON
+/// body holds model settings.
+pub async fn regenerate_handler(
+    chat_id: web::Path<i32>,
+    settings: Option<web::Json<ChatSettings>>,
+    context: ChatContext,
+) -> Result<HttpResponse, MyError> {
+    let ChatContext { db_pool, user, .. } = &context;
+    let chat_id = chat_id.into_inner();
+    let settings = settings.map(web::Json::into_inner).unwrap_or_default();
+    settings.validate()?;
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let chat = user.owned_chat(&client, chat_id).await?;
+    let last_id = chat
+        .active_message_id
+        .ok_or_else(|| MyError::BadRequest("the chat has no messages".to_string()))?;
+    let last = get_message(&client, chat_id, last_id).await?;
+    let remaining = crate::check_budget(db_pool, user).await?;
+
+    if last.role == "assistant" {
+        set_active_message(&client, chat_id, last.parent_id).await?;
+    }
+    drop(client);
+
+    let prepared = crate::prepare_chat_request(&context, chat_id, &settings, None).await?;
+
+    let body = crate::complete_chat(&context, chat_id, prepared).await?;
+    context.auto_title(chat_id).spawn();
 
-    Ok(HttpResponse::Ok().json(new_message))
+    Ok(crate::completion_response(body, &remaining))
 }
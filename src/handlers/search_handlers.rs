@@ -0,0 +1,128 @@
+use crate::auth::AuthenticatedUser;
+use crate::budget;
+use crate::config::Config;
+use crate::db::search;
+use crate::errors::MyError;
+use crate::export::escape_html;
+use crate::message_index;
+use crate::providers::EmbeddingProvider;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::{Client, Pool};
+use serde::Deserialize;
+use serde_json::json;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+const DEFAULT_SEMANTIC_LIMIT: i64 = 10;
+const MAX_SEMANTIC_LIMIT: i64 = 50;
+/// Longer messages are cut in semantic search results.
+const MAX_CONTENT_CHARS: usize = 500;
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+/// Searches the names and messages of the user's chats, every branch
+/// included, and returns a page of ranked hits.
+pub async fn search_handler(
+    db_pool: web::Data<Pool>,
+    query: web::Query<SearchQuery>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(MyError::BadRequest("q must not be empty".to_string()));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(MyError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_LIMIT
+        )));
+    }
+    let offset = query.offset.unwrap_or(0);
+    if offset < 0 {
+        return Err(MyError::BadRequest("offset must not be negative".to_string()));
+    }
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let mut hits = search(&client, user.id, q, limit, offset).await?;
+    let total = hits.first().map_or(0, |hit| hit.total);
+    for hit in &mut hits {
+        hit.snippet = highlight(&hit.snippet);
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "query": q,
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "hits": hits,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct SemanticSearchQuery {
+    q: String,
+    limit: Option<i64>,
+}
+
+/// Finds the user's messages closest in meaning to `q`, across all chats.
+/// Embedding the query counts against the user's budget.
+pub async fn semantic_search_handler(
+    db_pool: web::Data<Pool>,
+    embedder: web::Data<dyn EmbeddingProvider>,
+    config: web::Data<Config>,
+    query: web::Query<SemanticSearchQuery>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    if !config.semantic_search {
+        return Err(MyError::BadRequest("semantic search is disabled".to_string()));
+    }
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(MyError::BadRequest("q must not be empty".to_string()));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_SEMANTIC_LIMIT);
+    if !(1..=MAX_SEMANTIC_LIMIT).contains(&limit) {
+        return Err(MyError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_SEMANTIC_LIMIT
+        )));
+    }
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    budget::check(&client, user.id).await?;
+    let mut hits = message_index::search(
+        &client,
+        embedder.get_ref(),
+        &config,
+        user.id,
+        q,
+        limit as usize,
+    )
+    .await?;
+    for hit in &mut hits {
+        if let Some((end, _)) = hit.content.char_indices().nth(MAX_CONTENT_CHARS) {
+            hit.content = format!("{}…", &hit.content[..end]);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "query": q,
+        "limit": limit,
+        "hits": hits,
+    })))
+}
+
+/// Escapes a snippet for HTML and turns the match markers set by the query
+/// into `<mark>` tags.
+fn highlight(snippet: &str) -> String {
+    escape_html(snippet)
+        .replace('\u{2}', "<mark>")
+        .replace('\u{3}', "</mark>")
+}
@@ -0,0 +1,55 @@
+use crate::auth::AuthenticatedUser;
+use crate::db::{get_completions_by_chat_id, get_usage_by_chat_id, get_usage_by_user};
+use crate::errors::MyError;
+use crate::usage::UsageSummary;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Client, Pool};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Optional time range, `from` inclusive and `to` exclusive, in RFC 3339.
+#[derive(Deserialize)]
+pub struct UsageRange {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+/// Usage totals of a chat, plus the usage of each of its completions.
+pub async fn get_chat_usage_handler(
+    db_pool: web::Data<Pool>,
+    chat_id: web::Path<i32>,
+    range: web::Query<UsageRange>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let chat_id = chat_id.into_inner();
+
+    user.owned_chat(&client, chat_id).await?;
+    let summary = UsageSummary::from(get_usage_by_chat_id(&client, chat_id, range.from, range.to).await?);
+    let completions = get_completions_by_chat_id(&client, chat_id, range.from, range.to).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "chat_id": chat_id,
+        "usage": summary,
+        "completions": completions,
+    })))
+}
+
+/// Usage totals of a user across all their chats, including deleted ones.
+pub async fn get_user_usage_handler(
+    db_pool: web::Data<Pool>,
+    app_user: web::Path<i32>,
+    range: web::Query<UsageRange>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    user.ensure_is(*app_user)?;
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let summary = UsageSummary::from(get_usage_by_user(&client, *app_user, range.from, range.to).await?);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "app_user": *app_user,
+        "usage": summary,
+    })))
+}
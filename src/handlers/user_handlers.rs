@@ -0,0 +1,110 @@
+use crate::auth::{generate_api_key, hash_api_key, issue_token, AuthenticatedUser};
+use crate::config::Config;
+use crate::db::{create_user, get_user, update_user_api_key, update_user_tier};
+use crate::errors::MyError;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::{Client, Pool};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct UserTier {
+    tier: String,
+}
+
+/// Registers a user and returns its API key. The key is only shown once.
+/// Disabled unless `ALLOW_SIGNUP=true`.
+pub async fn create_user_handler(
+    db_pool: web::Data<Pool>,
+    config: web::Data<Config>,
+    new_user: web::Json<NewUser>,
+) -> Result<HttpResponse, MyError> {
+    if !config.allow_signup {
+        return Err(MyError::Forbidden);
+    }
+    if new_user.username.trim().is_empty() {
+        return Err(MyError::BadRequest("username must not be empty".to_string()));
+    }
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    let api_key = generate_api_key();
+    let user = create_user(&client, new_user.username.trim(), &hash_api_key(&api_key)).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": user.id,
+        "username": user.username,
+        "created_on": user.created_on,
+        "api_key": api_key,
+    })))
+}
+
+pub async fn get_current_user_handler(
+    db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    let user = get_user(&client, user.id).await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Replaces the user's API key. The previous key, and bearer tokens issued
+/// before, stop working immediately.
+pub async fn rotate_api_key_handler(
+    db_pool: web::Data<Pool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+
+    let api_key = generate_api_key();
+    update_user_api_key(&client, user.id, &hash_api_key(&api_key)).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "api_key": api_key })))
+}
+
+/// Exchanges the caller's credentials for a short-lived bearer token.
+pub async fn issue_token_handler(
+    config: web::Data<Config>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let secret = config
+        .jwt_secret
+        .as_deref()
+        .ok_or_else(|| MyError::BadRequest("JWT_SECRET is not configured".to_string()))?;
+
+    let (token, expires_at) = issue_token(&user, secret)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "token": token,
+        "token_type": "Bearer",
+        "expires_at": expires_at,
+    })))
+}
+
+/// Moves a user to another rate limit tier. Admins only. Bearer tokens carry
+/// the tier they were issued with until they expire.
+pub async fn update_user_tier_handler(
+    db_pool: web::Data<Pool>,
+    app_user: web::Path<i32>,
+    tier: web::Json<UserTier>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, MyError> {
+    let tier = tier.tier.trim();
+    if tier.is_empty() || tier.len() > 64 {
+        return Err(MyError::BadRequest(
+            "tier must be between 1 and 64 characters".to_string(),
+        ));
+    }
+
+    let client: Client = db_pool.get().await.map_err(MyError::PoolError)?;
+    user.ensure_admin(&client).await?;
+    update_user_tier(&client, *app_user, tier).await?;
+
+    Ok(HttpResponse::Ok().json(get_user(&client, *app_user).await?))
+}
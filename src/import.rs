@@ -0,0 +1,436 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, TimeZone, Utc};
+use deadpool_postgres::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::db;
+use crate::errors::MyError;
+use crate::message_index;
+use crate::models::Message;
+
+/// Name of imported chats that have no title.
+const UNTITLED: &str = "Imported chat";
+
+/// Longest chat name kept, in characters.
+const MAX_TITLE_CHARS: usize = 255;
+
+/// A conversation of ChatGPT's `conversations.json` export. Its messages form
+/// a tree in `mapping`, keyed by node id, with one branch per edit or
+/// regenerated reply.
+#[derive(Deserialize)]
+struct Conversation {
+    title: Option<String>,
+    create_time: Option<f64>,
+    #[serde(default)]
+    mapping: HashMap<String, Node>,
+    current_node: Option<String>,
+    conversation_id: Option<String>,
+    id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    message: Option<ExportedMessage>,
+    parent: Option<String>,
+    #[serde(default)]
+    children: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ExportedMessage {
+    author: Author,
+    create_time: Option<f64>,
+    content: Option<Content>,
+    recipient: Option<String>,
+    metadata: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct Content {
+    content_type: String,
+    #[serde(default)]
+    parts: Vec<Value>,
+}
+
+/// A conversation ready to be saved as a chat.
+pub struct ParsedConversation {
+    /// The conversation's id in the export, which makes importing it again a
+    /// no-op.
+    pub import_id: Option<String>,
+    pub title: Option<String>,
+    created_on: DateTime<Utc>,
+    /// Every branch of the conversation, parents before their children.
+    messages: Vec<ImportedMessage>,
+    /// Index in `messages` of the leaf of the branch that was open last.
+    active: Option<usize>,
+    /// Messages left out: system prompts, tool calls and results, hidden and
+    /// empty messages.
+    skipped_messages: usize,
+}
+
+struct ImportedMessage {
+    parent: Option<usize>,
+    role: String,
+    content: String,
+    created_on: DateTime<Utc>,
+}
+
+/// Reads one conversation of a ChatGPT export, keeping the user and assistant
+/// messages of all its branches. Messages whose parent was left out follow
+/// their nearest kept ancestor instead.
+pub fn parse(conversation: &Value) -> Result<ParsedConversation, MyError> {
+    let conversation = Conversation::deserialize(conversation)
+        .map_err(|e| MyError::BadRequest(format!("not a ChatGPT conversation: {}", e)))?;
+    let created_on = conversation
+        .create_time
+        .and_then(timestamp)
+        .unwrap_or_else(Utc::now);
+
+    let mut roots: Vec<&String> = conversation
+        .mapping
+        .iter()
+        .filter(|(_, node)| {
+            node.parent
+                .as_ref()
+                .is_none_or(|parent| !conversation.mapping.contains_key(parent))
+        })
+        .map(|(id, _)| id)
+        .collect();
+    roots.sort();
+
+    let mut messages: Vec<ImportedMessage> = Vec::new();
+    let mut skipped_messages = 0;
+    // The nearest kept message at or above each node.
+    let mut kept: HashMap<&str, Option<usize>> = HashMap::new();
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut stack: Vec<(&str, Option<usize>)> =
+        roots.into_iter().rev().map(|id| (id.as_str(), None)).collect();
+
+    while let Some((id, parent)) = stack.pop() {
+        // The export should be a tree, but a cycle must not loop forever.
+        if !visited.insert(id) {
+            continue;
+        }
+        let Some(node) = conversation.mapping.get(id) else {
+            continue;
+        };
+
+        let mut nearest = parent;
+        if let Some(message) = &node.message {
+            match importable(message) {
+                Some((role, content)) => {
+                    let created_on = message
+                        .create_time
+                        .and_then(timestamp)
+                        .or_else(|| parent.map(|parent| messages[parent].created_on))
+                        .unwrap_or(created_on);
+                    messages.push(ImportedMessage {
+                        parent,
+                        role,
+                        content,
+                        created_on,
+                    });
+                    nearest = Some(messages.len() - 1);
+                }
+                None => skipped_messages += 1,
+            }
+        }
+        kept.insert(id, nearest);
+
+        for child in node.children.iter().rev() {
+            stack.push((child.as_str(), nearest));
+        }
+    }
+
+    // Without a known current node, the branch with the newest message.
+    let active = conversation
+        .current_node
+        .as_deref()
+        .and_then(|id| kept.get(id).copied())
+        .unwrap_or_else(|| {
+            messages
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, message)| message.created_on)
+                .map(|(index, _)| index)
+        });
+
+    Ok(ParsedConversation {
+        import_id: conversation.conversation_id.or(conversation.id),
+        title: conversation.title,
+        created_on,
+        messages,
+        active,
+        skipped_messages,
+    })
+}
+
+/// The role and text of a message shown in the conversation, or `None` for
+/// anything else. Images in the text are replaced by `[image]`.
+fn importable(message: &ExportedMessage) -> Option<(String, String)> {
+    if !matches!(message.author.role.as_str(), "user" | "assistant")
+        || message.recipient.as_deref().is_some_and(|recipient| recipient != "all")
+    {
+        return None;
+    }
+    let hidden = message.metadata.as_ref().is_some_and(|metadata| {
+        metadata["is_visually_hidden_from_conversation"].as_bool() == Some(true)
+    });
+    if hidden {
+        return None;
+    }
+
+    let content = message.content.as_ref()?;
+    if !matches!(content.content_type.as_str(), "text" | "multimodal_text") {
+        return None;
+    }
+    let parts: Vec<&str> = content
+        .parts
+        .iter()
+        .map(|part| part.as_str().unwrap_or("[image]"))
+        .filter(|part| !part.trim().is_empty())
+        .collect();
+    let text = parts.join("\n\n");
+    (!text.trim().is_empty()).then(|| (message.author.role.clone(), text))
+}
+
+fn timestamp(seconds: f64) -> Option<DateTime<Utc>> {
+    let millis = (seconds * 1000.0).round() as i64;
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+/// What became of one conversation of an import.
+#[derive(Serialize)]
+pub struct ImportResult {
+    /// Position of the conversation in the export.
+    pub index: usize,
+    pub id: Option<String>,
+    pub title: Option<String>,
+    /// `imported`, `skipped` when it was imported before, or `failed`.
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<i32>,
+    pub messages: usize,
+    pub skipped_messages: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ImportResult {
+    /// A conversation that could not be read, with what can be found of its
+    /// id and title.
+    pub fn failed(index: usize, conversation: &Value, error: &MyError) -> Self {
+        let field = |name: &str| conversation[name].as_str().map(str::to_string);
+        Self {
+            index,
+            id: field("conversation_id").or_else(|| field("id")),
+            title: field("title"),
+            status: "failed",
+            chat_id: None,
+            messages: 0,
+            skipped_messages: 0,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Saves `conversation` as a chat of `app_user`, with every branch, unless
+/// it was imported before. A conversation that fails part way is not saved,
+/// so it can be imported again.
+pub async fn save(
+    client: &mut Client,
+    app_user: i32,
+    index: usize,
+    conversation: ParsedConversation,
+) -> ImportResult {
+    let mut result = ImportResult {
+        index,
+        id: conversation.import_id.clone(),
+        title: conversation.title.clone(),
+        status: "imported",
+        chat_id: None,
+        messages: conversation.messages.len(),
+        skipped_messages: conversation.skipped_messages,
+        error: None,
+    };
+
+    if let Some(import_id) = &conversation.import_id {
+        match db::chat_import_exists(client, app_user, import_id).await {
+            Ok(true) => {
+                result.status = "skipped";
+                return result;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                result.status = "failed";
+                result.error = Some(e.to_string());
+                return result;
+            }
+        }
+    }
+
+    let title: String = conversation
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .unwrap_or(UNTITLED)
+        .chars()
+        .take(MAX_TITLE_CHARS)
+        .collect();
+    let messages: Vec<(Option<usize>, Message)> = conversation
+        .messages
+        .into_iter()
+        .map(|message| {
+            (
+                message.parent,
+                Message {
+                    id: None,
+                    created_on: message.created_on,
+                    role: message.role,
+                    content: message.content,
+                    chat_id_relation: 0,
+                    pinned: false,
+                    parent_id: None,
+                    tool_calls: None,
+                    tool_call_id: None,
+                    citations: None,
+                },
+            )
+        })
+        .collect();
+
+    match db::add_imported_chat(
+        client,
+        app_user,
+        conversation.created_on,
+        &title,
+        conversation.import_id.as_deref(),
+        &messages,
+        conversation.active,
+    )
+    .await
+    {
+        Ok(chat) => {
+            result.chat_id = Some(chat.chat_id);
+            message_index::wake();
+        }
+        Err(e) => {
+            result.status = "failed";
+            result.error = Some(e.to_string());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(index: usize) -> ParsedConversation {
+        let conversations: Vec<Value> = serde_json::from_str(include_str!(
+            "../tests/fixtures/chatgpt_conversations.json"
+        ))
+        .unwrap();
+        parse(&conversations[index]).unwrap()
+    }
+
+    fn summary(conversation: &ParsedConversation) -> Vec<(Option<usize>, &str, &str)> {
+        conversation
+            .messages
+            .iter()
+            .map(|message| (message.parent, message.role.as_str(), message.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_every_branch_in_tree_order() {
+        let conversation = fixture(0);
+        assert_eq!(conversation.import_id.as_deref(), Some("conv-branches"));
+        assert_eq!(conversation.title.as_deref(), Some("Branches and tools"));
+        assert_eq!(
+            summary(&conversation),
+            vec![
+                (None, "user", "What is 2 + 2?"),
+                (Some(0), "assistant", "5"),
+                (Some(0), "assistant", "Let me check."),
+                (Some(2), "assistant", "It is 4."),
+                (Some(3), "user", "[image]\n\nAnd this one?"),
+            ]
+        );
+        assert_eq!(
+            conversation.messages[1].created_on,
+            timestamp(1700000002.0).unwrap()
+        );
+    }
+
+    #[test]
+    fn skipped_messages_give_way_to_their_nearest_kept_ancestor() {
+        let conversation = fixture(0);
+        // The hidden system prompt, the tool call and its result.
+        assert_eq!(conversation.skipped_messages, 3);
+        // The reply after the tool call follows the message that made it.
+        assert_eq!(conversation.messages[3].parent, Some(2));
+        // The first message, under the system prompt, becomes a root.
+        assert_eq!(conversation.messages[0].parent, None);
+    }
+
+    #[test]
+    fn opens_the_current_node() {
+        assert_eq!(fixture(0).active, Some(4));
+    }
+
+    #[test]
+    fn opens_the_newest_message_without_a_current_node() {
+        let conversation = fixture(1);
+        assert_eq!(conversation.import_id.as_deref(), Some("conv-newest"));
+        assert_eq!(conversation.messages[1].content, "Hello!");
+        assert_eq!(conversation.active, Some(1));
+    }
+
+    #[test]
+    fn opens_the_nearest_kept_ancestor_of_a_left_out_current_node() {
+        let conversation = fixture(2);
+        assert_eq!(
+            summary(&conversation),
+            vec![
+                (None, "user", "Remember this."),
+                (Some(0), "assistant", "Noted."),
+            ]
+        );
+        assert_eq!(conversation.skipped_messages, 1);
+        assert_eq!(conversation.active, Some(1));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_conversation() {
+        assert!(parse(&serde_json::json!([1, 2])).is_err());
+        assert!(parse(&serde_json::json!({ "mapping": { "a": { "parent": 3 } } })).is_err());
+    }
+
+    #[test]
+    fn survives_cycles() {
+        let conversation = parse(&serde_json::json!({
+            "mapping": {
+                "a": {
+                    "message": {
+                        "author": { "role": "user" },
+                        "content": { "content_type": "text", "parts": ["loop"] }
+                    },
+                    "parent": null,
+                    "children": ["b"]
+                },
+                "b": { "message": null, "parent": "a", "children": ["a"] }
+            }
+        }))
+        .unwrap();
+        assert_eq!(summary(&conversation), vec![(None, "user", "loop")]);
+    }
+}
@@ -1,154 +1,168 @@
 mod handlers {
+    pub mod batch_handlers;
+    pub mod budget_handlers;
     pub mod chat_handlers;
+    pub mod document_handlers;
+    pub mod export_handlers;
+    pub mod import_handlers;
     pub mod message_handlers;
     pub mod image_handlers;
+    pub mod search_handlers;
+    pub mod usage_handlers;
+    pub mod user_handlers;
 }
+use handlers::batch_handlers;
+use handlers::budget_handlers;
 use handlers::chat_handlers;
+use handlers::document_handlers;
+use handlers::export_handlers;
+use handlers::import_handlers;
 use handlers::message_handlers;
 use handlers::image_handlers;
+use handlers::search_handlers;
+use handlers::usage_handlers;
+use handlers::user_handlers;
 
 use actix_cors::Cors;
 use actix_web::body::BoxBody;
 use actix_web::body::EitherBody;
 use actix_web::dev::ServiceFactory;
 use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
-use actix_web::{post, web, App, HttpResponse, Responder};
+use actix_web::dev::Payload;
+use actix_web::{post, web, App, FromRequest, HttpRequest, HttpResponse};
+use std::future::{ready, Ready};
 use chrono::Utc;
-use reqwest::header::{HeaderValue, AUTHORIZATION};
-use reqwest::Client;
-use serde::ser::SerializeMap;
-use serde::{Deserialize, Serialize, Serializer};
-use serde_json;
-use std::error::Error as StdError;
-use std::fmt;
+use serde::{Deserialize, Serialize};
 extern crate chrono;
 extern crate serde;
 
+pub mod auth;
+pub mod batches;
+pub mod budget;
 pub mod config;
+pub mod context;
 pub mod db;
+pub mod documents;
 pub mod errors;
+pub mod export;
+pub mod fine_tuning;
+pub mod import;
+pub mod message_index;
+pub mod migrations;
 pub mod models;
+pub mod providers;
+pub mod rate_limit;
+pub mod request_id;
+mod streaming;
+pub mod summary;
+pub mod titles;
+pub mod tokenizer;
+pub mod tools;
+pub mod usage;
+
+use errors::MyError;
+use providers::{ChatProvider, ChatRequestBody, EmbeddingProvider};
 
 #[derive(Debug, Deserialize, Clone)]
 struct ChatPromptRequestBody {
     messages: Vec<ChatCompletionMessage>,
+    #[serde(flatten)]
+    settings: models::ChatSettings,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ChatCompletionMessage {
     role: String,
     content: String,
+    /// Tools an assistant message calls, in OpenAI's format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<serde_json::Value>,
+    /// The call a `tool` message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
 }
 
-#[derive(Debug)]
-struct OpenAIError(String);
-
-impl fmt::Display for OpenAIError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+impl ChatCompletionMessage {
+    /// A plain message, without tool calls.
+    pub fn new(role: &str, content: String) -> Self {
+        Self {
+            role: role.to_string(),
+            content,
+            tool_calls: None,
+            tool_call_id: None,
+        }
     }
 }
 
-#[derive(Debug, Serialize)]
-struct ChatRequestBody {
-    model: String,
-    messages: Vec<ChatCompletionMessage>,
-    temperature: Option<f32>,
-    max_tokens: Option<usize>,
+impl From<models::Message> for ChatCompletionMessage {
+    fn from(message: models::Message) -> Self {
+        Self {
+            role: message.role,
+            content: message.content,
+            tool_calls: message.tool_calls,
+            tool_call_id: message.tool_call_id,
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct OpenAIRequestChatCompletion {
-    model: String,
-    messages: Vec<ChatCompletionMessage>,
-    temperature: Option<f32>,
-    max_tokens: usize,
+/// What answering in a chat takes: the database, the providers, the tools,
+/// the user and the configuration. Handlers extract it in one go and pass it
+/// on to the helpers they share.
+pub struct ChatContext {
+    pub(crate) db_pool: web::Data<deadpool_postgres::Pool>,
+    pub(crate) provider: web::Data<dyn ChatProvider>,
+    pub(crate) embedder: web::Data<dyn EmbeddingProvider>,
+    pub(crate) tools: web::Data<tools::ToolRegistry>,
+    pub(crate) user: auth::AuthenticatedUser,
+    pub(crate) config: web::Data<config::Config>,
 }
 
-impl StdError for OpenAIError {}
-
-#[derive(Debug, Clone)]
-enum OpenAIRequest<'a> {
-    ChatCompletion {
-        model: String,
-        messages: &'a Vec<ChatCompletionMessage>,
-        temperature: Option<f32>,
-    },
-}
+impl FromRequest for ChatContext {
+    type Error = MyError;
+    type Future = Ready<Result<Self, Self::Error>>;
 
-impl<'a> Serialize for OpenAIRequest<'a> {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        match self {
-            OpenAIRequest::ChatCompletion {
-                model,
-                messages,
-                temperature,
-            } => {
-                let mut map = serializer.serialize_map(Some(3))?;
-                map.serialize_entry("model", model)?;
-                map.serialize_entry("messages", messages)?;
-                map.serialize_entry("temperature", temperature)?;
-                map.end()
-            }
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        fn data<T: ?Sized + 'static>(req: &HttpRequest) -> Result<web::Data<T>, MyError> {
+            req.app_data::<web::Data<T>>().cloned().ok_or_else(|| {
+                MyError::Internal(format!("{} is not configured", std::any::type_name::<T>()))
+            })
         }
+
+        let context = auth::AuthenticatedUser::from_request(req, payload)
+            .into_inner()
+            .and_then(|user| {
+                Ok(Self {
+                    db_pool: data(req)?,
+                    provider: data(req)?,
+                    embedder: data(req)?,
+                    tools: data(req)?,
+                    user,
+                    config: data(req)?,
+                })
+            });
+        ready(context)
     }
 }
 
-async fn call_openai_api(
-    input: OpenAIRequest<'_>,
-    api_key: String,
-    url: String,
-) -> Result<String, Box<dyn StdError>> {
-    // Print that we are in the function
-    println!("In call_openai_api");
-
-    let req_body = serde_json::to_string(&input)?;
-    let client = Client::new();
-
-    // Print the request body
-    println!("Request body: {:?}", input);
-    println!("Request body: {:?}", req_body);
-
-    let request_body = match input {
-        OpenAIRequest::ChatCompletion {
-            model: _,
-            messages,
-            temperature: _,
-        } => {
-            let prompt = ChatRequestBody {
-                model: "gpt-4".to_string(),
-                messages: messages.clone(),
-                temperature: Some(1.2),
-                max_tokens: Some(1000),
-            };
-            serde_json::to_vec(&prompt)?
-        }
-    };
-
-    let other_response = client
-        .post(url)
-        .header(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+impl ChatContext {
+    /// Names the chat after its first reply, in the background.
+    fn auto_title(&self, chat_id_value: i32) -> titles::AutoTitle {
+        titles::AutoTitle::new(
+            self.db_pool.get_ref().clone(),
+            self.provider.clone(),
+            self.config.clone(),
+            self.user.id,
+            chat_id_value,
         )
-        .header("Content-Type", "application/json")
-        .body(request_body)
-        .send()
-        .await?;
-
-    let response_text = other_response.text().await?;
-
-    Ok(response_text)
+    }
 }
 
 async fn add_and_save_message(
     message: &ChatCompletionMessage,
+    citations: Option<serde_json::Value>,
     chat_id_value: i32,
     db_pool: &web::Data<deadpool_postgres::Pool>,
-) -> Result<(), Box<dyn StdError>> {
+) -> Result<models::Message, MyError> {
     // Convert the message to the Message format
     let new_message = models::Message {
         id: None,
@@ -156,106 +170,397 @@ async fn add_and_save_message(
         role: message.role.clone(),
         content: message.content.clone(),
         chat_id_relation: chat_id_value,
+        pinned: false,
+        parent_id: None,
+        tool_calls: message.tool_calls.clone(),
+        tool_call_id: message.tool_call_id.clone(),
+        citations,
     };
 
-    // Call the add_message_handler function
-    message_handlers::add_message_handler(db_pool.clone(), web::Json(new_message)).await?;
-
-    Ok(())
+    let client = db_pool.get().await?;
+    let message = db::add_message(&client, new_message).await?;
+    // Embedded for semantic search by the indexer, off the request path.
+    message_index::wake();
+    Ok(message)
 }
 
+/// Tokens kept free for the reply when the chat does not set `max_tokens`.
+const DEFAULT_COMPLETION_RESERVE: usize = 1000;
+
+/// Loads the chat history and trims it to the model's context window using
+/// the chat's context strategy.
+///
+/// Once the history outgrows the window, older messages are replaced by a
+/// rolling summary that is sent as a system message ahead of the recent ones.
+/// Excerpts retrieved from the chat's documents go right before the newest
+/// message.
 async fn get_consolidated_messages(
+    context: &ChatContext,
     chat_id_value: i32,
+    messages: Vec<models::Message>,
+    settings: &models::ChatSettings,
+    model: &str,
+    documents: Option<ChatCompletionMessage>,
+    ) -> Result<Vec<ChatCompletionMessage>, MyError> {
+    let strategy = context::ContextStrategy::from_settings(settings)?;
+    let reserve = settings
+        .max_tokens
+        .map(|max_tokens| max_tokens as usize)
+        .unwrap_or(DEFAULT_COMPLETION_RESERVE);
+    let mut budget = tokenizer::context_window(model).saturating_sub(reserve);
+    if let Some(documents) = &documents {
+        budget = budget.saturating_sub(tokenizer::count_message_tokens(documents));
+    }
+
+    let client = context.db_pool.get().await?;
+    let summary = match summary::refresh_summary(context, &client, chat_id_value, model, &messages, budget).await {
+        Ok(summary) => summary,
+        Err(e) => {
+            eprintln!("Error refreshing chat summary: {}", e);
+            summary::current_summary(&client, chat_id_value, &messages).await?
+        }
+    };
+
+    // Summarized messages are left out, except the ones the strategy must keep.
+    let summarized_up_to = summary.as_ref().map_or(0, |summary| summary.last_message_id);
+    let messages = messages
+        .into_iter()
+        .filter(|msg| msg.id.unwrap_or_default() > summarized_up_to || msg.pinned || msg.role == "system")
+        .collect();
+
+    let summary_message = summary.map(|summary| summary.as_message());
+    if let Some(summary_message) = &summary_message {
+        budget = budget.saturating_sub(tokenizer::count_message_tokens(summary_message));
+    }
+
+    let mut consolidated = context::build_context(messages, strategy, budget);
+    if let Some(summary_message) = summary_message {
+        let position = consolidated
+            .iter()
+            .position(|msg| msg.role != "system")
+            .unwrap_or(consolidated.len());
+        consolidated.insert(position, summary_message);
+    }
+    if let Some(documents) = documents {
+        consolidated.insert(consolidated.len().saturating_sub(1), documents);
+    }
+
+    Ok(consolidated)
+}
+
+/// Resolves the model settings for a turn: request parameters override the
+/// chat's stored defaults. Parameters sent with a request become the chat's new
+/// defaults, so later turns reuse them.
+async fn resolve_chat_settings(
+    chat_id_value: i32,
+    overrides: &models::ChatSettings,
     db_pool: &web::Data<deadpool_postgres::Pool>,
-    ) -> Result<Vec<ChatCompletionMessage>, Box<dyn StdError>> {
-    let messages = message_handlers::get_messages_by_chat_id_handler(db_pool.clone(), chat_id_value).await?;
+    user: &auth::AuthenticatedUser,
+) -> Result<models::ChatSettings, errors::MyError> {
+    let client = db_pool.get().await?;
+    let stored = user.owned_chat(&client, chat_id_value).await?.settings();
+    let settings = stored.clone().merge(overrides);
+
+    if settings != stored {
+        db::update_chat_settings(&client, chat_id_value, &settings).await?;
+    }
 
-    Ok(messages
-       .into_iter()
-       .map(|msg| ChatCompletionMessage {
-           role: msg.role,
-           content: msg.content,
-       })
-       .collect())
+    Ok(settings)
 }
 
-#[post("/chat/{chat_id}")]
-async fn chat(
-    chat_id: web::Path<i32>,
-    chat_completion: web::Json<ChatPromptRequestBody>,
-    db_pool: web::Data<deadpool_postgres::Pool>,
-    ) -> impl Responder {
-    let chat_id_value = chat_id.into_inner();
+/// A request ready to send upstream, with the citations of the document
+/// excerpts it carries.
+struct PreparedChat {
+    request: ChatRequestBody,
+    citations: Option<serde_json::Value>,
+}
 
-    // Get the last message from the request
-    if let Some(message) = chat_completion.messages.last() {
-        if let Err(e) = add_and_save_message(message, chat_id_value, &db_pool).await {
-            eprintln!("Error while adding and saving the message: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
+/// Validates the settings, saves the user's new message, if any, and
+/// assembles the request to send upstream.
+async fn prepare_chat_request(
+    context: &ChatContext,
+    chat_id_value: i32,
+    overrides: &models::ChatSettings,
+    new_message: Option<&ChatCompletionMessage>,
+) -> Result<PreparedChat, MyError> {
+    overrides.validate()?;
+
+    let ChatContext { db_pool, config, .. } = context;
+    let settings = resolve_chat_settings(chat_id_value, overrides, db_pool, &context.user).await?;
+
+    if let Some(message) = new_message {
+        add_and_save_message(message, None, chat_id_value, db_pool).await?;
     }
 
-    let openai_messages = match get_consolidated_messages(chat_id_value, &db_pool).await {
-        Ok(messages) => messages,
-        Err(e) => {
-            eprintln!("Error getting messages: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
+    let messages = message_handlers::get_messages_by_chat_id_handler(db_pool.clone(), chat_id_value).await?;
+    let (documents, citations) = match retrieve_documents(context, chat_id_value, &messages).await {
+        Some((documents, citations)) => (Some(documents), Some(citations)),
+        None => (None, None),
     };
 
-    let chat_url = "https://api.openai.com/v1/chat/completions".to_string();
+    let model = settings.model.as_deref().unwrap_or(&config.default_model);
+    let openai_messages = get_consolidated_messages(context, chat_id_value, messages, &settings, model, documents).await?;
 
-    println!("In chat");
-    println!("Chat completion: {:?}", chat_completion.messages);
-
-    let request = OpenAIRequest::ChatCompletion {
-        model: "gpt-4".to_string(),
-        messages: &openai_messages,
-        temperature: Some(1.5),
-    };
+    Ok(PreparedChat {
+        request: ChatRequestBody::new(openai_messages, settings, &config.default_model),
+        citations,
+    })
+}
 
-    let config = config::Config::from_env().unwrap();
+/// Retrieves the excerpts of the chat's documents relevant to the newest user
+/// message. Retrieval failures are logged and the chat goes on without them.
+async fn retrieve_documents(
+    context: &ChatContext,
+    chat_id_value: i32,
+    messages: &[models::Message],
+) -> Option<(ChatCompletionMessage, serde_json::Value)> {
+    let query = messages.iter().rev().find(|message| message.role == "user")?;
+    let client = context.db_pool.get().await.ok()?;
 
-    let openai_response = match call_openai_api(request, config.api_key, chat_url).await {
-        Ok(response) => response,
+    match documents::retrieve(&client, context.embedder.get_ref(), &context.config, context.user.id, chat_id_value, &query.content).await {
+        Ok(documents) => documents,
         Err(e) => {
-            eprintln!("Error calling OpenAI API: {}", e);
-            return HttpResponse::InternalServerError().body("Error calling OpenAI API");
+            eprintln!("Error retrieving documents: {}", e);
+            None
         }
+    }
+}
+
+/// Sends `request` upstream with the registered tools, saves the assistant
+/// reply and its usage, and returns the provider's response body.
+///
+/// While the model calls tools, the calls and their results are saved and sent
+/// back to it, for at most `max_tool_rounds` rounds; the last round does not
+/// allow tool calls, so the model has to answer.
+async fn complete_chat(
+    context: &ChatContext,
+    chat_id_value: i32,
+    prepared: PreparedChat,
+) -> Result<String, MyError> {
+    let ChatContext {
+        db_pool,
+        provider,
+        tools,
+        user,
+        config,
+        ..
+    } = context;
+    let tool_context = tools::ToolContext {
+        app_user: user.id,
+        chat_id: chat_id_value,
+        db_pool: db_pool.get_ref().clone(),
     };
+    let PreparedChat {
+        mut request,
+        citations,
+    } = prepared;
+    request.tools = tools.definitions();
+
+    let mut round = 0;
+    loop {
+        if request.tools.is_some() && round >= config.max_tool_rounds {
+            request.tool_choice = Some(serde_json::json!("none"));
+        }
+
+        let pending = usage::PendingCompletion::start(user.id, Some(chat_id_value), &request);
+        let openai_response = provider.chat_completion(&request).await?;
+
+        let response_json: serde_json::Value = serde_json::from_str(&openai_response)
+            .map_err(|e| upstream_error(format!("invalid JSON in completion: {}", e)))?;
+
+        let message = &response_json["choices"][0]["message"];
+        let tool_calls = message["tool_calls"]
+            .as_array()
+            .filter(|tool_calls| !tool_calls.is_empty());
+        let content = match (message["content"].as_str(), tool_calls) {
+            (Some(content), _) => content,
+            (None, Some(_)) => "",
+            (None, None) => {
+                return Err(upstream_error("completion has no message content".to_string()))
+            }
+        };
+
+        let ai_message = ChatCompletionMessage {
+            role: "assistant".to_string(),
+            content: content.to_string(),
+            tool_calls: tool_calls.cloned().map(serde_json::Value::Array),
+            tool_call_id: None,
+        };
+
+        // Citations belong to the final answer, not to the tool calls.
+        let message_citations = match tool_calls {
+            None => citations.clone(),
+            Some(_) => None,
+        };
+        let message_id = save_message(&ai_message, message_citations, chat_id_value, db_pool).await;
+
+        let mut outcome = usage::CompletionOutcome::default();
+        outcome.update(&response_json);
+        let completion = pending.finish(message_id, outcome, content, &config.pricing);
+        if let Err(e) = record_completion(db_pool, completion).await {
+            eprintln!("Error recording completion usage: {}", e);
+        }
+
+        let tool_calls = match tool_calls {
+            Some(tool_calls) if request.tool_choice.is_none() => tool_calls.clone(),
+            _ => return Ok(openai_response),
+        };
+
+        request.messages.push(ai_message);
+        for result in tools.run(&tool_calls, &tool_context).await {
+            save_message(&result, None, chat_id_value, db_pool).await;
+            request.messages.push(result);
+        }
+        round += 1;
+    }
+}
 
-    let response_json: serde_json::Value = match serde_json::from_str(&openai_response) {
-        Ok(val) => val,
+/// Forwards a completion with the user's remaining budget in its headers.
+fn completion_response(body: String, remaining: &budget::Remaining) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    remaining.insert_headers(&mut response);
+    response.content_type("application/json").body(body)
+}
+
+/// Saves a message produced while answering and returns its id. Failures are
+/// logged rather than returned, so the reply still reaches the client.
+async fn save_message(
+    message: &ChatCompletionMessage,
+    citations: Option<serde_json::Value>,
+    chat_id_value: i32,
+    db_pool: &web::Data<deadpool_postgres::Pool>,
+) -> Option<i32> {
+    match add_and_save_message(message, citations, chat_id_value, db_pool).await {
+        Ok(message) => message.id,
         Err(e) => {
-            eprintln!("Error parsing JSON: {}", e);
-            return HttpResponse::InternalServerError().body("Error parsing JSON");
+            eprintln!("Error saving the {} message: {}", message.role, e);
+            None
         }
-    };
+    }
+}
 
-    let content = response_json["choices"][0]["message"]["content"]
-        .as_str()
-        .ok_or_else(|| {
-            eprintln!("Error getting response from OpenAI API");
-            HttpResponse::InternalServerError().body("Error getting response from OpenAI API")
-        });
+#[post("/chat/{chat_id}")]
+async fn chat(
+    chat_id: web::Path<i32>,
+    chat_completion: web::Json<ChatPromptRequestBody>,
+    context: ChatContext,
+    ) -> Result<HttpResponse, MyError> {
+    let chat_id_value = chat_id.into_inner();
 
-    let ai_message = models::Message {
-        id: None,
-        created_on: Utc::now(),
-        role: "assistant".to_string(),
-        content: content.unwrap().to_string(),
-        chat_id_relation: chat_id_value,
-    };
+    let remaining = check_budget(&context.db_pool, &context.user).await?;
+    let prepared = prepare_chat_request(
+        &context,
+        chat_id_value,
+        &chat_completion.settings,
+        chat_completion.messages.last(),
+    )
+    .await?;
+
+    let body = complete_chat(&context, chat_id_value, prepared).await?;
+    context.auto_title(chat_id_value).spawn();
+
+    Ok(completion_response(body, &remaining))
+}
+
+#[post("/chat/{chat_id}/stream")]
+async fn chat_stream(
+    chat_id: web::Path<i32>,
+    chat_completion: web::Json<ChatPromptRequestBody>,
+    context: ChatContext,
+    ) -> Result<HttpResponse, MyError> {
+    let chat_id_value = chat_id.into_inner();
+    let ChatContext {
+        db_pool,
+        provider,
+        tools,
+        user,
+        config,
+        ..
+    } = &context;
+
+    let remaining = check_budget(db_pool, user).await?;
+    let PreparedChat {
+        mut request,
+        citations,
+    } = prepare_chat_request(
+        &context,
+        chat_id_value,
+        &chat_completion.settings,
+        chat_completion.messages.last(),
+    )
+    .await?;
+
+    // Tool calls are not run for streamed replies. The tools are still
+    // declared, since the history may hold earlier calls, but may not be used.
+    request.tools = tools.definitions();
+    if request.tools.is_some() {
+        request.tool_choice = Some(serde_json::json!("none"));
+    }
 
-    let ai_message_result = message_handlers::add_message_handler(db_pool.clone(), web::Json(ai_message)).await;
-    println!("AI message result: {:?}", ai_message_result);
+    let pending = usage::PendingCompletion::start(user.id, Some(chat_id_value), &request);
+    let upstream = provider.chat_completion_stream(&request).await?;
+
+    let mut response = HttpResponse::Ok();
+    remaining.insert_headers(&mut response);
+    Ok(response
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(streaming::SseRelay::new(
+            upstream,
+            chat_id_value,
+            db_pool.get_ref().clone(),
+            pending,
+            config.pricing.clone(),
+            citations,
+            context.auto_title(chat_id_value),
+        )))
+}
+
+/// Fails with `budget_exceeded` once the user has used up their budget.
+async fn check_budget(
+    db_pool: &web::Data<deadpool_postgres::Pool>,
+    user: &auth::AuthenticatedUser,
+) -> Result<budget::Remaining, MyError> {
+    let client = db_pool.get().await?;
+    budget::check(&client, user.id).await
+}
+
+async fn record_completion(
+    db_pool: &web::Data<deadpool_postgres::Pool>,
+    completion: models::Completion,
+) -> Result<models::Completion, MyError> {
+    let client = db_pool.get().await?;
+    db::add_completion(&client, completion).await
+}
+
+/// Error for a provider response that cannot be used.
+fn upstream_error(message: String) -> MyError {
+    MyError::Upstream {
+        status: None,
+        code: None,
+        message,
+        timed_out: false,
+    }
+}
+
+/// Allows any origin when `origins` contains `"*"`, otherwise only the listed
+/// ones.
+fn cors(origins: &[String]) -> Cors {
+    if origins.iter().any(|origin| origin == "*") {
+        return Cors::permissive();
+    }
 
-    HttpResponse::Ok().body(openai_response)
+    origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allow_any_method()
+        .allow_any_header()
 }
 
 pub fn create_app(
     pool: deadpool_postgres::Pool,
     config: config::Config,
+    providers: providers::Providers,
     ) -> App<
 impl ServiceFactory<
 ServiceRequest,
@@ -265,27 +570,154 @@ Error = Error,
 InitError = (),
 >,
 > {
+    let cors = cors(&config.cors_origins);
+    let rate_limiter = rate_limit::RateLimiter::from_config(&config, pool.clone());
+    let tools = tools::ToolRegistry::from_config(&config);
+    let max_document_bytes = config.max_document_bytes;
+    let max_import_bytes = config.max_import_bytes;
+    let max_batch_bytes = config.max_batch_bytes;
+    let json_config = web::JsonConfig::default()
+        .limit(config.max_request_bytes)
+        .error_handler(|e, _| MyError::BadRequest(e.to_string()).into());
+    let path_config = web::PathConfig::default()
+        .error_handler(|e, _| MyError::BadRequest(e.to_string()).into());
+    let query_config = web::QueryConfig::default()
+        .error_handler(|e, _| MyError::BadRequest(e.to_string()).into());
+
     App::new()
         .app_data(web::Data::new(pool))
+        .app_data(json_config)
+        .app_data(path_config)
+        .app_data(query_config)
         .app_data(web::Data::new(config))
-        .wrap(Cors::permissive())
+        .app_data(web::Data::from(providers.chat))
+        .app_data(web::Data::from(providers.image))
+        .app_data(web::Data::from(providers.embedding))
+        .app_data(web::Data::new(tools))
+        .wrap(rate_limiter)
+        .wrap(auth::Authentication)
+        .wrap(request_id::RequestId)
+        .wrap(cors)
         .service(chat)
+        .service(chat_stream)
+        .route("/users", web::post().to(user_handlers::create_user_handler))
+        .route("/users/me", web::get().to(user_handlers::get_current_user_handler))
+        .route(
+            "/users/me/api_key",
+            web::post().to(user_handlers::rotate_api_key_handler),
+            )
+        .route("/users/{id}/tier", web::put().to(user_handlers::update_user_tier_handler))
+        .route("/auth/token", web::post().to(user_handlers::issue_token_handler))
         .route(
             "/create_chat/{app_user}",
             web::post().to(chat_handlers::create_chat_handler),
             )
         .route("/chats/{app_user}", web::get().to(chat_handlers::get_chats_handler))
+        .route(
+            "/chats/{chat_id}/settings",
+            web::get().to(chat_handlers::get_chat_settings_handler),
+            )
+        .route(
+            "/chats/{chat_id}/settings",
+            web::put().to(chat_handlers::update_chat_settings_handler),
+            )
+        .route(
+            "/chats/{chat_id}/export",
+            web::get().to(export_handlers::export_chat_handler),
+            )
+        .route(
+            "/chats/{chat_id}/retitle",
+            web::post().to(chat_handlers::retitle_chat_handler),
+            )
+        .route(
+            "/chats/{chat_id}/summary",
+            web::get().to(chat_handlers::get_chat_summary_handler),
+            )
+        .route(
+            "/chats/{chat_id}/usage",
+            web::get().to(usage_handlers::get_chat_usage_handler),
+            )
+        .route("/users/{id}/budget", web::get().to(budget_handlers::get_budget_handler))
+        .route("/users/{id}/budget", web::put().to(budget_handlers::update_budget_handler))
+        .route(
+            "/users/{id}/usage",
+            web::get().to(usage_handlers::get_user_usage_handler),
+            )
+        .service(
+            web::resource("/users/{id}/import")
+                .app_data(web::PayloadConfig::new(max_import_bytes))
+                .route(web::post().to(import_handlers::import_chats_handler)),
+            )
         .route(
             "/chats/{chat_id}/messages",
             web::get().to(message_handlers::get_messages_by_chat_id_endpoint),
             )
+        .route(
+            "/chats/{chat_id}/messages/{id}",
+            web::put().to(message_handlers::edit_message_handler),
+            )
+        .route(
+            "/chats/{chat_id}/branches",
+            web::get().to(message_handlers::get_branches_handler),
+            )
+        .route(
+            "/chats/{chat_id}/branch",
+            web::put().to(message_handlers::switch_branch_handler),
+            )
+        .route(
+            "/chats/{chat_id}/regenerate",
+            web::post().to(message_handlers::regenerate_handler),
+            )
+        .route(
+            "/chats/{chat_id}/messages/{id}/pin",
+            web::put().to(message_handlers::pin_message_handler),
+            )
+        .route(
+            "/chats/{chat_id}/messages/{id}/rating",
+            web::put().to(message_handlers::rate_message_handler),
+            )
+        .route("/chats/{chat_id}/tags", web::put().to(chat_handlers::update_chat_tags_handler))
+        .route(
+            "/fine_tuning/dataset",
+            web::post().to(export_handlers::fine_tuning_dataset_handler),
+            )
+        .route("/search", web::get().to(search_handlers::search_handler))
+        .route(
+            "/search/semantic",
+            web::get().to(search_handlers::semantic_search_handler),
+            )
         .route("/update_chat_name", web::put().to(chat_handlers::update_chat_name_handler))
         .route(
             "/delete_chat/{chat_id}",
             web::delete().to(chat_handlers::delete_chat_handler),
             )
+        .service(
+            web::resource("/chats/{chat_id}/documents")
+                .app_data(web::PayloadConfig::new(max_document_bytes))
+                .route(web::post().to(document_handlers::upload_document_handler))
+                .route(web::get().to(document_handlers::get_documents_handler)),
+            )
+        .route(
+            "/chats/{chat_id}/documents/{id}",
+            web::delete().to(document_handlers::delete_document_handler),
+            )
+        .route(
+            "/chats/{chat_id}/images",
+            web::get().to(image_handlers::get_images_by_chat_id),
+            )
         .route(
             "/images/generations",
             web::post().to(image_handlers::generate_image),
             )
+        .service(
+            web::resource("/batches")
+                .app_data(web::PayloadConfig::new(max_batch_bytes))
+                .route(web::post().to(batch_handlers::create_batch_handler))
+                .route(web::get().to(batch_handlers::get_batches_handler)),
+            )
+        .route("/batches/{id}", web::get().to(batch_handlers::get_batch_handler))
+        .route(
+            "/batches/{id}/results",
+            web::get().to(batch_handlers::get_batch_results_handler),
+            )
 }
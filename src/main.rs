@@ -1,28 +1,174 @@
 use actix_web::HttpServer;
 use dotenv::dotenv;
+use hjowdy::batches;
 use hjowdy::create_app;
+use hjowdy::message_index;
+use hjowdy::migrations;
+use hjowdy::providers;
+use hjowdy::rate_limit;
 use tokio_postgres::NoTls;
 extern crate chrono;
 extern crate serde;
 
 use hjowdy::config::Config;
 
-mod config;
-mod db;
-mod errors;
-mod models;
+const USAGE: &str = "Usage:
+    hjowdy                      Run pending migrations (unless AUTO_MIGRATE=false) and start the server
+    hjowdy migrate [up]         Apply pending migrations
+    hjowdy migrate down [N]     Revert the last N migrations (default 1)
+    hjowdy migrate status       List migrations and whether they are applied
+    hjowdy batch FILE --user ID [--output FILE]
+                                Run a JSONL file of chat requests for a user and write the results
+    hjowdy batch resume ID [--takeover] [--output FILE]
+                                Finish an interrupted batch and write its results;
+                                --takeover reruns the lines it left running at once";
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
 
-    let config = Config::from_env().unwrap();
-    let pool = config.pg.create_pool(None, NoTls).unwrap();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let pool = config.pg.create_pool(None, NoTls).map_err(to_io_error)?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = args.first().map(String::as_str);
+    match command {
+        None | Some("batch") => {}
+        Some("migrate") => return migrate(&pool, &args[1..]).await,
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+
+    if config.auto_migrate {
+        let mut client = pool.get().await.map_err(to_io_error)?;
+        migrations::migrate_up(&mut client).await.map_err(to_io_error)?;
+    }
+
+    let providers = providers::from_config(&config).map_err(to_io_error)?;
+    if command == Some("batch") {
+        return batch(&pool, providers.chat.as_ref(), &config, &args[1..]).await;
+    }
+
+    message_index::spawn(pool.clone(), providers.embedding.clone(), config.clone());
+    batches::resume(pool.clone(), providers.chat.clone(), config.clone());
+    rate_limit::spawn_pruning(pool.clone(), &config);
 
     std::env::set_var("RUST_LOG", "debug");
     env_logger::init();
-    HttpServer::new(move || create_app(pool.clone(), config.clone()))
-        .bind("127.0.0.1:8080")?
+
+    let server_addr = config.server_addr.clone();
+    let workers = config.workers;
+    HttpServer::new(move || create_app(pool.clone(), config.clone(), providers.clone()))
+        .workers(workers)
+        .bind(server_addr)?
         .run()
         .await
 }
+
+async fn migrate(pool: &deadpool_postgres::Pool, args: &[String]) -> std::io::Result<()> {
+    let mut client = pool.get().await.map_err(to_io_error)?;
+
+    match args.first().map(String::as_str) {
+        None | Some("up") => {
+            let applied = migrations::migrate_up(&mut client).await.map_err(to_io_error)?;
+            println!("{} migration(s) applied", applied.len());
+        }
+        Some("down") => {
+            let steps = match args.get(1) {
+                Some(steps) => steps.parse::<usize>().map_err(to_io_error)?,
+                None => 1,
+            };
+            let reverted = migrations::migrate_down(&mut client, steps)
+                .await
+                .map_err(to_io_error)?;
+            println!("{} migration(s) reverted", reverted.len());
+        }
+        Some("status") => {
+            for (migration, applied) in migrations::status(&client).await.map_err(to_io_error)? {
+                let state = if applied { "applied" } else { "pending" };
+                println!("{:>4} {:<32} {}", migration.version, migration.name, state);
+            }
+        }
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a batch in the foreground, then writes its results to `--output` or
+/// stdout. Interrupted, it can be finished with `batch resume`.
+async fn batch(
+    pool: &deadpool_postgres::Pool,
+    provider: &dyn providers::ChatProvider,
+    config: &Config,
+    args: &[String],
+) -> std::io::Result<()> {
+    let option = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|index| args.get(index + 1))
+    };
+    let client = pool.get().await.map_err(to_io_error)?;
+    let mut takeover = false;
+    let batch_id = match args.first().map(String::as_str) {
+        Some("resume") => match args.get(1).map(|id| id.parse::<i32>()) {
+            Some(Ok(batch_id)) => {
+                takeover = args.iter().any(|arg| arg == "--takeover");
+                batch_id
+            }
+            _ => usage(),
+        },
+        Some(file) if !file.starts_with("--") => {
+            let app_user = match option("--user").map(|id| id.parse::<i32>()) {
+                Some(Ok(app_user)) => app_user,
+                _ => usage(),
+            };
+            hjowdy::db::get_user(&client, app_user).await.map_err(to_io_error)?;
+            let items = batches::parse(&std::fs::read_to_string(file)?);
+            let batch_id = hjowdy::db::create_batch(&client, app_user, &items)
+                .await
+                .map_err(to_io_error)?;
+            eprintln!("Batch {} created with {} line(s)", batch_id, items.len());
+            batch_id
+        }
+        _ => usage(),
+    };
+
+    batches::run(pool, provider, config, batch_id, takeover)
+        .await
+        .map_err(to_io_error)?;
+
+    let batch = hjowdy::db::get_batch(&client, batch_id).await.map_err(to_io_error)?;
+    eprintln!(
+        "Batch {}: {} succeeded, {} failed, {} pending",
+        batch.id, batch.succeeded, batch.failed, batch.pending
+    );
+    let items = hjowdy::db::get_batch_items(&client, batch_id)
+        .await
+        .map_err(to_io_error)?;
+    let results = batches::results(&items);
+    match option("--output") {
+        Some(path) => std::fs::write(path, results),
+        None => std::io::Write::write_all(&mut std::io::stdout(), results.as_bytes()),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn to_io_error<E: std::fmt::Display>(e: E) -> std::io::Error {
+    std::io::Error::other(e.to_string())
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use deadpool_postgres::{Client, Pool};
+use tokio::sync::Notify;
+
+use crate::budget;
+use crate::config::Config;
+use crate::db;
+use crate::documents::{cosine_similarity, embed};
+use crate::errors::MyError;
+use crate::models::{MessageEmbedding, SimilarMessage, UnembeddedMessage};
+use crate::providers::EmbeddingProvider;
+use crate::tokenizer::truncate_tokens;
+
+/// Messages embedded per pass.
+const BATCH: i64 = 100;
+
+/// How often the indexer looks for messages without being woken, which picks
+/// up messages saved by other instances, older history and earlier failures.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Failed attempts after which a message is no longer embedded. The wait
+/// before the next attempt doubles from `POLL_INTERVAL` after each one.
+const MAX_ATTEMPTS: i32 = 8;
+
+/// Tokens of a message that are embedded; the rest of a longer one is left
+/// out so it stays within the embedding model's input limit.
+const MAX_MESSAGE_TOKENS: usize = 8000;
+
+fn notify() -> &'static Notify {
+    static NOTIFY: OnceLock<Notify> = OnceLock::new();
+    NOTIFY.get_or_init(Notify::new)
+}
+
+/// Tells the indexer that a message was saved. Cheap, and harmless when the
+/// indexer is not running.
+pub fn wake() {
+    notify().notify_one();
+}
+
+/// Starts the background task that embeds user and assistant messages for
+/// semantic search, unless `semantic_search` is off. Must be called from
+/// within the actix runtime, once per process.
+pub fn spawn(pool: Pool, provider: Arc<dyn EmbeddingProvider>, config: Config) {
+    if !config.semantic_search {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        loop {
+            match index_pending(&pool, provider.as_ref(), &config).await {
+                // A full batch: more messages may be waiting.
+                Ok(indexed) if indexed as i64 == BATCH => continue,
+                Ok(_) => {
+                    let _ = tokio::time::timeout(POLL_INTERVAL, notify().notified()).await;
+                }
+                Err(e) => {
+                    eprintln!("Error embedding messages: {}", e);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    });
+}
+
+/// Embeds a batch of messages that have no embedding yet and returns how many
+/// there were. A chat whose messages cannot be embedded, or whose user is out
+/// of budget, is put off until later without holding up the others.
+async fn index_pending(
+    pool: &Pool,
+    provider: &dyn EmbeddingProvider,
+    config: &Config,
+) -> Result<usize, MyError> {
+    let client = pool.get().await?;
+    let messages = db::get_unembedded_messages(&client, BATCH, MAX_ATTEMPTS).await?;
+    let count = messages.len();
+
+    // One request per chat, so the usage is recorded against it.
+    let mut by_chat: BTreeMap<(i32, i32), Vec<UnembeddedMessage>> = BTreeMap::new();
+    for message in messages {
+        by_chat
+            .entry((message.app_user, message.chat_id))
+            .or_default()
+            .push(message);
+    }
+
+    for ((app_user, chat_id), messages) in by_chat {
+        match budget::check(&client, app_user).await {
+            Ok(_) => {}
+            // Waiting for the budget to reset is not a failed attempt.
+            Err(e @ MyError::BudgetExceeded { retry_after, .. }) => {
+                let wait = retry_after.map_or(POLL_INTERVAL, Duration::from_secs);
+                for message in &messages {
+                    put_off(&client, message, message.attempts, &e, wait).await?;
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        let inputs = messages
+            .iter()
+            .map(|message| truncate_tokens(&message.content, MAX_MESSAGE_TOKENS))
+            .collect();
+        match embed(&client, provider, config, app_user, Some(chat_id), inputs).await {
+            Ok(embeddings) => {
+                for (message, embedding) in messages.iter().zip(embeddings) {
+                    db::add_message_embedding(&client, message.id, &embedding).await?;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error embedding messages of chat {}: {}", chat_id, e);
+                for message in &messages {
+                    let wait = POLL_INTERVAL * 2u32.saturating_pow(message.attempts as u32);
+                    put_off(&client, message, message.attempts + 1, &e, wait).await?;
+                }
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+/// Leaves `message` out of the passes of the next `wait`.
+async fn put_off(
+    client: &Client,
+    message: &UnembeddedMessage,
+    attempts: i32,
+    error: &MyError,
+    wait: Duration,
+) -> Result<(), MyError> {
+    let retry_after = Utc::now() + chrono::Duration::seconds(wait.as_secs() as i64);
+    db::add_message_embedding_failure(
+        client,
+        message.id,
+        attempts,
+        &error.to_string(),
+        retry_after,
+    )
+    .await
+}
+
+/// The `limit` messages of `app_user`, across all chats, most similar in
+/// meaning to `query`, best first. Messages not embedded yet are not found.
+pub async fn search(
+    client: &Client,
+    provider: &dyn EmbeddingProvider,
+    config: &Config,
+    app_user: i32,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<SimilarMessage>, MyError> {
+    let embedding = embed(client, provider, config, app_user, None, vec![query.to_string()])
+        .await?
+        .pop()
+        .unwrap_or_default();
+
+    match config.vector_search.as_str() {
+        "pgvector" => {
+            db::search_message_embeddings(client, app_user, &embedding, limit as i64).await
+        }
+        _ => Ok(nearest(
+            db::get_message_embeddings_by_user(client, app_user).await?,
+            &embedding,
+            limit,
+        )),
+    }
+}
+
+/// The `k` messages most similar to `embedding`, best first.
+fn nearest(messages: Vec<MessageEmbedding>, embedding: &[f32], k: usize) -> Vec<SimilarMessage> {
+    let mut matches: Vec<SimilarMessage> = messages
+        .into_iter()
+        .map(|message| SimilarMessage {
+            score: cosine_similarity(&message.embedding, embedding),
+            message_id: message.message_id,
+            chat_id: message.chat_id,
+            chat_name: message.chat_name,
+            role: message.role,
+            content: message.content,
+            created_on: message.created_on,
+        })
+        .collect();
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+    matches.truncate(k);
+    matches
+}
@@ -0,0 +1,168 @@
+use deadpool_postgres::Client;
+
+use crate::errors::MyError;
+
+/// A numbered schema change, embedded in the binary from `migrations/`.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $name, ".up.sql")),
+            down: include_str!(concat!("../migrations/", $name, ".down.sql")),
+        }
+    };
+}
+
+/// Every migration, in the order they are applied. New migrations go at the
+/// end with the next version number.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_initial"),
+    migration!(2, "0002_chat_settings"),
+    migration!(3, "0003_chat_summaries"),
+    migration!(4, "0004_users"),
+    migration!(5, "0005_completions"),
+    migration!(6, "0006_budgets"),
+    migration!(7, "0007_rate_limits"),
+    migration!(8, "0008_message_tree"),
+    migration!(9, "0009_tool_calls"),
+    migration!(10, "0010_notes"),
+    migration!(11, "0011_documents"),
+    migration!(12, "0012_search"),
+    migration!(13, "0013_message_embeddings"),
+    migration!(14, "0014_auto_title"),
+    migration!(15, "0015_chat_imports"),
+    migration!(16, "0016_fine_tuning"),
+    migration!(17, "0017_batches"),
+    migration!(18, "0018_embedding_failures"),
+    migration!(19, "0019_batch_claims"),
+    migration!(20, "0020_rate_limit_cleanup"),
+    migration!(21, "0021_user_key_version"),
+];
+
+/// Key of the session-level advisory lock that keeps concurrently starting
+/// instances from migrating the same database at once.
+const LOCK_KEY: i64 = 0x686a_6f77_6479;
+
+const CREATE_SCHEMA_MIGRATIONS: &str = "CREATE TABLE IF NOT EXISTS public.schema_migrations
+(
+    version bigint PRIMARY KEY,
+    name character varying(255) NOT NULL,
+    applied_on timestamp with time zone NOT NULL DEFAULT now()
+)";
+
+/// Applies every pending migration and returns the versions applied.
+pub async fn migrate_up(client: &mut Client) -> Result<Vec<i64>, MyError> {
+    lock(client).await?;
+    let result = apply_pending(client).await;
+    unlock(client).await?;
+    result
+}
+
+/// Reverts the `steps` most recently applied migrations and returns the
+/// versions reverted.
+pub async fn migrate_down(client: &mut Client, steps: usize) -> Result<Vec<i64>, MyError> {
+    lock(client).await?;
+    let result = revert(client, steps).await;
+    unlock(client).await?;
+    result
+}
+
+/// Lists every known migration with whether it has been applied.
+pub async fn status(client: &Client) -> Result<Vec<(&'static Migration, bool)>, MyError> {
+    let applied = applied_versions(client).await?;
+
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| (migration, applied.contains(&migration.version)))
+        .collect())
+}
+
+async fn lock(client: &Client) -> Result<(), MyError> {
+    client
+        .execute("SELECT pg_advisory_lock($1)", &[&LOCK_KEY])
+        .await?;
+    Ok(())
+}
+
+async fn unlock(client: &Client) -> Result<(), MyError> {
+    client
+        .execute("SELECT pg_advisory_unlock($1)", &[&LOCK_KEY])
+        .await?;
+    Ok(())
+}
+
+/// The applied versions in order, creating `schema_migrations` if needed.
+async fn applied_versions(client: &Client) -> Result<Vec<i64>, MyError> {
+    client.batch_execute(CREATE_SCHEMA_MIGRATIONS).await?;
+    Ok(client
+        .query("SELECT version FROM schema_migrations ORDER BY version", &[])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect())
+}
+
+async fn apply_pending(client: &mut Client) -> Result<Vec<i64>, MyError> {
+    let applied = applied_versions(client).await?;
+    let mut versions = Vec::new();
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+    {
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(migration.up).await?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                &[&migration.version, &migration.name],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        println!("Applied migration {}", migration.name);
+        versions.push(migration.version);
+    }
+
+    Ok(versions)
+}
+
+async fn revert(client: &mut Client, steps: usize) -> Result<Vec<i64>, MyError> {
+    let applied = applied_versions(client).await?;
+    let mut versions = Vec::new();
+
+    for version in applied.into_iter().rev().take(steps) {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.version == version)
+            .ok_or_else(|| {
+                MyError::Internal(format!(
+                    "applied migration {} is unknown to this build and cannot be reverted",
+                    version
+                ))
+            })?;
+
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(migration.down).await?;
+        transaction
+            .execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&migration.version],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        println!("Reverted migration {}", migration.name);
+        versions.push(migration.version);
+    }
+
+    Ok(versions)
+}
@@ -1,8 +1,24 @@
 use chrono::DateTime;
 use chrono::Utc;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use tokio_pg_mapper_derive::PostgresMapper;
 
+use crate::context::ContextStrategy;
+use crate::errors::MyError;
+
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "users")]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub is_admin: bool,
+    pub tier: String,
+    pub created_on: DateTime<Utc>,
+    /// Bumped when the API key is replaced, revoking earlier bearer tokens.
+    #[serde(skip)]
+    pub key_version: i32,
+}
+
 #[derive(Serialize, Deserialize, PostgresMapper)]
 #[pg_mapper(table = "chats")]
 pub struct Chat {
@@ -10,6 +26,178 @@ pub struct Chat {
     pub app_user: i32,
     pub created_on: DateTime<Utc>,
     pub chat_name: String,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<i32>,
+    pub stop: Option<Vec<String>>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub seed: Option<i64>,
+    pub response_format: Option<serde_json::Value>,
+    pub context_strategy: Option<String>,
+    pub context_last_n: Option<i32>,
+    pub auto_title: Option<bool>,
+    /// Leaf of the branch being shown.
+    pub active_message_id: Option<i32>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Chat {
+    pub fn settings(&self) -> ChatSettings {
+        ChatSettings {
+            model: self.model.clone(),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            stop: self.stop.clone(),
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            seed: self.seed,
+            response_format: self.response_format.clone(),
+            context_strategy: self.context_strategy.clone(),
+            context_last_n: self.context_last_n,
+            auto_title: self.auto_title,
+        }
+    }
+}
+
+/// Model parameters for a chat. Every field is optional: unset fields fall back
+/// to the chat's stored defaults, and then to the provider's defaults.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i32>,
+    #[serde(
+        default,
+        deserialize_with = "string_or_vec",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stop: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+    /// How the history is trimmed to fit the model's context window, see
+    /// [`ContextStrategy`](crate::context::ContextStrategy).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_strategy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_last_n: Option<i32>,
+    /// Whether the chat is named by the model after its first reply; unset
+    /// follows the `auto_title` setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_title: Option<bool>,
+}
+
+impl ChatSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == ChatSettings::default()
+    }
+
+    /// Returns `self` with every field that is set in `overrides` replaced.
+    pub fn merge(self, overrides: &ChatSettings) -> ChatSettings {
+        ChatSettings {
+            model: overrides.model.clone().or(self.model),
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            stop: overrides.stop.clone().or(self.stop),
+            presence_penalty: overrides.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+            seed: overrides.seed.or(self.seed),
+            response_format: overrides.response_format.clone().or(self.response_format),
+            context_strategy: overrides.context_strategy.clone().or(self.context_strategy),
+            context_last_n: overrides.context_last_n.or(self.context_last_n),
+            auto_title: overrides.auto_title.or(self.auto_title),
+        }
+    }
+
+    /// Checks every set field against the ranges accepted by the OpenAI API.
+    pub fn validate(&self) -> Result<(), MyError> {
+        fn in_range(name: &str, value: Option<f32>, min: f32, max: f32) -> Result<(), MyError> {
+            match value {
+                Some(value) if !(min..=max).contains(&value) => Err(MyError::BadRequest(
+                    format!("{} must be between {} and {}", name, min, max),
+                )),
+                _ => Ok(()),
+            }
+        }
+
+        if let Some(model) = &self.model {
+            if model.trim().is_empty() {
+                return Err(MyError::BadRequest("model must not be empty".to_string()));
+            }
+        }
+        in_range("temperature", self.temperature, 0.0, 2.0)?;
+        in_range("top_p", self.top_p, 0.0, 1.0)?;
+        in_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        in_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens < 1 {
+                return Err(MyError::BadRequest(
+                    "max_tokens must be at least 1".to_string(),
+                ));
+            }
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > 4 {
+                return Err(MyError::BadRequest(
+                    "stop accepts at most 4 sequences".to_string(),
+                ));
+            }
+        }
+        if let Some(response_format) = &self.response_format {
+            match response_format["type"].as_str() {
+                Some("text") | Some("json_object") => {}
+                _ => {
+                    return Err(MyError::BadRequest(
+                        "response_format.type must be \"text\" or \"json_object\"".to_string(),
+                    ))
+                }
+            }
+        }
+        if let Some(context_last_n) = self.context_last_n {
+            if context_last_n < 1 {
+                return Err(MyError::BadRequest(
+                    "context_last_n must be at least 1".to_string(),
+                ));
+            }
+        }
+        ContextStrategy::from_settings(self)?;
+        Ok(())
+    }
+}
+
+/// Accepts `stop` either as a single string or as an array of strings.
+fn string_or_vec<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrVec {
+        String(String),
+        Vec(Vec<String>),
+    }
+
+    Ok(
+        Option::<StringOrVec>::deserialize(deserializer)?.map(|stop| match stop {
+            StringOrVec::String(stop) => vec![stop],
+            StringOrVec::Vec(stop) => stop,
+        }),
+    )
 }
 
 #[derive(Serialize, Deserialize, PostgresMapper)]
@@ -20,6 +208,127 @@ pub struct Message {
     pub role: String,
     pub content: String,
     pub chat_id_relation: i32,
+    #[serde(default)]
+    pub pinned: bool,
+    /// The message this one answers or follows; `None` for the first message.
+    #[serde(default)]
+    pub parent_id: Option<i32>,
+    /// The tools an assistant message calls, in OpenAI's `tool_calls` format.
+    #[serde(default)]
+    pub tool_calls: Option<serde_json::Value>,
+    /// The call a `tool` message answers.
+    #[serde(default)]
+    pub tool_call_id: Option<String>,
+    /// The document chunks retrieved for an assistant reply.
+    #[serde(default)]
+    pub citations: Option<serde_json::Value>,
+}
+
+/// The leaf of one branch of a chat's message tree.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "messages")]
+pub struct Branch {
+    pub leaf_id: i32,
+    pub role: String,
+    pub content: String,
+    pub created_on: DateTime<Utc>,
+    /// Messages from the root to the leaf.
+    pub length: i32,
+    /// Whether this is the chat's active branch.
+    pub active: bool,
+}
+
+/// Token usage, cost and latency of one model call.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "completions")]
+pub struct Completion {
+    pub id: Option<i32>,
+    pub app_user: i32,
+    pub chat_id: Option<i32>,
+    /// The assistant message the call produced.
+    pub message_id: Option<i32>,
+    pub model: String,
+    pub prompt_tokens: i32,
+    pub completion_tokens: i32,
+    pub finish_reason: Option<String>,
+    pub latency_ms: i32,
+    /// In dollars; `None` when the model has no configured price.
+    pub cost: Option<f64>,
+    pub created_on: DateTime<Utc>,
+}
+
+/// Completions aggregated for one model.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "completions")]
+pub struct ModelUsage {
+    pub model: String,
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub cost: Option<f64>,
+}
+
+/// Spending limits of a user. `None` is unlimited; days and months are UTC.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "budgets")]
+pub struct Budget {
+    pub app_user: i32,
+    pub daily_tokens: Option<i64>,
+    pub monthly_tokens: Option<i64>,
+    /// In dollars.
+    pub daily_cost: Option<f64>,
+    /// In dollars.
+    pub monthly_cost: Option<f64>,
+    pub updated_on: DateTime<Utc>,
+}
+
+/// New limits for a user, as sent by an admin. Limits left out are unlimited.
+#[derive(Debug, Deserialize)]
+pub struct BudgetLimits {
+    pub daily_tokens: Option<i64>,
+    pub monthly_tokens: Option<i64>,
+    pub daily_cost: Option<f64>,
+    pub monthly_cost: Option<f64>,
+}
+
+impl BudgetLimits {
+    pub fn validate(&self) -> Result<(), MyError> {
+        let negative_tokens = [self.daily_tokens, self.monthly_tokens]
+            .iter()
+            .flatten()
+            .any(|limit| *limit < 0);
+        let negative_cost = [self.daily_cost, self.monthly_cost]
+            .iter()
+            .flatten()
+            .any(|limit| *limit < 0.0);
+        if negative_tokens || negative_cost {
+            return Err(MyError::BadRequest(
+                "budget limits must not be negative".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What a user has spent in the current day and month.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "completions")]
+pub struct Spending {
+    pub daily_tokens: i64,
+    pub monthly_tokens: i64,
+    pub daily_cost: f64,
+    pub monthly_cost: f64,
+}
+
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "chat_summaries")]
+pub struct ChatSummary {
+    pub chat_id: i32,
+    pub content: String,
+    /// Id of the newest message folded into the summary.
+    pub last_message_id: i32,
+    pub created_on: DateTime<Utc>,
+    pub updated_on: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize, PostgresMapper)]
@@ -30,3 +339,172 @@ pub struct Image {
     pub url: String,
     pub created_on: DateTime<Utc>,
 }
+
+/// A note the assistant keeps for a user.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "notes")]
+pub struct Note {
+    pub app_user: i32,
+    pub key: String,
+    pub value: String,
+    pub updated_on: DateTime<Utc>,
+}
+
+/// A message of one of the user's chats that matches a search.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "messages")]
+pub struct MessageMatch {
+    pub message_id: i32,
+    pub chat_id: i32,
+    pub chat_name: String,
+    pub role: String,
+    pub content: String,
+    pub created_on: DateTime<Utc>,
+}
+
+/// A document attached to a chat.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "documents")]
+pub struct Document {
+    pub id: i32,
+    pub chat_id: i32,
+    pub name: String,
+    pub content_type: String,
+    /// Number of chunks the document was split into.
+    pub chunks: i64,
+    pub created_on: DateTime<Utc>,
+}
+
+/// A chunk of a document with its embedding.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "document_chunks")]
+pub struct DocumentChunk {
+    pub id: i32,
+    pub document_id: i32,
+    pub document_name: String,
+    pub chunk_index: i32,
+    pub content: String,
+    pub embedding: Vec<f32>,
+}
+
+/// A chunk of a document relevant to a message.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "document_chunks")]
+pub struct ChunkMatch {
+    pub id: i32,
+    pub document_id: i32,
+    pub document_name: String,
+    pub chunk_index: i32,
+    pub content: String,
+    /// Cosine similarity to the message, from -1 to 1.
+    pub score: f64,
+}
+
+/// A chat or message matching a full-text search.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "messages")]
+pub struct SearchHit {
+    /// `"chat"` for a match in the chat's name, `"message"` for one in a
+    /// message.
+    pub kind: String,
+    pub chat_id: i32,
+    pub chat_name: String,
+    pub message_id: Option<i32>,
+    pub role: Option<String>,
+    /// The matching text, HTML-escaped, with matches in `<mark>` tags.
+    pub snippet: String,
+    pub rank: f32,
+    pub created_on: DateTime<Utc>,
+    /// Hits over every page.
+    #[serde(skip)]
+    pub total: i64,
+}
+
+/// A message waiting to be embedded for semantic search.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "messages")]
+pub struct UnembeddedMessage {
+    pub id: i32,
+    pub chat_id: i32,
+    pub app_user: i32,
+    pub content: String,
+    /// Failed attempts to embed it so far.
+    pub attempts: i32,
+}
+
+/// A message with its embedding.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "message_embeddings")]
+pub struct MessageEmbedding {
+    pub message_id: i32,
+    pub chat_id: i32,
+    pub chat_name: String,
+    pub role: String,
+    pub content: String,
+    pub created_on: DateTime<Utc>,
+    pub embedding: Vec<f32>,
+}
+
+/// A message similar to a semantic search query.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "message_embeddings")]
+pub struct SimilarMessage {
+    pub message_id: i32,
+    pub chat_id: i32,
+    pub chat_name: String,
+    pub role: String,
+    pub content: String,
+    pub created_on: DateTime<Utc>,
+    /// Cosine similarity to the query, from -1 to 1.
+    pub score: f64,
+}
+
+/// A batch of chat requests, with its items counted by status.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "batches")]
+pub struct Batch {
+    pub id: i32,
+    pub app_user: i32,
+    /// `running` until every item succeeded or failed, then `completed`.
+    pub status: String,
+    pub created_on: DateTime<Utc>,
+    pub finished_on: Option<DateTime<Utc>>,
+    pub total: i64,
+    /// Items waiting or running.
+    pub pending: i64,
+    pub succeeded: i64,
+    pub failed: i64,
+}
+
+/// One line of a batch file and its outcome.
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "batch_items")]
+pub struct BatchItem {
+    pub batch_id: i32,
+    /// Line number in the file, from 1.
+    pub line: i32,
+    pub custom_id: Option<String>,
+    /// The chat request; `None` when the line could not be read.
+    pub request: Option<serde_json::Value>,
+    /// `pending`, `running`, `succeeded` or `failed`.
+    pub status: String,
+    pub attempts: i32,
+    /// The provider's completion response.
+    pub response: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub prompt_tokens: Option<i32>,
+    pub completion_tokens: Option<i32>,
+    pub cost: Option<f64>,
+    pub started_on: Option<DateTime<Utc>>,
+    pub finished_on: Option<DateTime<Utc>>,
+    /// The run working on the item while it is running.
+    #[serde(skip)]
+    pub claim_token: Option<String>,
+}
+
+/// A line of a batch file to add: the request, or why it could not be read.
+pub struct NewBatchItem {
+    pub line: i32,
+    pub custom_id: Option<String>,
+    pub request: Result<serde_json::Value, String>,
+}
@@ -0,0 +1,189 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::Stream;
+use derive_more::{Display, From};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::models::ChatSettings;
+use crate::ChatCompletionMessage;
+
+mod openai;
+mod retry;
+pub use openai::{CompatibleProvider, OPENAI_BASE_URL};
+pub use retry::{CircuitBreaker, RetryPolicy, Upstream};
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ChatRequestBody {
+    pub model: String,
+    pub messages: Vec<ChatCompletionMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+    /// Definitions of the tools the model may call.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+}
+
+impl ChatRequestBody {
+    /// Builds a request from resolved chat settings, using `default_model` when
+    /// the chat does not name one.
+    pub fn new(
+        messages: Vec<ChatCompletionMessage>,
+        settings: ChatSettings,
+        default_model: &str,
+    ) -> Self {
+        Self {
+            model: settings.model.unwrap_or_else(|| default_model.to_string()),
+            messages,
+            temperature: settings.temperature,
+            top_p: settings.top_p,
+            max_tokens: settings.max_tokens,
+            stop: settings.stop,
+            presence_penalty: settings.presence_penalty,
+            frequency_penalty: settings.frequency_penalty,
+            seed: settings.seed,
+            response_format: settings.response_format,
+            tools: None,
+            tool_choice: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ImageRequestBody {
+    pub model: String,
+    pub prompt: String,
+    pub n: u32,
+    pub size: String,
+    pub response_format: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EmbeddingRequestBody {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+#[derive(Display, From, Debug)]
+pub enum ProviderError {
+    Http(reqwest::Error),
+    Header(reqwest::header::InvalidHeaderValue),
+    HeaderName(reqwest::header::InvalidHeaderName),
+    Json(serde_json::Error),
+    #[display(fmt = "upstream returned {}: {}", status, body)]
+    #[from(ignore)]
+    Status { status: u16, body: String },
+    /// The circuit breaker is open and the call was not attempted.
+    #[display(fmt = "upstream unavailable, retry in {}s", "retry_after.as_secs().max(1)")]
+    #[from(ignore)]
+    CircuitOpen { retry_after: Duration },
+    /// A streamed response did not start, or sent no data, for `after`.
+    #[display(fmt = "upstream sent nothing for {}s", "after.as_secs()")]
+    #[from(ignore)]
+    Stalled { after: Duration },
+}
+impl std::error::Error for ProviderError {}
+
+/// Raw body of a streamed upstream response, in server-sent events format.
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, ProviderError>> + Send>>;
+
+/// A backend able to answer chat completion requests.
+///
+/// Implementations return the raw response body so handlers can forward it to
+/// the client unchanged.
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn chat_completion(&self, request: &ChatRequestBody) -> Result<String, ProviderError>;
+
+    /// Sends `request` with `stream: true` and returns the upstream event stream.
+    async fn chat_completion_stream(
+        &self,
+        request: &ChatRequestBody,
+    ) -> Result<ByteStream, ProviderError>;
+}
+
+/// A backend able to generate images from a prompt.
+#[async_trait]
+pub trait ImageProvider: Send + Sync {
+    async fn generate_image(&self, request: &ImageRequestBody) -> Result<String, ProviderError>;
+}
+
+/// A backend able to embed text as vectors.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    async fn embed(&self, request: &EmbeddingRequestBody) -> Result<String, ProviderError>;
+}
+
+#[derive(Clone)]
+pub struct Providers {
+    pub chat: Arc<dyn ChatProvider>,
+    pub image: Arc<dyn ImageProvider>,
+    pub embedding: Arc<dyn EmbeddingProvider>,
+}
+
+/// Builds the providers selected by `config.provider`.
+///
+/// `"openai"` (the default) talks to api.openai.com, `"compatible"` talks to
+/// any OpenAI-compatible server at `config.provider_base_url`. Both share one
+/// HTTP client, retry policy and circuit breaker. Fails when the HTTP client
+/// cannot be built, e.g. without TLS support.
+pub fn from_config(config: &Config) -> Result<Providers, reqwest::Error> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .build()?;
+    let upstream = Upstream::new(
+        client,
+        Duration::from_secs(config.request_timeout_secs),
+        RetryPolicy {
+            max_retries: config.max_retries,
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_secs(config.retry_max_delay_secs),
+        },
+        CircuitBreaker::new(
+            config.circuit_breaker_threshold,
+            Duration::from_secs(config.circuit_breaker_cooldown_secs),
+        ),
+    );
+
+    let provider = Arc::new(match config.provider.as_str() {
+        "compatible" => CompatibleProvider::new(
+            upstream,
+            config.provider_base_url.clone().unwrap_or_default(),
+            Some(config.api_key.clone()).filter(|key| !key.is_empty()),
+            config.provider_auth_header.clone(),
+        ),
+        _ => CompatibleProvider::openai(
+            upstream,
+            config
+                .provider_base_url
+                .clone()
+                .unwrap_or_else(|| OPENAI_BASE_URL.to_string()),
+            config.api_key.clone(),
+        ),
+    });
+    Ok(Providers {
+        chat: provider.clone(),
+        image: provider.clone(),
+        embedding: provider,
+    })
+}
@@ -0,0 +1,190 @@
+use async_trait::async_trait;
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use serde::Serialize;
+
+use super::retry::Upstream;
+use super::{
+    ByteStream, ChatProvider, ChatRequestBody, EmbeddingProvider, EmbeddingRequestBody,
+    ImageProvider, ImageRequestBody, ProviderError,
+};
+
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+#[derive(Serialize)]
+struct StreamingRequest<'a> {
+    #[serde(flatten)]
+    request: &'a ChatRequestBody,
+    stream: bool,
+    /// Asks for a final chunk carrying the token usage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<serde_json::Value>,
+}
+
+/// Provider for servers exposing the OpenAI API shape: self-hosted or proxy
+/// servers under a custom base URL, e.g. `http://localhost:8000/v1`, or the
+/// hosted OpenAI API itself, see [`CompatibleProvider::openai`].
+///
+/// The API key is optional. When `auth_header` is set the key is sent verbatim
+/// in that header instead of as a bearer token.
+pub struct CompatibleProvider {
+    upstream: Upstream,
+    base_url: String,
+    api_key: Option<String>,
+    auth_header: Option<String>,
+    /// Whether to ask for a final stream chunk carrying the token usage.
+    /// Only OpenAI is asked, as compatible servers may reject it.
+    stream_usage: bool,
+}
+
+impl CompatibleProvider {
+    pub fn new(
+        upstream: Upstream,
+        base_url: String,
+        api_key: Option<String>,
+        auth_header: Option<String>,
+    ) -> Self {
+        Self {
+            upstream,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            auth_header,
+            stream_usage: false,
+        }
+    }
+
+    /// Provider for the hosted OpenAI API at `base_url`, normally
+    /// [`OPENAI_BASE_URL`] (another one for e.g. a mock server), sending
+    /// `api_key` as a bearer token.
+    pub fn openai(upstream: Upstream, base_url: String, api_key: String) -> Self {
+        Self {
+            stream_usage: true,
+            ..Self::new(upstream, base_url, Some(api_key), None)
+        }
+    }
+
+    fn auth(&self) -> Result<Option<(HeaderName, HeaderValue)>, ProviderError> {
+        let api_key = match &self.api_key {
+            Some(api_key) => api_key,
+            None => return Ok(None),
+        };
+        match &self.auth_header {
+            Some(header) => {
+                let name = HeaderName::from_bytes(header.as_bytes())?;
+                Ok(Some((name, HeaderValue::from_str(api_key)?)))
+            }
+            None => Ok(Some(bearer(api_key)?)),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatProvider for CompatibleProvider {
+    async fn chat_completion(&self, request: &ChatRequestBody) -> Result<String, ProviderError> {
+        let auth = self.auth()?;
+        post_json(
+            &self.upstream,
+            &format!("{}/chat/completions", self.base_url),
+            auth,
+            request,
+        )
+        .await
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        request: &ChatRequestBody,
+    ) -> Result<ByteStream, ProviderError> {
+        let auth = self.auth()?;
+        post_json_stream(
+            &self.upstream,
+            &format!("{}/chat/completions", self.base_url),
+            auth,
+            &StreamingRequest {
+                request,
+                stream: true,
+                stream_options: self
+                    .stream_usage
+                    .then(|| serde_json::json!({ "include_usage": true })),
+            },
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl ImageProvider for CompatibleProvider {
+    async fn generate_image(&self, request: &ImageRequestBody) -> Result<String, ProviderError> {
+        let auth = self.auth()?;
+        post_json(
+            &self.upstream,
+            &format!("{}/images/generations", self.base_url),
+            auth,
+            request,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for CompatibleProvider {
+    async fn embed(&self, request: &EmbeddingRequestBody) -> Result<String, ProviderError> {
+        let auth = self.auth()?;
+        post_json(
+            &self.upstream,
+            &format!("{}/embeddings", self.base_url),
+            auth,
+            request,
+        )
+        .await
+    }
+}
+
+fn bearer(api_key: &str) -> Result<(HeaderName, HeaderValue), ProviderError> {
+    Ok((
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+    ))
+}
+
+async fn post_json_stream<T: Serialize + ?Sized>(
+    upstream: &Upstream,
+    url: &str,
+    auth: Option<(HeaderName, HeaderValue)>,
+    body: &T,
+) -> Result<ByteStream, ProviderError> {
+    let response = upstream
+        .post_stream(url, auth, &serde_json::to_vec(body)?)
+        .await?;
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(ProviderError::Status {
+            status: status.as_u16(),
+            body: response.text().await?,
+        });
+    }
+
+    Ok(upstream.stream_body(response))
+}
+
+async fn post_json<T: Serialize + ?Sized>(
+    upstream: &Upstream,
+    url: &str,
+    auth: Option<(HeaderName, HeaderValue)>,
+    body: &T,
+) -> Result<String, ProviderError> {
+    let response = upstream
+        .post(url, auth, &serde_json::to_vec(body)?)
+        .await?;
+    let status = response.status();
+    let body = response.text().await?;
+
+    if !status.is_success() {
+        return Err(ProviderError::Status {
+            status: status.as_u16(),
+            body,
+        });
+    }
+
+    Ok(body)
+}
@@ -0,0 +1,611 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Client, Response, StatusCode};
+
+use super::{ByteStream, ProviderError};
+
+/// How failed upstream calls are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every retry after it.
+    pub base_delay: Duration,
+    /// Longest wait between two attempts. When the upstream asks to wait
+    /// longer than this, its error is returned instead.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter: half the delay is fixed and the other
+    /// half random, so clients that failed together do not retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0..=half))
+    }
+}
+
+/// Fails calls fast once the upstream looks down.
+///
+/// After `threshold` consecutive failures (connection errors, timeouts and 5xx
+/// responses) the breaker opens and rejects calls for `cooldown`. Then a single
+/// call is let through: if it succeeds the breaker closes, otherwise it opens
+/// again for another `cooldown`.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// A `threshold` of 0 disables the breaker.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    fn check(&self) -> Result<(), ProviderError> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(open_until) if Instant::now() < open_until => Err(ProviderError::CircuitOpen {
+                retry_after: open_until - Instant::now(),
+            }),
+            Some(_) => {
+                // Let this call probe the upstream; the others keep failing fast
+                // until it reports back or another cooldown passes.
+                state.open_until = Some(Instant::now() + self.cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        state.open_until = None;
+    }
+
+    fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.threshold {
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+/// HTTP client shared by every provider call, with retries and a circuit
+/// breaker around it. The connect timeout is set on the wrapped
+/// `reqwest::Client`.
+pub struct Upstream {
+    client: Client,
+    /// Longest a call may take, response body included. Streamed calls may
+    /// take this long to start and between two chunks instead, as a long
+    /// reply keeps streaming for minutes.
+    timeout: Duration,
+    policy: RetryPolicy,
+    breaker: CircuitBreaker,
+}
+
+impl Upstream {
+    pub fn new(
+        client: Client,
+        timeout: Duration,
+        policy: RetryPolicy,
+        breaker: CircuitBreaker,
+    ) -> Self {
+        Self {
+            client,
+            timeout,
+            policy,
+            breaker,
+        }
+    }
+
+    /// POSTs `body` as JSON to `url`, retrying connection errors, timeouts,
+    /// 408, 429 and 5xx responses.
+    ///
+    /// Other responses are returned as they are, successful or not. When the
+    /// retries run out, the last response or error is returned.
+    pub async fn post(
+        &self,
+        url: &str,
+        auth: Option<(HeaderName, HeaderValue)>,
+        body: &[u8],
+    ) -> Result<Response, ProviderError> {
+        self.send(url, auth, body, false).await
+    }
+
+    /// Like [`Upstream::post`], for a response that is streamed: only the wait
+    /// for the response to start is timed. Read its body with
+    /// [`Upstream::stream_body`].
+    pub async fn post_stream(
+        &self,
+        url: &str,
+        auth: Option<(HeaderName, HeaderValue)>,
+        body: &[u8],
+    ) -> Result<Response, ProviderError> {
+        self.send(url, auth, body, true).await
+    }
+
+    /// The body of a streamed `response`, ending with
+    /// [`ProviderError::Stalled`] when no chunk arrives within the timeout.
+    pub fn stream_body(&self, response: Response) -> ByteStream {
+        let idle = self.timeout;
+        let chunks = Box::pin(response.bytes_stream());
+        Box::pin(futures_util::stream::unfold(Some(chunks), move |chunks| async move {
+            let mut chunks = chunks?;
+            match tokio::time::timeout(idle, chunks.next()).await {
+                Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(chunks))),
+                Ok(Some(Err(e))) => Some((Err(e.into()), None)),
+                Ok(None) => None,
+                Err(_) => Some((Err(ProviderError::Stalled { after: idle }), None)),
+            }
+        }))
+    }
+
+    async fn send(
+        &self,
+        url: &str,
+        auth: Option<(HeaderName, HeaderValue)>,
+        body: &[u8],
+        stream: bool,
+    ) -> Result<Response, ProviderError> {
+        let mut attempt = 0;
+        loop {
+            self.breaker.check()?;
+
+            let mut request = self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.to_vec());
+            if let Some((name, value)) = &auth {
+                request = request.header(name.clone(), value.clone());
+            }
+            let result = if stream {
+                match tokio::time::timeout(self.timeout, request.send()).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        self.breaker.record_failure();
+                        if attempt >= self.policy.max_retries {
+                            return Err(ProviderError::Stalled {
+                                after: self.timeout,
+                            });
+                        }
+                        tokio::time::sleep(self.policy.backoff(attempt)).await;
+                        attempt += 1;
+                        continue;
+                    }
+                }
+            } else {
+                request.timeout(self.timeout).send().await
+            };
+            let requested_delay = match &result {
+                Ok(response) if is_retryable(response.status()) => {
+                    if response.status().is_server_error() {
+                        self.breaker.record_failure();
+                    } else {
+                        self.breaker.record_success();
+                    }
+                    requested_delay(response.headers())
+                }
+                Ok(_) => {
+                    self.breaker.record_success();
+                    return Ok(result?);
+                }
+                Err(e) if e.is_connect() || e.is_timeout() => {
+                    self.breaker.record_failure();
+                    None
+                }
+                Err(_) => return Ok(result?),
+            };
+
+            if attempt >= self.policy.max_retries {
+                return Ok(result?);
+            }
+            let delay = match requested_delay {
+                Some(delay) if delay > self.policy.max_delay => return Ok(result?),
+                Some(delay) => delay,
+                None => self.policy.backoff(attempt),
+            };
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
+/// How long the upstream asked us to wait, from `retry-after-ms`,
+/// `Retry-After` (seconds or an HTTP date), or else the longest of OpenAI's
+/// `x-ratelimit-reset-*` headers.
+fn requested_delay(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|value| value.parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+    if let Some(value) = header(RETRY_AFTER.as_str()) {
+        if let Ok(secs) = value.parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        if let Ok(date) = chrono::DateTime::parse_from_rfc2822(value) {
+            let secs = (date.timestamp() - chrono::Utc::now().timestamp()).max(0);
+            return Some(Duration::from_secs(secs as u64));
+        }
+    }
+
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+        .iter()
+        .filter_map(|name| header(name).and_then(parse_reset))
+        .max()
+}
+
+/// Parses OpenAI's reset durations, such as `"20ms"`, `"1s"` or `"6m0.5s"`.
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..split].parse().ok()?;
+        rest = &rest[split..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_len] {
+            "ms" => number / 1000.0,
+            "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 3600.0,
+            _ => return None,
+        };
+        total += seconds;
+        rest = &rest[unit_len..];
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use super::*;
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_max_delay() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+        for (attempt, full) in [(0, 100), (1, 200), (2, 400), (3, 500), (9, 500)] {
+            let delay = policy.backoff(attempt).as_millis();
+            assert!(
+                (full / 2..=full).contains(&delay),
+                "attempt {}: {}ms",
+                attempt,
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn retries_timeouts_rate_limits_and_server_errors() {
+        for status in [408, 429, 500, 502, 503, 504] {
+            assert!(is_retryable(StatusCode::from_u16(status).unwrap()), "{}", status);
+        }
+        for status in [200, 400, 401, 403, 404, 422] {
+            assert!(!is_retryable(StatusCode::from_u16(status).unwrap()), "{}", status);
+        }
+    }
+
+    #[test]
+    fn reads_requested_delay_from_headers() {
+        assert_eq!(requested_delay(&headers(&[])), None);
+        assert_eq!(
+            requested_delay(&headers(&[("retry-after-ms", "1500")])),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            requested_delay(&headers(&[("retry-after", "7")])),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            requested_delay(&headers(&[("retry-after", "Mon, 01 Jan 2001 00:00:00 GMT")])),
+            Some(Duration::ZERO)
+        );
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        let delay = requested_delay(&headers(&[("retry-after", &date)])).unwrap();
+        assert!((Duration::from_secs(28)..=Duration::from_secs(30)).contains(&delay));
+
+        // retry-after-ms is the most precise, so it wins.
+        assert_eq!(
+            requested_delay(&headers(&[("retry-after-ms", "250"), ("retry-after", "7")])),
+            Some(Duration::from_millis(250))
+        );
+        // Without Retry-After, the later of the two rate limit resets.
+        assert_eq!(
+            requested_delay(&headers(&[
+                ("x-ratelimit-reset-requests", "1s"),
+                ("x-ratelimit-reset-tokens", "6m0s"),
+            ])),
+            Some(Duration::from_secs(360))
+        );
+        assert_eq!(requested_delay(&headers(&[("retry-after", "soon")])), None);
+    }
+
+    #[test]
+    fn parses_rate_limit_resets() {
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_reset("6m0.5s"), Some(Duration::from_millis(360_500)));
+        assert_eq!(parse_reset("1h2m"), Some(Duration::from_secs(3720)));
+        assert_eq!(parse_reset("5x"), None);
+        assert_eq!(parse_reset("s"), None);
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+
+        breaker.record_failure();
+        assert!(matches!(
+            breaker.check(),
+            Err(ProviderError::CircuitOpen { retry_after }) if retry_after <= Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    fn breaker_success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn breaker_lets_one_call_probe_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+        assert!(breaker.check().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        // The others fail fast while the probe is out.
+        assert!(breaker.check().is_err());
+
+        // A failed probe opens it for another cooldown...
+        breaker.record_failure();
+        assert!(breaker.check().is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+
+        // ...and a successful one closes it.
+        breaker.record_success();
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn breaker_with_zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(breaker.check().is_ok());
+    }
+
+    /// How the mock upstream answers its `n`th request, from 0: the status,
+    /// any `Retry-After` and how long to wait before answering.
+    type Script = fn(usize) -> (u16, Option<&'static str>, Duration);
+
+    struct Mock {
+        script: Script,
+        hits: AtomicUsize,
+    }
+
+    async fn answer(mock: web::Data<Mock>) -> HttpResponse {
+        let hit = mock.hits.fetch_add(1, Ordering::SeqCst);
+        let (status, retry_after, delay) = (mock.script)(hit);
+        tokio::time::sleep(delay).await;
+        let mut response = HttpResponse::build(status.try_into().unwrap());
+        if let Some(retry_after) = retry_after {
+            response.insert_header(("retry-after", retry_after));
+        }
+        response.body("{}")
+    }
+
+    /// Starts a mock upstream answering as `script` says, and returns its URL
+    /// with the count of requests it got.
+    fn serve(script: Script) -> (String, web::Data<Mock>) {
+        let mock = web::Data::new(Mock {
+            script,
+            hits: AtomicUsize::new(0),
+        });
+        let data = mock.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .default_service(web::to(answer))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/v1/chat/completions", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        (url, mock)
+    }
+
+    fn upstream(timeout: Duration, max_retries: u32, threshold: u32) -> Upstream {
+        Upstream::new(
+            Client::new(),
+            timeout,
+            policy(max_retries),
+            CircuitBreaker::new(threshold, Duration::from_secs(60)),
+        )
+    }
+
+    #[actix_web::test]
+    async fn retries_server_errors_until_success() {
+        let (url, mock) = serve(|hit| match hit {
+            0 | 1 => (503, None, Duration::ZERO),
+            _ => (200, None, Duration::ZERO),
+        });
+        let response = upstream(Duration::from_secs(5), 3, 0)
+            .post(&url, None, b"{}")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(mock.hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_web::test]
+    async fn returns_last_response_when_retries_run_out() {
+        let (url, mock) = serve(|_| (500, None, Duration::ZERO));
+        let response = upstream(Duration::from_secs(5), 2, 0)
+            .post(&url, None, b"{}")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(mock.hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_web::test]
+    async fn does_not_retry_client_errors() {
+        let (url, mock) = serve(|_| (400, None, Duration::ZERO));
+        let response = upstream(Duration::from_secs(5), 3, 0)
+            .post(&url, None, b"{}")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(mock.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn gives_up_when_asked_to_wait_past_max_delay() {
+        let (url, mock) = serve(|_| (429, Some("120"), Duration::ZERO));
+        let response = upstream(Duration::from_secs(5), 3, 0)
+            .post(&url, None, b"{}")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 429);
+        assert_eq!(mock.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn retries_timeouts() {
+        let (url, mock) = serve(|hit| match hit {
+            0 => (200, None, Duration::from_millis(500)),
+            _ => (200, None, Duration::ZERO),
+        });
+        let upstream = upstream(Duration::from_millis(100), 1, 0);
+        let response = upstream.post(&url, None, b"{}").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(mock.hits.load(Ordering::SeqCst), 2);
+
+        let (url, mock) = serve(|_| (200, None, Duration::from_millis(500)));
+        let result = upstream.post_stream(&url, None, b"{}").await;
+        assert!(matches!(result, Err(ProviderError::Stalled { .. })));
+        assert_eq!(mock.hits.load(Ordering::SeqCst), 2);
+    }
+
+    /// Starts a mock upstream streaming a chunk after each of `gaps`, and
+    /// returns its URL.
+    fn serve_stream(gaps: &'static [u64]) -> String {
+        let server = HttpServer::new(move || {
+            App::new().default_service(web::to(move || async move {
+                HttpResponse::Ok().streaming(futures_util::stream::iter(gaps).then(
+                    |gap| async move {
+                        tokio::time::sleep(Duration::from_millis(*gap)).await;
+                        Ok::<_, actix_web::Error>(web::Bytes::from("data: {}\n\n"))
+                    },
+                ))
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/v1/chat/completions", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    #[actix_web::test]
+    async fn times_streams_per_chunk() {
+        let upstream = upstream(Duration::from_millis(200), 0, 0);
+
+        // Longer than the timeout in all, but never idle for that long.
+        let url = serve_stream(&[0, 100, 100, 100]);
+        let response = upstream.post_stream(&url, None, b"{}").await.unwrap();
+        let chunks: Vec<_> = upstream.stream_body(response).collect().await;
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(Result::is_ok));
+
+        let url = serve_stream(&[0, 500]);
+        let response = upstream.post_stream(&url, None, b"{}").await.unwrap();
+        let chunks: Vec<_> = upstream.stream_body(response).collect().await;
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        assert!(matches!(chunks[1], Err(ProviderError::Stalled { .. })));
+    }
+
+    #[actix_web::test]
+    async fn open_breaker_stops_calls() {
+        let (url, mock) = serve(|_| (503, None, Duration::ZERO));
+        let upstream = upstream(Duration::from_secs(5), 3, 2);
+        let result = upstream.post(&url, None, b"{}").await;
+        assert!(matches!(result, Err(ProviderError::CircuitOpen { .. })));
+        assert_eq!(mock.hits.load(Ordering::SeqCst), 2);
+    }
+}
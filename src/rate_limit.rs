@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use futures_util::future::LocalBoxFuture;
+
+use crate::auth::AuthenticatedUser;
+use crate::config::{Config, RateLimitRule};
+use crate::db;
+use crate::errors::MyError;
+
+/// Tier of requests without credentials.
+const ANONYMOUS_TIER: &str = "anonymous";
+
+/// Idle buckets older than this are dropped from memory.
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(3600);
+
+/// How often full buckets are deleted from Postgres.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Storage for token buckets.
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    /// Refills the bucket `key` and takes a token from it if a whole one is
+    /// left. Returns the tokens left and whether one was taken.
+    async fn take(&self, key: &str, capacity: f64, per_second: f64)
+        -> Result<(f64, bool), MyError>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets kept in process memory. Limits hold per instance.
+#[derive(Default)]
+pub struct MemoryBackend {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryBackend {
+    /// The backend shared by every worker of this process, so a client cannot
+    /// multiply its limit by the number of workers.
+    pub fn shared() -> Arc<MemoryBackend> {
+        static SHARED: OnceLock<Arc<MemoryBackend>> = OnceLock::new();
+        SHARED.get_or_init(Default::default).clone()
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for MemoryBackend {
+    async fn take(
+        &self,
+        key: &str,
+        capacity: f64,
+        per_second: f64,
+    ) -> Result<(f64, bool), MyError> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > 10_000 {
+            buckets.retain(|_, bucket| now - bucket.updated < IDLE_BUCKET_TTL);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = (now - bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok((bucket.tokens, true))
+        } else {
+            Ok((bucket.tokens, false))
+        }
+    }
+}
+
+/// Buckets kept in Postgres, so limits hold across every instance sharing
+/// the database. Buckets that have refilled are deleted by the task started
+/// with [`spawn_pruning`].
+pub struct PostgresBackend {
+    pool: Pool,
+}
+
+impl PostgresBackend {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+/// Starts the background task that deletes the Postgres buckets that have
+/// refilled, every [`PRUNE_INTERVAL`], unless the buckets are kept in memory.
+/// Must be called from within the actix runtime, once per process.
+pub fn spawn_pruning(pool: Pool, config: &Config) {
+    if config.rate_limit_backend != "postgres" {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            let result = match pool.get().await {
+                Ok(client) => db::delete_full_rate_limit_buckets(&client).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                eprintln!("Error deleting full rate limit buckets: {}", e);
+            }
+        }
+    });
+}
+
+#[async_trait]
+impl RateLimitBackend for PostgresBackend {
+    async fn take(
+        &self,
+        key: &str,
+        capacity: f64,
+        per_second: f64,
+    ) -> Result<(f64, bool), MyError> {
+        let client = self.pool.get().await?;
+        db::take_rate_limit_token(&client, key, capacity, per_second).await
+    }
+}
+
+/// Middleware that applies the first matching [`RateLimitRule`] to every
+/// request, answering `429 rate_limited` once the bucket is empty.
+///
+/// Must be wrapped inside [`crate::auth::Authentication`] so it can see the
+/// user. Requests are let through if the backend fails.
+pub struct RateLimiter {
+    rules: Rc<Vec<RateLimitRule>>,
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl RateLimiter {
+    pub fn from_config(config: &Config, pool: Pool) -> Self {
+        let backend: Arc<dyn RateLimitBackend> = match config.rate_limit_backend.as_str() {
+            "postgres" => Arc::new(PostgresBackend::new(pool)),
+            _ => MemoryBackend::shared(),
+        };
+        Self {
+            rules: Rc::new(config.rate_limits.clone()),
+            backend,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            rules: Rc::clone(&self.rules),
+            backend: Arc::clone(&self.backend),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    rules: Rc<Vec<RateLimitRule>>,
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let backend = Arc::clone(&self.backend);
+
+        let (subject, tier) = match req.extensions().get::<AuthenticatedUser>() {
+            Some(user) => (format!("user:{}", user.id), user.tier.clone()),
+            None => (
+                format!(
+                    "ip:{}",
+                    req.peer_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_default()
+                ),
+                ANONYMOUS_TIER.to_string(),
+            ),
+        };
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| req.path().to_string());
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| matches(rule, &route, &tier))
+            .cloned();
+
+        Box::pin(async move {
+            let rule = match rule {
+                Some(rule) => rule,
+                None => return service.call(req).await,
+            };
+
+            let key = format!(
+                "{}|{}|{}",
+                rule.route,
+                rule.tier.as_deref().unwrap_or("*"),
+                subject
+            );
+            let capacity = rule.capacity as f64;
+            let per_second = rule.per_minute / 60.0;
+            let tokens = match backend.take(&key, capacity, per_second).await {
+                Ok((tokens, true)) => tokens,
+                Ok((tokens, false)) => {
+                    return Err(MyError::RateLimited {
+                        message: format!("rate limit of {} exceeded", rule.route),
+                        retry_after: Some(retry_after(tokens, per_second)),
+                    }
+                    .into())
+                }
+                Err(e) => {
+                    eprintln!("Error checking rate limit: {}", e);
+                    return service.call(req).await;
+                }
+            };
+
+            let mut response = service.call(req).await?;
+            let headers = response.headers_mut();
+            headers.insert(
+                HeaderName::from_static("x-ratelimit-limit"),
+                HeaderValue::from(rule.capacity),
+            );
+            headers.insert(
+                HeaderName::from_static("x-ratelimit-remaining"),
+                HeaderValue::from(tokens.floor() as u32),
+            );
+            Ok(response)
+        })
+    }
+}
+
+/// Seconds until a bucket holding `tokens` has a whole one again, at least 1.
+fn retry_after(tokens: f64, per_second: f64) -> u64 {
+    ((1.0 - tokens) / per_second).ceil().max(1.0) as u64
+}
+
+fn matches(rule: &RateLimitRule, route: &str, tier: &str) -> bool {
+    let route_matches = match rule.route.strip_suffix('*') {
+        Some(prefix) => route.starts_with(prefix),
+        None => rule.route == route,
+    };
+    route_matches && rule.tier.as_deref().is_none_or(|rule_tier| rule_tier == tier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(route: &str, tier: Option<&str>) -> RateLimitRule {
+        RateLimitRule {
+            route: route.to_string(),
+            tier: tier.map(str::to_string),
+            capacity: 2,
+            per_minute: 60.0,
+        }
+    }
+
+    #[test]
+    fn matches_exact_routes() {
+        let rule = rule("/chat/{chat_id}", None);
+        assert!(matches(&rule, "/chat/{chat_id}", "default"));
+        assert!(!matches(&rule, "/chat/{chat_id}/stream", "default"));
+        assert!(!matches(&rule, "/chat", "default"));
+    }
+
+    #[test]
+    fn matches_route_prefixes() {
+        let prefix = rule("/chat/*", None);
+        assert!(matches(&prefix, "/chat/{chat_id}", "default"));
+        assert!(matches(&prefix, "/chat/{chat_id}/stream", "default"));
+        assert!(!matches(&prefix, "/chats", "default"));
+
+        let everything = rule("*", None);
+        assert!(matches(&everything, "/users/me", ANONYMOUS_TIER));
+    }
+
+    #[test]
+    fn matches_tiers() {
+        let pro = rule("/chat/*", Some("pro"));
+        assert!(matches(&pro, "/chat/{chat_id}", "pro"));
+        assert!(!matches(&pro, "/chat/{chat_id}", "default"));
+        assert!(!matches(&pro, "/chat/{chat_id}", ANONYMOUS_TIER));
+
+        let any = rule("/chat/*", None);
+        assert!(matches(&any, "/chat/{chat_id}", ANONYMOUS_TIER));
+    }
+
+    #[test]
+    fn waits_until_a_whole_token_is_back() {
+        // One token a minute: empty waits 60s, half full 30s.
+        assert_eq!(retry_after(0.0, 1.0 / 60.0), 60);
+        assert_eq!(retry_after(0.5, 1.0 / 60.0), 30);
+        // Partial seconds round up, and the wait is never under a second.
+        assert_eq!(retry_after(0.0, 0.4), 3);
+        assert_eq!(retry_after(0.9, 10.0), 1);
+    }
+
+    #[actix_web::test]
+    async fn memory_backend_empties_and_refills() {
+        let backend = MemoryBackend::default();
+        assert_eq!(backend.take("a", 2.0, 10.0).await.unwrap(), (1.0, true));
+        let (tokens, taken) = backend.take("a", 2.0, 10.0).await.unwrap();
+        assert!(taken);
+        assert!(tokens < 1.0);
+        let (tokens, taken) = backend.take("a", 2.0, 10.0).await.unwrap();
+        assert!(!taken);
+        assert!(tokens < 1.0);
+
+        // Other keys have their own bucket.
+        assert!(backend.take("b", 2.0, 10.0).await.unwrap().1);
+
+        // Ten tokens a second refill one in 100ms.
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        assert!(backend.take("a", 2.0, 10.0).await.unwrap().1);
+    }
+
+    #[actix_web::test]
+    async fn memory_backend_refills_up_to_capacity() {
+        let backend = MemoryBackend::default();
+        assert!(backend.take("a", 2.0, 100.0).await.unwrap().1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (tokens, taken) = backend.take("a", 2.0, 100.0).await.unwrap();
+        assert!(taken);
+        assert_eq!(tokens, 1.0);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use chrono::Utc;
+use deadpool_postgres::Pool;
+use futures_util::Stream;
+
+use crate::config::ModelPrice;
+use crate::db;
+use crate::errors::MyError;
+use crate::message_index;
+use crate::models::Message;
+use crate::providers::ByteStream;
+use crate::titles::AutoTitle;
+use crate::usage::{CompletionOutcome, PendingCompletion};
+
+/// Forwards an upstream chat completion stream to the client as server-sent
+/// events while assembling the assistant reply from the `delta` chunks.
+///
+/// The assembled reply and its usage are saved when the relay is dropped,
+/// which happens both when the upstream finishes and when the client
+/// disconnects mid-stream.
+pub struct SseRelay {
+    upstream: ByteStream,
+    buffer: Vec<u8>,
+    reply: String,
+    chat_id: i32,
+    db_pool: Pool,
+    done: bool,
+    pending: Option<PendingCompletion>,
+    outcome: CompletionOutcome,
+    pricing: HashMap<String, ModelPrice>,
+    /// Document excerpts the reply was given, saved with it.
+    citations: Option<serde_json::Value>,
+    /// Started once the reply is saved.
+    auto_title: Option<AutoTitle>,
+}
+
+impl SseRelay {
+    pub fn new(
+        upstream: ByteStream,
+        chat_id: i32,
+        db_pool: Pool,
+        pending: PendingCompletion,
+        pricing: HashMap<String, ModelPrice>,
+        citations: Option<serde_json::Value>,
+        auto_title: AutoTitle,
+    ) -> Self {
+        Self {
+            upstream,
+            buffer: Vec::new(),
+            reply: String::new(),
+            chat_id,
+            db_pool,
+            done: false,
+            pending: Some(pending),
+            outcome: CompletionOutcome::default(),
+            pricing,
+            citations,
+            auto_title: Some(auto_title),
+        }
+    }
+
+    /// Removes the next complete event (terminated by a blank line) from the
+    /// buffer. Once the upstream has ended, whatever is left counts as an event.
+    fn next_event(&mut self) -> Option<String> {
+        let end = self
+            .buffer
+            .windows(2)
+            .position(|window| window == b"\n\n")
+            .map(|position| position + 2)
+            .or((self.done && !self.buffer.is_empty()).then_some(self.buffer.len()))?;
+
+        let event: Vec<u8> = self.buffer.drain(..end).collect();
+        let event = String::from_utf8_lossy(&event).trim().to_string();
+        if event.is_empty() {
+            return self.next_event();
+        }
+        Some(event)
+    }
+
+    fn collect(&mut self, event: &str) {
+        for line in event.lines() {
+            let data = match line.strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => continue,
+            };
+            if data == "[DONE]" {
+                continue;
+            }
+            if let Ok(chunk) = serde_json::from_str::<serde_json::Value>(data) {
+                self.outcome.update(&chunk);
+                if let Some(content) = chunk["choices"][0]["delta"]["content"].as_str() {
+                    self.reply.push_str(content);
+                }
+            }
+        }
+    }
+}
+
+impl Stream for SseRelay {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.next_event() {
+                this.collect(&event);
+                return Poll::Ready(Some(Ok(Bytes::from(format!("{}\n\n", event)))));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            match this.upstream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    this.buffer
+                        .extend(chunk.iter().copied().filter(|byte| *byte != b'\r'));
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    eprintln!("Error reading OpenAI stream: {}", e);
+                    this.done = true;
+                    return Poll::Ready(Some(Err(MyError::from(e).into())));
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for SseRelay {
+    fn drop(&mut self) {
+        if self.reply.is_empty() && self.outcome.usage.is_none() {
+            return;
+        }
+
+        let reply = std::mem::take(&mut self.reply);
+        let completion = self.pending.take().map(|pending| {
+            pending.finish(None, std::mem::take(&mut self.outcome), &reply, &self.pricing)
+        });
+        let ai_message = Message {
+            id: None,
+            created_on: Utc::now(),
+            role: "assistant".to_string(),
+            content: reply,
+            chat_id_relation: self.chat_id,
+            pinned: false,
+            parent_id: None,
+            tool_calls: None,
+            tool_call_id: None,
+            citations: self.citations.take(),
+        };
+        let db_pool = self.db_pool.clone();
+        let auto_title = self.auto_title.take();
+
+        actix_web::rt::spawn(async move {
+            let client = match db_pool.get().await {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("Error saving streamed assistant message: {}", e);
+                    return;
+                }
+            };
+
+            let mut message_id = None;
+            if !ai_message.content.is_empty() {
+                match db::add_message(&client, ai_message).await {
+                    Ok(message) => {
+                        message_id = message.id;
+                        message_index::wake();
+                        if let Some(auto_title) = auto_title {
+                            auto_title.spawn();
+                        }
+                    }
+                    Err(e) => eprintln!("Error saving streamed assistant message: {}", e),
+                }
+            }
+            if let Some(mut completion) = completion {
+                completion.message_id = message_id;
+                if let Err(e) = db::add_completion(&client, completion).await {
+                    eprintln!("Error recording completion usage: {}", e);
+                }
+            }
+        });
+    }
+}
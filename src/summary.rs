@@ -0,0 +1,309 @@
+use std::error::Error as StdError;
+
+use deadpool_postgres::Client;
+
+use crate::db;
+use crate::errors::MyError;
+use crate::models::{ChatSettings, ChatSummary, Message};
+use crate::providers::ChatRequestBody;
+use crate::tokenizer::{
+    context_window, count_message_tokens, count_prompt_tokens, count_tokens, truncate_tokens,
+};
+use crate::usage::{CompletionOutcome, PendingCompletion};
+use crate::{ChatCompletionMessage, ChatContext};
+
+const SUMMARY_INSTRUCTIONS: &str = "You maintain a running summary of a conversation between a user and an assistant. \
+Merge the previous summary (if any) with the new messages into a single updated summary. \
+Keep facts, decisions, names, numbers and open questions; drop pleasantries. \
+Write concise prose of at most a few paragraphs and reply with the summary only.";
+
+/// Upper bound on the length of a generated summary.
+const SUMMARY_MAX_TOKENS: i32 = 500;
+
+impl ChatSummary {
+    /// The summary as the system message that stands in for the summarized
+    /// history.
+    pub fn as_message(&self) -> ChatCompletionMessage {
+        ChatCompletionMessage::new(
+            "system",
+            format!("Summary of the earlier conversation:\n{}", self.content),
+        )
+    }
+}
+
+/// The chat's summary, if it summarizes the branch `messages` belong to.
+///
+/// A summary covers the path up to its `last_message_id`, so it only applies
+/// while that message is on the active branch.
+pub async fn current_summary(
+    client: &Client,
+    chat_id: i32,
+    messages: &[Message],
+) -> Result<Option<ChatSummary>, MyError> {
+    Ok(db::get_chat_summary(client, chat_id).await?.filter(|summary| {
+        messages
+            .iter()
+            .any(|message| message.id == Some(summary.last_message_id))
+    }))
+}
+
+/// Brings the chat's summary up to date and returns it.
+///
+/// Nothing happens while the unsummarized messages fit in `budget` tokens.
+/// Once they don't, the older messages are folded into the summary, keeping
+/// the newest messages (about half the budget) verbatim. When the older
+/// messages are too long to summarize at once, the oldest ones that fit are
+/// folded in and the rest are left for the next turns.
+pub async fn refresh_summary(
+    context: &ChatContext,
+    client: &Client,
+    chat_id: i32,
+    model: &str,
+    messages: &[Message],
+    budget: usize,
+) -> Result<Option<ChatSummary>, Box<dyn StdError>> {
+    let summary = current_summary(client, chat_id, messages).await?;
+    let summarized_up_to = summary.as_ref().map_or(0, |summary| summary.last_message_id);
+    let summary_tokens = summary
+        .as_ref()
+        .map_or(0, |summary| count_message_tokens(&summary.as_message()));
+
+    let unsummarized: Vec<&Message> = messages
+        .iter()
+        .filter(|message| message.id.unwrap_or_default() > summarized_up_to)
+        .collect();
+    let unsummarized_tokens = count_prompt_tokens(
+        &unsummarized
+            .iter()
+            .map(|message| to_completion_message(message))
+            .collect::<Vec<_>>(),
+    );
+    if summary_tokens + unsummarized_tokens <= budget {
+        return Ok(summary);
+    }
+
+    let split = kept_from(&unsummarized, budget / 2);
+    let (transcript, covered) = transcript(
+        summary.as_ref().map(|summary| summary.content.as_str()),
+        &unsummarized[..split],
+        transcript_limit(model),
+    );
+    let last_message_id = match unsummarized[..covered].last().and_then(|message| message.id) {
+        Some(id) => id,
+        None => return Ok(summary),
+    };
+
+    let content = summarize(context, client, chat_id, model, transcript).await?;
+
+    let summary = db::upsert_chat_summary(client, chat_id, &content, last_message_id).await?;
+    Ok(Some(summary))
+}
+
+/// Where the newest of `messages` to keep verbatim start: the last one, and
+/// the ones before it that fit in `max_tokens`. The rest are left to
+/// summarize.
+fn kept_from(messages: &[&Message], max_tokens: usize) -> usize {
+    let mut kept_tokens = 0;
+    let mut split = messages.len().saturating_sub(1);
+    while split > 0 {
+        let tokens = count_message_tokens(&to_completion_message(messages[split - 1]));
+        if kept_tokens + tokens > max_tokens {
+            break;
+        }
+        kept_tokens += tokens;
+        split -= 1;
+    }
+    split
+}
+
+/// Tokens of `model`'s context window left for the transcript once the
+/// instructions and the summary itself are accounted for.
+fn transcript_limit(model: &str) -> usize {
+    let instructions = count_prompt_tokens(&[
+        ChatCompletionMessage::new("system", SUMMARY_INSTRUCTIONS.to_string()),
+        ChatCompletionMessage::new("user", String::new()),
+    ]);
+    context_window(model).saturating_sub(instructions + SUMMARY_MAX_TOKENS as usize)
+}
+
+/// The previous summary and the oldest of `messages` that fit in
+/// `max_tokens`, as text to summarize, and how many messages it holds. A first
+/// message too long on its own is cut to fit, so the summary always moves on.
+fn transcript(previous: Option<&str>, messages: &[&Message], max_tokens: usize) -> (String, usize) {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("Previous summary:\n{}\n\n", previous));
+    }
+    transcript.push_str("New messages:\n");
+    let mut tokens = count_tokens(&transcript);
+
+    let mut covered = 0;
+    for message in messages {
+        let line = match &message.tool_calls {
+            Some(tool_calls) => format!(
+                "{} (calling tools {}): {}\n",
+                message.role, tool_calls, message.content
+            ),
+            None => format!("{}: {}\n", message.role, message.content),
+        };
+        let line_tokens = count_tokens(&line);
+        if tokens + line_tokens > max_tokens {
+            if covered == 0 {
+                transcript.push_str(&truncate_tokens(&line, max_tokens.saturating_sub(tokens)));
+                covered = 1;
+            }
+            break;
+        }
+        transcript.push_str(&line);
+        tokens += line_tokens;
+        covered += 1;
+    }
+    (transcript, covered)
+}
+
+/// Asks `model` to summarize `transcript` and records the usage against the
+/// chat.
+async fn summarize(
+    context: &ChatContext,
+    client: &Client,
+    chat_id: i32,
+    model: &str,
+    transcript: String,
+) -> Result<String, Box<dyn StdError>> {
+    let request = ChatRequestBody::new(
+        vec![
+            ChatCompletionMessage::new("system", SUMMARY_INSTRUCTIONS.to_string()),
+            ChatCompletionMessage::new("user", transcript),
+        ],
+        ChatSettings {
+            temperature: Some(0.2),
+            max_tokens: Some(SUMMARY_MAX_TOKENS),
+            ..Default::default()
+        },
+        model,
+    );
+
+    let pending = PendingCompletion::start(context.user.id, Some(chat_id), &request);
+    let response = context.provider.chat_completion(&request).await?;
+    let response_json: serde_json::Value = serde_json::from_str(&response)?;
+
+    let reply = response_json["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap_or_default();
+    let mut outcome = CompletionOutcome::default();
+    outcome.update(&response_json);
+    let completion = pending.finish(None, outcome, reply, &context.config.pricing);
+    if let Err(e) = db::add_completion(client, completion).await {
+        eprintln!("Error recording completion usage: {}", e);
+    }
+
+    Some(reply.trim())
+        .filter(|reply| !reply.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| "summary response has no content".into())
+}
+
+fn to_completion_message(message: &Message) -> ChatCompletionMessage {
+    ChatCompletionMessage {
+        role: message.role.clone(),
+        content: message.content.clone(),
+        tool_calls: message.tool_calls.clone(),
+        tool_call_id: message.tool_call_id.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            id: None,
+            created_on: Utc::now(),
+            role: role.to_string(),
+            content: content.to_string(),
+            chat_id_relation: 1,
+            pinned: false,
+            parent_id: None,
+            tool_calls: None,
+            tool_call_id: None,
+            citations: None,
+        }
+    }
+
+    fn tokens(message: &Message) -> usize {
+        count_message_tokens(&to_completion_message(message))
+    }
+
+    #[test]
+    fn transcript_holds_every_message_that_fits() {
+        let messages = [message("user", "What is 2+2?"), message("assistant", "4")];
+        let messages: Vec<&Message> = messages.iter().collect();
+
+        let (text, covered) = transcript(None, &messages, 1000);
+        assert_eq!(text, "New messages:\nuser: What is 2+2?\nassistant: 4\n");
+        assert_eq!(covered, 2);
+
+        let (text, covered) = transcript(Some("They talked maths."), &messages, 1000);
+        assert_eq!(
+            text,
+            "Previous summary:\nThey talked maths.\n\nNew messages:\nuser: What is 2+2?\nassistant: 4\n"
+        );
+        assert_eq!(covered, 2);
+    }
+
+    #[test]
+    fn transcript_stops_before_the_first_message_over_the_limit() {
+        let messages = [
+            message("user", "first"),
+            message("assistant", "second"),
+            message("user", &"third ".repeat(100)),
+            message("assistant", "fourth"),
+        ];
+        let messages: Vec<&Message> = messages.iter().collect();
+
+        let (text, covered) = transcript(None, &messages, 50);
+        assert_eq!(covered, 2);
+        assert_eq!(text, "New messages:\nuser: first\nassistant: second\n");
+        assert!(count_tokens(&text) <= 50);
+    }
+
+    #[test]
+    fn transcript_cuts_a_first_message_too_long_on_its_own() {
+        let messages = [message("user", &"word ".repeat(500)), message("assistant", "ok")];
+        let messages: Vec<&Message> = messages.iter().collect();
+
+        let (text, covered) = transcript(Some("Earlier."), &messages, 60);
+        assert_eq!(covered, 1);
+        assert!(text.starts_with("Previous summary:\nEarlier.\n\nNew messages:\nuser: word"));
+        assert!(count_tokens(&text) <= 60);
+    }
+
+    #[test]
+    fn keeps_the_newest_messages_within_the_limit() {
+        let messages = [
+            message("user", "one"),
+            message("assistant", "two"),
+            message("user", "three"),
+            message("assistant", "four"),
+        ];
+        let messages: Vec<&Message> = messages.iter().collect();
+
+        // The last message is kept on top of the limit.
+        let limit = tokens(messages[1]) + tokens(messages[2]);
+        assert_eq!(kept_from(&messages, limit), 1);
+        assert_eq!(kept_from(&messages, limit - 1), 2);
+        assert_eq!(kept_from(&messages, 10_000), 0);
+    }
+
+    #[test]
+    fn always_leaves_the_last_message_unsummarized() {
+        let messages = [message("user", "one"), message("assistant", &"long ".repeat(100))];
+        let messages: Vec<&Message> = messages.iter().collect();
+        assert_eq!(kept_from(&messages, 0), 1);
+        assert_eq!(kept_from(&messages[..1], 0), 0);
+        assert_eq!(kept_from(&[], 0), 0);
+    }
+}
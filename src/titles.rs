@@ -0,0 +1,192 @@
+use actix_web::web;
+use deadpool_postgres::{Client, Pool};
+
+use crate::config::Config;
+use crate::db;
+use crate::errors::MyError;
+use crate::models::{Chat, ChatSettings, Message};
+use crate::providers::{ChatProvider, ChatRequestBody};
+use crate::tokenizer::{count_tokens, truncate_tokens};
+use crate::usage::{CompletionOutcome, PendingCompletion};
+use crate::{upstream_error, ChatCompletionMessage};
+
+const TITLE_INSTRUCTIONS: &str = "You name conversations between a user and an assistant. \
+Reply with a title of at most six words for the conversation below, in its language. \
+Reply with the title only, without quotes or a final period.";
+
+/// Upper bound on the length of a generated title, in tokens.
+const TITLE_MAX_TOKENS: i32 = 20;
+
+/// Longest title kept, in characters.
+const MAX_TITLE_CHARS: usize = 80;
+
+/// Tokens of each message, and of the whole conversation, shown to the model.
+const MAX_MESSAGE_TOKENS: usize = 500;
+const MAX_TRANSCRIPT_TOKENS: usize = 2000;
+
+/// Names a chat after its first reply, in the background.
+///
+/// Built by the handlers that answer messages and started once the reply is
+/// saved. Does nothing unless the reply was the first of its branch and the
+/// chat has automatic titles on.
+pub struct AutoTitle {
+    db_pool: Pool,
+    provider: web::Data<dyn ChatProvider>,
+    config: web::Data<Config>,
+    app_user: i32,
+    chat_id: i32,
+}
+
+impl AutoTitle {
+    pub fn new(
+        db_pool: Pool,
+        provider: web::Data<dyn ChatProvider>,
+        config: web::Data<Config>,
+        app_user: i32,
+        chat_id: i32,
+    ) -> Self {
+        Self {
+            db_pool,
+            provider,
+            config,
+            app_user,
+            chat_id,
+        }
+    }
+
+    /// Titles the chat on a separate task. Must be called from within the
+    /// actix runtime.
+    pub fn spawn(self) {
+        actix_web::rt::spawn(async move {
+            if let Err(e) = self.run().await {
+                eprintln!("Error titling chat {}: {}", self.chat_id, e);
+            }
+        });
+    }
+
+    async fn run(&self) -> Result<(), MyError> {
+        let client = self.db_pool.get().await?;
+        let chat = db::get_chat(&client, self.chat_id).await?;
+        if !chat.auto_title.unwrap_or(self.config.auto_title) {
+            return Ok(());
+        }
+
+        let messages = db::get_messages_by_chat_id(&client, self.chat_id).await?;
+        if messages.iter().filter(|message| is_reply(message)).count() != 1 {
+            return Ok(());
+        }
+
+        retitle(
+            &client,
+            self.provider.get_ref(),
+            &self.config,
+            self.app_user,
+            &chat,
+            &messages,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// An assistant message that answers rather than calls tools.
+fn is_reply(message: &Message) -> bool {
+    message.role == "assistant" && message.tool_calls.is_none() && !message.content.is_empty()
+}
+
+/// Asks the chat's model for a title for `messages`, the chat's active branch,
+/// saves it as the chat's name and returns it. The call's usage is recorded
+/// against the chat.
+pub async fn retitle(
+    client: &Client,
+    provider: &dyn ChatProvider,
+    config: &Config,
+    app_user: i32,
+    chat: &Chat,
+    messages: &[Message],
+) -> Result<String, MyError> {
+    let transcript = transcript(messages);
+    if transcript.is_empty() {
+        return Err(MyError::BadRequest(
+            "the chat has no messages to title".to_string(),
+        ));
+    }
+
+    let request = ChatRequestBody::new(
+        vec![
+            ChatCompletionMessage::new("system", TITLE_INSTRUCTIONS.to_string()),
+            ChatCompletionMessage::new("user", transcript),
+        ],
+        ChatSettings {
+            model: chat.model.clone(),
+            temperature: Some(0.2),
+            max_tokens: Some(TITLE_MAX_TOKENS),
+            ..Default::default()
+        },
+        &config.default_model,
+    );
+
+    let pending = PendingCompletion::start(app_user, Some(chat.chat_id), &request);
+    let response = provider.chat_completion(&request).await?;
+    let response_json: serde_json::Value = serde_json::from_str(&response)
+        .map_err(|e| upstream_error(format!("invalid JSON in completion: {}", e)))?;
+
+    let reply = response_json["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap_or_default();
+    let mut outcome = CompletionOutcome::default();
+    outcome.update(&response_json);
+    let completion = pending.finish(None, outcome, reply, &config.pricing);
+    if let Err(e) = db::add_completion(client, completion).await {
+        eprintln!("Error recording completion usage: {}", e);
+    }
+
+    let title = clean_title(reply)
+        .ok_or_else(|| upstream_error("completion has no title".to_string()))?;
+    db::update_chat_name(client, chat.chat_id, title.clone()).await?;
+    Ok(title)
+}
+
+/// The user and assistant messages from the start of the conversation, each
+/// cut to `MAX_MESSAGE_TOKENS`, for at most `MAX_TRANSCRIPT_TOKENS` in all.
+fn transcript(messages: &[Message]) -> String {
+    let mut transcript = String::new();
+    let mut tokens = 0;
+
+    for message in messages.iter().filter(|message| {
+        matches!(message.role.as_str(), "user" | "assistant") && !message.content.is_empty()
+    }) {
+        let line = format!(
+            "{}: {}\n",
+            message.role,
+            truncate_tokens(&message.content, MAX_MESSAGE_TOKENS)
+        );
+        let line_tokens = count_tokens(&line);
+        if tokens + line_tokens > MAX_TRANSCRIPT_TOKENS && !transcript.is_empty() {
+            break;
+        }
+        transcript.push_str(&line);
+        tokens += line_tokens;
+    }
+
+    transcript
+}
+
+/// The first line of the model's reply without the quotes, labels and final
+/// period models tend to add, or `None` if nothing is left.
+fn clean_title(reply: &str) -> Option<String> {
+    let line = reply.lines().map(str::trim).find(|line| !line.is_empty())?;
+    let line = line
+        .strip_prefix("Title:")
+        .or_else(|| line.strip_prefix("title:"))
+        .unwrap_or(line);
+    let title = line
+        .trim()
+        .trim_matches(|c| matches!(c, '"' | '\'' | '“' | '”' | '*' | '#'))
+        .trim_end_matches('.')
+        .trim();
+
+    let title: String = title.chars().take(MAX_TITLE_CHARS).collect();
+    let title = title.trim_end().to_string();
+    (!title.is_empty()).then_some(title)
+}
@@ -0,0 +1,110 @@
+use tiktoken_rs::cl100k_base_singleton;
+
+use crate::ChatCompletionMessage;
+
+/// Tokens the API adds around every message (`<|start|>role ... <|end|>`).
+const TOKENS_PER_MESSAGE: usize = 3;
+/// Tokens the API adds to prime the assistant's reply.
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Counts the cl100k_base tokens in `text`.
+pub fn count_tokens(text: &str) -> usize {
+    let bpe = cl100k_base_singleton();
+    let bpe = bpe.lock();
+    bpe.encode_ordinary(text).len()
+}
+
+/// The longest prefix of `text` that fits in `max_tokens` tokens.
+pub fn truncate_tokens(text: &str, max_tokens: usize) -> String {
+    let bpe = cl100k_base_singleton();
+    let bpe = bpe.lock();
+    let mut tokens = bpe.encode_ordinary(text);
+    if tokens.len() <= max_tokens {
+        return text.to_string();
+    }
+
+    // A cut inside a multi-byte character does not decode; cut earlier.
+    tokens.truncate(max_tokens);
+    while !tokens.is_empty() {
+        if let Ok(prefix) = bpe.decode(tokens.clone()) {
+            return prefix;
+        }
+        tokens.pop();
+    }
+    String::new()
+}
+
+/// Counts the tokens a single message contributes to a chat completion prompt.
+pub fn count_message_tokens(message: &ChatCompletionMessage) -> usize {
+    let tool_calls = message
+        .tool_calls
+        .as_ref()
+        .map_or(0, |tool_calls| count_tokens(&tool_calls.to_string()));
+    TOKENS_PER_MESSAGE + count_tokens(&message.role) + count_tokens(&message.content) + tool_calls
+}
+
+/// Counts the tokens of a whole chat completion prompt.
+pub fn count_prompt_tokens(messages: &[ChatCompletionMessage]) -> usize {
+    messages.iter().map(count_message_tokens).sum::<usize>() + TOKENS_PER_REPLY
+}
+
+/// Returns the context window of `model`, in tokens. Unknown models get the
+/// 4096-token window of the original gpt-3.5-turbo.
+pub fn context_window(model: &str) -> usize {
+    tiktoken_rs::model::get_context_size(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_message_and_reply_overhead() {
+        assert_eq!(count_tokens(""), 0);
+        assert_eq!(count_tokens("hello world"), 2);
+
+        let message = ChatCompletionMessage::new("user", "hello world".to_string());
+        assert_eq!(count_message_tokens(&message), TOKENS_PER_MESSAGE + 1 + 2);
+        assert_eq!(
+            count_prompt_tokens(&[message.clone(), message]),
+            2 * (TOKENS_PER_MESSAGE + 1 + 2) + TOKENS_PER_REPLY
+        );
+        assert_eq!(count_prompt_tokens(&[]), TOKENS_PER_REPLY);
+    }
+
+    #[test]
+    fn counts_tool_calls() {
+        let mut message = ChatCompletionMessage::new("assistant", String::new());
+        let plain = count_message_tokens(&message);
+        message.tool_calls = Some(serde_json::json!([{ "id": "a" }]));
+        assert!(count_message_tokens(&message) > plain);
+    }
+
+    #[test]
+    fn truncates_to_a_prefix_within_max_tokens() {
+        let text = "the quick brown fox jumps over the lazy dog";
+        assert_eq!(truncate_tokens(text, 100), text);
+        assert_eq!(truncate_tokens(text, 0), "");
+
+        let truncated = truncate_tokens(text, 3);
+        assert_eq!(truncated, "the quick brown");
+        assert_eq!(count_tokens(&truncated), 3);
+    }
+
+    #[test]
+    fn truncates_between_characters() {
+        let text = "🦀🦀🦀🦀";
+        for max_tokens in 0..count_tokens(text) {
+            let truncated = truncate_tokens(text, max_tokens);
+            assert!(text.starts_with(&truncated), "{}", max_tokens);
+            assert!(count_tokens(&truncated) <= max_tokens, "{}", max_tokens);
+        }
+    }
+
+    #[test]
+    fn knows_context_windows() {
+        assert_eq!(context_window("gpt-4"), 8192);
+        assert_eq!(context_window("gpt-4-32k"), 32768);
+        assert_eq!(context_window("some-local-model"), 4096);
+    }
+}
@@ -0,0 +1,312 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use super::{Tool, ToolContext, ToolError};
+
+/// Deepest nesting of parentheses, function calls, signs and powers, so a
+/// hostile expression cannot overflow the stack.
+const MAX_DEPTH: usize = 100;
+
+/// Evaluates arithmetic expressions, so the model does not have to.
+pub struct Calculator;
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &str {
+        "calculator"
+    }
+
+    fn description(&self) -> &str {
+        "Evaluates an arithmetic expression exactly as written. Supports + - * / % ^, \
+         parentheses, the constants pi and e, and the functions sqrt, abs, ln, log10, \
+         exp, sin, cos, tan, floor, ceil and round."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "The expression, e.g. \"(3 + 4) * 2 ^ 10 / 7\""
+                }
+            },
+            "required": ["expression"]
+        })
+    }
+
+    async fn execute(&self, _context: &ToolContext, arguments: Value) -> Result<Value, ToolError> {
+        let expression = arguments["expression"]
+            .as_str()
+            .ok_or_else(|| ToolError::InvalidArguments("expression must be a string".to_string()))?;
+
+        let result = evaluate(expression).map_err(ToolError::Failed)?;
+        Ok(json!({ "expression": expression, "result": result }))
+    }
+}
+
+/// Evaluates `expression` with the usual precedence; `^` binds tightest and
+/// is right-associative.
+fn evaluate(expression: &str) -> Result<f64, String> {
+    let mut parser = Parser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.expression()?;
+    if let Some(c) = parser.peek() {
+        return Err(format!("unexpected '{}' at position {}", c, parser.position));
+    }
+    if !value.is_finite() {
+        return Err("the result is not a finite number".to_string());
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    /// How many `unary` calls are under way; every kind of nesting goes
+    /// through one.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    return Err("division by zero".to_string());
+                }
+                value /= divisor;
+            } else if self.eat('%') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    return Err("division by zero".to_string());
+                }
+                value %= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!(
+                "the expression is nested more than {} levels deep",
+                MAX_DEPTH
+            ));
+        }
+        self.depth += 1;
+        let value = self.signed();
+        self.depth -= 1;
+        value
+    }
+
+    /// ('-' | '+') unary | power
+    fn signed(&mut self) -> Result<f64, String> {
+        if self.eat('-') {
+            Ok(-self.unary()?)
+        } else if self.eat('+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    /// atom ('^' unary)?
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            Ok(base.powf(self.unary()?))
+        } else {
+            Ok(base)
+        }
+    }
+
+    /// number | name | name '(' expression ')' | '(' expression ')'
+    fn atom(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let value = self.expression()?;
+                if !self.eat(')') {
+                    return Err("missing ')'".to_string());
+                }
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => self.name(),
+            Some(c) => Err(format!("unexpected '{}' at position {}", c, self.position)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_digit() || c == '.')
+        {
+            self.position += 1;
+        }
+        if matches!(self.peek(), Some('e' | 'E'))
+            && self
+                .chars
+                .get(self.position + 1)
+                .is_some_and(|c| c.is_ascii_digit() || *c == '-' || *c == '+')
+        {
+            self.position += 2;
+            while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                self.position += 1;
+            }
+        }
+
+        let text: String = self.chars[start..self.position].iter().collect();
+        text.parse()
+            .map_err(|_| format!("invalid number \"{}\"", text))
+    }
+
+    fn name(&mut self) -> Result<f64, String> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+            self.position += 1;
+        }
+        let name: String = self.chars[start..self.position].iter().collect();
+
+        match name.as_str() {
+            "pi" => return Ok(std::f64::consts::PI),
+            "e" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+
+        if !self.eat('(') {
+            return Err(format!("unknown name \"{}\"", name));
+        }
+        let argument = self.expression()?;
+        if !self.eat(')') {
+            return Err("missing ')'".to_string());
+        }
+
+        match name.as_str() {
+            "sqrt" if argument < 0.0 => Err("sqrt of a negative number".to_string()),
+            "sqrt" => Ok(argument.sqrt()),
+            "abs" => Ok(argument.abs()),
+            "ln" | "log10" if argument <= 0.0 => {
+                Err(format!("{} of a number that is not positive", name))
+            }
+            "ln" => Ok(argument.ln()),
+            "log10" => Ok(argument.log10()),
+            "exp" => Ok(argument.exp()),
+            "sin" => Ok(argument.sin()),
+            "cos" => Ok(argument.cos()),
+            "tan" => Ok(argument.tan()),
+            "floor" => Ok(argument.floor()),
+            "ceil" => Ok(argument.ceil()),
+            "round" => Ok(argument.round()),
+            _ => Err(format!("unknown function \"{}\"", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(evaluate("2 * 3 % 4"), Ok(2.0));
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("-2 ^ 2"), Ok(-4.0));
+        assert_eq!(evaluate("2 ^ -1"), Ok(0.5));
+        assert_eq!(evaluate("--3 + +2"), Ok(5.0));
+    }
+
+    #[test]
+    fn reads_numbers_names_and_functions() {
+        assert_eq!(evaluate("1.5e3 + .5"), Ok(1500.5));
+        assert_eq!(evaluate("2E-2"), Ok(0.02));
+        assert_eq!(evaluate("sqrt(16) + abs(-2) + round(2.5)"), Ok(9.0));
+        assert_eq!(evaluate("floor(ln(e) * 10 + 0.5)"), Ok(10.0));
+        assert_eq!(evaluate("cos(pi)"), Ok(-1.0));
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(evaluate("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(evaluate("5 % (2 - 2)"), Err("division by zero".to_string()));
+        assert_eq!(evaluate("(1 + 2"), Err("missing ')'".to_string()));
+        assert_eq!(evaluate("1 + 2)"), Err("unexpected ')' at position 3".to_string()));
+        assert_eq!(evaluate("1 +"), Err("unexpected end of expression".to_string()));
+        assert_eq!(evaluate("2 # 3"), Err("unexpected '#' at position 1".to_string()));
+        assert_eq!(evaluate("tau"), Err("unknown name \"tau\"".to_string()));
+        assert_eq!(evaluate("cbrt(8)"), Err("unknown function \"cbrt\"".to_string()));
+        assert_eq!(evaluate("sqrt(-1)"), Err("sqrt of a negative number".to_string()));
+        assert_eq!(
+            evaluate("ln(0)"),
+            Err("ln of a number that is not positive".to_string())
+        );
+        assert_eq!(evaluate("1.2.3"), Err("invalid number \"1.2.3\"".to_string()));
+        assert_eq!(
+            evaluate("exp(1000)"),
+            Err("the result is not a finite number".to_string())
+        );
+    }
+
+    #[test]
+    fn allows_reasonable_nesting() {
+        let expression = format!("{}1{}", "(".repeat(50), ")".repeat(50));
+        assert_eq!(evaluate(&expression), Ok(1.0));
+        let expression = format!("{}1{}", "sqrt(".repeat(30), ")".repeat(30));
+        assert_eq!(evaluate(&expression), Ok(1.0));
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let too_deep = format!("the expression is nested more than {} levels deep", MAX_DEPTH);
+        for expression in [
+            format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000)),
+            format!("{}1", "-".repeat(100_000)),
+            format!("{}1", "2^".repeat(100_000)),
+            format!("{}1{}", "abs(".repeat(100_000), ")".repeat(100_000)),
+        ] {
+            assert_eq!(evaluate(&expression), Err(too_deep.clone()));
+        }
+    }
+}
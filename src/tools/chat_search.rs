@@ -0,0 +1,81 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use super::{Tool, ToolContext, ToolError};
+use crate::db;
+use crate::errors::MyError;
+
+const DEFAULT_LIMIT: i64 = 5;
+const MAX_LIMIT: i64 = 20;
+/// Longer messages are cut to this many characters to spare the context.
+const MAX_CONTENT_CHARS: usize = 500;
+
+/// Searches the messages of the user's own chats.
+pub struct ChatSearch;
+
+#[async_trait]
+impl Tool for ChatSearch {
+    fn name(&self) -> &str {
+        "search_chats"
+    }
+
+    fn description(&self) -> &str {
+        "Searches the user's past chats for messages containing some text, newest first. \
+         Use it when the user refers to an earlier conversation."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": {
+                    "type": "string",
+                    "description": "Text to look for, ignoring case"
+                },
+                "limit": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "maximum": MAX_LIMIT,
+                    "description": "Messages to return, 5 by default"
+                }
+            },
+            "required": ["query"]
+        })
+    }
+
+    async fn execute(&self, context: &ToolContext, arguments: Value) -> Result<Value, ToolError> {
+        let query = arguments["query"]
+            .as_str()
+            .map(str::trim)
+            .filter(|query| !query.is_empty())
+            .ok_or_else(|| ToolError::InvalidArguments("query must be a non-empty string".to_string()))?;
+        let limit = arguments["limit"]
+            .as_i64()
+            .unwrap_or(DEFAULT_LIMIT)
+            .clamp(1, MAX_LIMIT);
+
+        let client = context.db_pool.get().await.map_err(MyError::PoolError)?;
+        let matches = db::search_user_messages(&client, context.app_user, query, limit).await?;
+
+        Ok(json!({
+            "matches": matches
+                .into_iter()
+                .map(|found| {
+                    let content = match found.content.char_indices().nth(MAX_CONTENT_CHARS) {
+                        Some((end, _)) => format!("{}…", &found.content[..end]),
+                        None => found.content,
+                    };
+                    json!({
+                        "chat_id": found.chat_id,
+                        "chat_name": found.chat_name,
+                        "message_id": found.message_id,
+                        "role": found.role,
+                        "content": content,
+                        "created_on": found.created_on,
+                        "current_chat": found.chat_id == context.chat_id,
+                    })
+                })
+                .collect::<Vec<_>>()
+        }))
+    }
+}
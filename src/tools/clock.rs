@@ -0,0 +1,48 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use chrono_tz::Tz;
+use serde_json::{json, Value};
+
+use super::{Tool, ToolContext, ToolError};
+
+/// Tells the model the current date and time, which it cannot know.
+pub struct Clock;
+
+#[async_trait]
+impl Tool for Clock {
+    fn name(&self) -> &str {
+        "current_time"
+    }
+
+    fn description(&self) -> &str {
+        "Returns the current date, time and weekday in a time zone."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "timezone": {
+                    "type": "string",
+                    "description": "IANA time zone, e.g. \"Europe/Paris\". Defaults to UTC."
+                }
+            }
+        })
+    }
+
+    async fn execute(&self, _context: &ToolContext, arguments: Value) -> Result<Value, ToolError> {
+        let timezone: Tz = match arguments["timezone"].as_str() {
+            Some(name) => name.parse().map_err(|_| {
+                ToolError::InvalidArguments(format!("unknown time zone \"{}\"", name))
+            })?,
+            None => Tz::UTC,
+        };
+
+        let now = Utc::now().with_timezone(&timezone);
+        Ok(json!({
+            "timezone": timezone.name(),
+            "datetime": now.to_rfc3339(),
+            "weekday": now.format("%A").to_string(),
+        }))
+    }
+}
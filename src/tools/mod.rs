@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use derive_more::{Display, From};
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::errors::MyError;
+use crate::ChatCompletionMessage;
+
+mod calculator;
+mod chat_search;
+mod clock;
+mod notes;
+pub use calculator::Calculator;
+pub use chat_search::ChatSearch;
+pub use clock::Clock;
+pub use notes::Notes;
+
+/// Names of the built-in tools, which are all enabled unless
+/// `builtin_tools` says otherwise.
+pub const BUILTIN_TOOLS: [&str; 4] = ["calculator", "current_time", "search_chats", "notes"];
+
+/// Who a tool call runs for.
+pub struct ToolContext {
+    pub app_user: i32,
+    pub chat_id: i32,
+    pub db_pool: Pool,
+}
+
+#[derive(Display, From, Debug)]
+pub enum ToolError {
+    /// The arguments do not match the tool's schema.
+    #[display(fmt = "invalid arguments: {}", _0)]
+    #[from(ignore)]
+    InvalidArguments(String),
+    #[from(ignore)]
+    Failed(String),
+    Database(MyError),
+}
+impl std::error::Error for ToolError {}
+
+/// A function the model can call while answering.
+///
+/// Tools run in-process. Their errors are reported back to the model as the
+/// call's result, so it can correct its arguments or answer without the tool.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// The name the model calls the tool by: letters, digits, `_` and `-`.
+    fn name(&self) -> &str;
+
+    /// Tells the model what the tool does and when to use it.
+    fn description(&self) -> &str;
+
+    /// JSON schema of the arguments object.
+    fn parameters(&self) -> Value;
+
+    /// Runs the tool. A string result is passed to the model as is, any other
+    /// value as JSON.
+    async fn execute(&self, context: &ToolContext, arguments: Value) -> Result<Value, ToolError>;
+}
+
+/// The tools offered to the model on every non-streamed chat request.
+#[derive(Default, Clone)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in tools enabled by `config.builtin_tools`.
+    pub fn from_config(config: &Config) -> Self {
+        config
+            .builtin_tools
+            .iter()
+            .fold(Self::new(), |registry, name| match name.as_str() {
+                "calculator" => registry.register(Calculator),
+                "current_time" => registry.register(Clock),
+                "search_chats" => registry.register(ChatSearch),
+                "notes" => registry.register(Notes),
+                _ => registry,
+            })
+    }
+
+    /// Adds `tool`, replacing any tool with the same name.
+    pub fn register(mut self, tool: impl Tool + 'static) -> Self {
+        self.tools.retain(|registered| registered.name() != tool.name());
+        self.tools.push(Arc::new(tool));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Tool> {
+        self.tools
+            .iter()
+            .find(|tool| tool.name() == name)
+            .map(|tool| tool.as_ref())
+    }
+
+    /// The `tools` field of a chat request, or `None` without tools.
+    pub fn definitions(&self) -> Option<Vec<Value>> {
+        if self.tools.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name(),
+                            "description": tool.description(),
+                            "parameters": tool.parameters(),
+                        }
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Runs the `tool_calls` of an assistant message concurrently and returns
+    /// a `tool` message with the result of each, in the order of the calls.
+    pub async fn run(&self, tool_calls: &[Value], context: &ToolContext) -> Vec<ChatCompletionMessage> {
+        join_all(tool_calls.iter().map(|call| self.run_one(call, context))).await
+    }
+
+    async fn run_one(&self, call: &Value, context: &ToolContext) -> ChatCompletionMessage {
+        let name = call["function"]["name"].as_str().unwrap_or_default();
+        let result = match self.get(name) {
+            Some(tool) => {
+                let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+                match serde_json::from_str(arguments) {
+                    Ok(arguments) => tool.execute(context, arguments).await,
+                    Err(e) => Err(ToolError::InvalidArguments(e.to_string())),
+                }
+            }
+            None => Err(ToolError::Failed(format!("unknown tool \"{}\"", name))),
+        };
+
+        let content = match result {
+            Ok(Value::String(content)) => content,
+            Ok(value) => value.to_string(),
+            Err(e) => json!({ "error": e.to_string() }).to_string(),
+        };
+
+        ChatCompletionMessage {
+            role: "tool".to_string(),
+            content,
+            tool_calls: None,
+            tool_call_id: call["id"].as_str().map(str::to_string),
+        }
+    }
+}
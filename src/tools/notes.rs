@@ -0,0 +1,108 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use super::{Tool, ToolContext, ToolError};
+use crate::db;
+use crate::errors::MyError;
+
+const MAX_KEY_CHARS: usize = 255;
+const MAX_VALUE_CHARS: usize = 10_000;
+
+/// A key/value store the model keeps for each user across chats.
+pub struct Notes;
+
+#[async_trait]
+impl Tool for Notes {
+    fn name(&self) -> &str {
+        "notes"
+    }
+
+    fn description(&self) -> &str {
+        "Keeps notes for the user that persist across chats. Use \"list\" to see every note, \
+         \"get\" to read one, \"set\" to create or replace one and \"delete\" to remove one. \
+         Save facts the user asks you to remember."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "action": {
+                    "type": "string",
+                    "enum": ["list", "get", "set", "delete"]
+                },
+                "key": {
+                    "type": "string",
+                    "description": "Name of the note; required except for \"list\""
+                },
+                "value": {
+                    "type": "string",
+                    "description": "Content of the note, for \"set\""
+                }
+            },
+            "required": ["action"]
+        })
+    }
+
+    async fn execute(&self, context: &ToolContext, arguments: Value) -> Result<Value, ToolError> {
+        let action = arguments["action"].as_str().unwrap_or_default();
+        let key = || {
+            arguments["key"]
+                .as_str()
+                .map(str::trim)
+                .filter(|key| !key.is_empty() && key.chars().count() <= MAX_KEY_CHARS)
+                .ok_or_else(|| {
+                    ToolError::InvalidArguments(format!(
+                        "key must be a non-empty string of at most {} characters",
+                        MAX_KEY_CHARS
+                    ))
+                })
+        };
+
+        let client = context.db_pool.get().await.map_err(MyError::PoolError)?;
+        match action {
+            "list" => {
+                let notes = db::get_notes(&client, context.app_user).await?;
+                Ok(json!({
+                    "notes": notes
+                        .into_iter()
+                        .map(|note| json!({ "key": note.key, "value": note.value }))
+                        .collect::<Vec<_>>()
+                }))
+            }
+            "get" => {
+                let key = key()?;
+                match db::get_note(&client, context.app_user, key).await? {
+                    Some(note) => Ok(json!({ "key": note.key, "value": note.value })),
+                    None => Err(ToolError::Failed(format!("no note named \"{}\"", key))),
+                }
+            }
+            "set" => {
+                let key = key()?;
+                let value = arguments["value"]
+                    .as_str()
+                    .filter(|value| value.chars().count() <= MAX_VALUE_CHARS)
+                    .ok_or_else(|| {
+                        ToolError::InvalidArguments(format!(
+                            "value must be a string of at most {} characters",
+                            MAX_VALUE_CHARS
+                        ))
+                    })?;
+                let note = db::upsert_note(&client, context.app_user, key, value).await?;
+                Ok(json!({ "saved": note.key }))
+            }
+            "delete" => {
+                let key = key()?;
+                if db::delete_note(&client, context.app_user, key).await? {
+                    Ok(json!({ "deleted": key }))
+                } else {
+                    Err(ToolError::Failed(format!("no note named \"{}\"", key)))
+                }
+            }
+            other => Err(ToolError::InvalidArguments(format!(
+                "action must be list, get, set or delete, got \"{}\"",
+                other
+            ))),
+        }
+    }
+}
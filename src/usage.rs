@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::config::ModelPrice;
+use crate::models::{Completion, ModelUsage};
+use crate::providers::ChatRequestBody;
+use crate::tokenizer::{count_prompt_tokens, count_tokens};
+
+/// A model call that has been sent upstream and not yet recorded.
+pub struct PendingCompletion {
+    app_user: i32,
+    chat_id: Option<i32>,
+    model: String,
+    /// Local count, used when the provider does not report usage.
+    estimated_prompt_tokens: usize,
+    started: Instant,
+}
+
+impl PendingCompletion {
+    /// Starts timing `request`. Create it right before calling the provider.
+    pub fn start(app_user: i32, chat_id: Option<i32>, request: &ChatRequestBody) -> Self {
+        Self {
+            app_user,
+            chat_id,
+            model: request.model.clone(),
+            estimated_prompt_tokens: count_prompt_tokens(&request.messages),
+            started: Instant::now(),
+        }
+    }
+
+    /// Starts timing an image generation with `model`.
+    pub fn start_image(app_user: i32, chat_id: i32, model: &str) -> Self {
+        Self {
+            app_user,
+            chat_id: Some(chat_id),
+            model: model.to_string(),
+            estimated_prompt_tokens: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Starts timing the embedding of `inputs` with `model`, made for a chat
+    /// or, without `chat_id`, for the user as a whole.
+    pub fn start_embedding(
+        app_user: i32,
+        chat_id: Option<i32>,
+        model: &str,
+        inputs: &[String],
+    ) -> Self {
+        Self {
+            app_user,
+            chat_id,
+            model: model.to_string(),
+            estimated_prompt_tokens: inputs.iter().map(|input| count_tokens(input)).sum(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Builds the row for an embedding call from its response. Embeddings
+    /// only use prompt tokens.
+    pub fn finish_embedding(
+        self,
+        response: &serde_json::Value,
+        pricing: &HashMap<String, ModelPrice>,
+    ) -> Completion {
+        let prompt_tokens = response["usage"]["prompt_tokens"]
+            .as_i64()
+            .map_or(self.estimated_prompt_tokens as i32, |tokens| tokens as i32);
+        let responded = response["model"].as_str();
+        let cost = price(pricing, &self.model, responded).map(|price| cost(price, prompt_tokens, 0));
+
+        Completion {
+            id: None,
+            app_user: self.app_user,
+            chat_id: self.chat_id,
+            message_id: None,
+            model: responded.map_or(self.model, str::to_string),
+            prompt_tokens,
+            completion_tokens: 0,
+            finish_reason: None,
+            latency_ms: self.started.elapsed().as_millis().min(i32::MAX as u128) as i32,
+            cost,
+            created_on: Utc::now(),
+        }
+    }
+
+    /// Builds the row for `images` generated images. Images use no tokens and
+    /// cost the model's `per_image` price each.
+    pub fn finish_image(self, images: u32, pricing: &HashMap<String, ModelPrice>) -> Completion {
+        let cost = price(pricing, &self.model, None).map(|price| price.per_image * images as f64);
+
+        Completion {
+            id: None,
+            app_user: self.app_user,
+            chat_id: self.chat_id,
+            message_id: None,
+            model: self.model,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            finish_reason: None,
+            latency_ms: self.started.elapsed().as_millis().min(i32::MAX as u128) as i32,
+            cost,
+            created_on: Utc::now(),
+        }
+    }
+
+    /// Builds the completion row for the assistant message `message_id`.
+    /// Token counts the provider did not report are counted locally.
+    pub fn finish(
+        self,
+        message_id: Option<i32>,
+        outcome: CompletionOutcome,
+        reply: &str,
+        pricing: &HashMap<String, ModelPrice>,
+    ) -> Completion {
+        let (prompt_tokens, completion_tokens) = outcome.usage.unwrap_or((
+            self.estimated_prompt_tokens as i32,
+            count_tokens(reply) as i32,
+        ));
+        let cost = price(pricing, &self.model, outcome.model.as_deref())
+            .map(|price| cost(price, prompt_tokens, completion_tokens));
+
+        Completion {
+            id: None,
+            app_user: self.app_user,
+            chat_id: self.chat_id,
+            message_id,
+            model: outcome.model.unwrap_or(self.model),
+            prompt_tokens,
+            completion_tokens,
+            finish_reason: outcome.finish_reason,
+            latency_ms: self.started.elapsed().as_millis().min(i32::MAX as u128) as i32,
+            cost,
+            created_on: Utc::now(),
+        }
+    }
+}
+
+/// What the provider reported about a completion.
+#[derive(Debug, Default)]
+pub struct CompletionOutcome {
+    /// The exact model that answered, e.g. `gpt-4-0613`.
+    pub model: Option<String>,
+    /// Prompt and completion tokens.
+    pub usage: Option<(i32, i32)>,
+    pub finish_reason: Option<String>,
+}
+
+impl CompletionOutcome {
+    /// Picks up the fields present in a completion response or in one chunk of
+    /// a streamed response.
+    pub fn update(&mut self, response: &serde_json::Value) {
+        if let Some(model) = response["model"].as_str() {
+            self.model = Some(model.to_string());
+        }
+        let usage = &response["usage"];
+        if let (Some(prompt), Some(completion)) = (
+            usage["prompt_tokens"].as_i64(),
+            usage["completion_tokens"].as_i64(),
+        ) {
+            self.usage = Some((prompt as i32, completion as i32));
+        }
+        if let Some(finish_reason) = response["choices"][0]["finish_reason"].as_str() {
+            self.finish_reason = Some(finish_reason.to_string());
+        }
+    }
+}
+
+/// Looks up the price of a call: an exact match on the requested or the
+/// responding model first, then the longest configured prefix of the
+/// responding model, so `gpt-4` also prices `gpt-4-0613`.
+fn price(
+    pricing: &HashMap<String, ModelPrice>,
+    requested: &str,
+    responded: Option<&str>,
+) -> Option<ModelPrice> {
+    let responded = responded.unwrap_or(requested);
+    pricing
+        .get(requested)
+        .or_else(|| pricing.get(responded))
+        .or_else(|| {
+            pricing
+                .iter()
+                .filter(|(model, _)| responded.starts_with(model.as_str()))
+                .max_by_key(|(model, _)| model.len())
+                .map(|(_, price)| price)
+        })
+        .copied()
+}
+
+fn cost(price: ModelPrice, prompt_tokens: i32, completion_tokens: i32) -> f64 {
+    (prompt_tokens as f64 * price.prompt_per_1k + completion_tokens as f64 * price.completion_per_1k)
+        / 1000.0
+}
+
+/// Usage totals over a set of completions, with a breakdown per model.
+#[derive(Serialize)]
+pub struct UsageSummary {
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    /// In dollars, over the completions whose model has a price.
+    pub cost: f64,
+    pub by_model: Vec<ModelUsage>,
+}
+
+impl From<Vec<ModelUsage>> for UsageSummary {
+    fn from(by_model: Vec<ModelUsage>) -> Self {
+        let requests = by_model.iter().map(|usage| usage.requests).sum();
+        let prompt_tokens = by_model.iter().map(|usage| usage.prompt_tokens).sum();
+        let completion_tokens = by_model.iter().map(|usage| usage.completion_tokens).sum();
+        let cost = by_model.iter().filter_map(|usage| usage.cost).sum();
+
+        Self {
+            requests,
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            cost,
+            by_model,
+        }
+    }
+}